@@ -0,0 +1,112 @@
+//! Plaintext admin endpoint. Bind it to loopback only: it is unauthenticated.
+//!
+//! `POST /reload` rebuilds the TLS acceptor from disk and reports the outcome.
+
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, Take};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+use crate::reload::ReloadableAcceptor;
+
+/// Requests with a larger head (request line and headers) are refused.
+const MAX_REQUEST_HEAD: u64 = 8 * 1024;
+/// Time allowed for a client to send the request head.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn serve(listener: TcpListener, acceptor: Arc<ReloadableAcceptor>) -> Result<()> {
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(tcp, &acceptor).await {
+                warn!(%peer, "admin: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle(mut tcp: TcpStream, acceptor: &ReloadableAcceptor) -> Result<()> {
+    let (read, mut write) = tcp.split();
+    let mut reader = BufReader::new(read.take(MAX_REQUEST_HEAD));
+    let head = tokio::time::timeout(READ_TIMEOUT, read_head(&mut reader)).await
+        .map_err(|_| anyhow!("timed out after {READ_TIMEOUT:?} reading the request"))??;
+
+    let (status, body) = match head {
+        None => ("431 Request Header Fields Too Large", format!("request head over {MAX_REQUEST_HEAD} bytes\n")),
+        Some(request_line) => {
+            let mut parts = request_line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("POST"), Some("/reload")) => match acceptor.reload("admin request") {
+                    Ok(()) => ("200 OK", "reloaded\n".to_string()),
+                    Err(e) => ("500 Internal Server Error", format!("reload failed: {e:#}\n")),
+                },
+                (Some(_), Some("/reload")) => ("405 Method Not Allowed", "use POST\n".to_string()),
+                _ => ("404 Not Found", "not found\n".to_string()),
+            }
+        }
+    };
+
+    let resp = format!(
+        "HTTP/1.1 {status}\r\nContent-Length: {}\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    write.write_all(resp.as_bytes()).await?;
+    write.shutdown().await.ok();
+    Ok(())
+}
+
+/// Read the request line and drain the headers; requests carry no body we care about.
+/// `None` when the head runs past the reader's limit.
+async fn read_head<R: AsyncRead + Unpin>(reader: &mut BufReader<Take<R>>) -> Result<Option<String>> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line).await?;
+        if line == "\r\n" || line == "\n" {
+            return Ok(Some(request_line));
+        }
+        if n == 0 {
+            // The client closed early, or the limit cut it off.
+            return Ok((reader.get_ref().limit() > 0).then_some(request_line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn head(input: &[u8]) -> Option<String> {
+        read_head(&mut BufReader::new(input.take(MAX_REQUEST_HEAD))).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_line_is_read_and_headers_drained() {
+        let req = b"POST /reload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(head(req).await.as_deref(), Some("POST /reload HTTP/1.1\r\n"));
+        assert_eq!(head(b"POST /reload HTTP/1.0\n\n").await.as_deref(), Some("POST /reload HTTP/1.0\n"));
+        // A client that stops early still gets an answer.
+        assert_eq!(head(b"POST /reload HTTP/1.1\r\n").await.as_deref(), Some("POST /reload HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn oversized_heads_are_refused() {
+        let limit = MAX_REQUEST_HEAD as usize;
+        let long_line = format!("POST /{} HTTP/1.1\r\n\r\n", "a".repeat(limit));
+        assert_eq!(head(long_line.as_bytes()).await, None);
+        let many_headers = format!("POST /reload HTTP/1.1\r\n{}\r\n", "X-Filler: 0123456789\r\n".repeat(limit / 20));
+        assert_eq!(head(many_headers.as_bytes()).await, None);
+        // Without a newline anywhere, e.g. a client streaming one endless line.
+        assert_eq!(head(&vec![b'a'; 10 * limit]).await, None);
+
+        // Exactly at the limit still fits.
+        let filler = limit - "POST /reload HTTP/1.1\r\nX: \r\n\r\n".len();
+        let exact = format!("POST /reload HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(filler));
+        assert_eq!(exact.len(), limit);
+        assert!(head(exact.as_bytes()).await.is_some());
+    }
+}
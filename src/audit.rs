@@ -0,0 +1,1021 @@
+//! Audit log of every handshake attempt on the server's listeners, accepted or not, as
+//! JSON lines appended to `[audit] file`.
+//!
+//! Each record holds the time, the client address, SNI, the TLS versions and ALPN
+//! protocols the client offered and those negotiated, the chain it presented (leaf
+//! first, as sent), the first certificate verification error and the policy verdict.
+//! The ClientHello callback and the verify callback collect these on the `Ssl` while the
+//! handshake runs; the record is written once it has finished either way.
+//!
+//! The file is rotated (renamed with a UTC timestamp suffix and started afresh) before a
+//! record that would take it past `max_bytes`, or once it is `max_age_secs` old. Rotated
+//! files are never deleted here.
+//!
+//! Records are numbered (`seq`) and chained: each carries the SHA-256 of the previous
+//! line (`prev`), across rotations and restarts. At rotation the last record's number
+//! and hash are signed into `<rotated file>.sig`, with `[audit] signing_key` or the
+//! server key, which seals every record up to it. The current file is covered by
+//! `<file>.checkpoint` instead, which signs the chain so far and is replaced at startup,
+//! after each rotation and every `checkpoint_secs` while records come in. `audit verify`
+//! walks the chain and checks the seals and the checkpoint, so a deleted, reordered or
+//! edited record shows up, as does a rotated file passed off as the current one; only
+//! the records written since the last checkpoint can be cut without a trace.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, Subcommand};
+use foreign_types::ForeignTypeRef;
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public};
+use openssl::sign::{Signer, Verifier};
+use openssl::ssl::{ClientHelloResponse, Ssl, SslAlert, SslRef, SslVersion};
+use openssl::stack::StackRef;
+use openssl::x509::{X509StoreContext, X509StoreContextRef, X509Ref, X509};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::check::{Format, Report, Status};
+use crate::config::{check_file, IdentityConfig};
+use crate::peer;
+use crate::policy::{serial_hex, Action};
+use crate::secret::Secret;
+use crate::tls::{load_identity, load_private_key};
+use crate::x509_name_to_string;
+
+const TLSEXT_ALPN: u32 = 16;
+const TLSEXT_SUPPORTED_VERSIONS: u32 = 43;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// JSON lines file; auditing is off when unset.
+    pub file: Option<PathBuf>,
+    /// Rotate before the file would grow past this size.
+    pub max_bytes: u64,
+    /// Rotate once the file is this old; 0 rotates on size only.
+    pub max_age_secs: u64,
+    /// Re-sign the checkpoint of the current file at most this often while records are
+    /// written.
+    pub checkpoint_secs: u64,
+    /// PEM private key that signs rotated files; the server key when unset.
+    pub signing_key: Option<PathBuf>,
+    pub signing_key_password: Option<Secret>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            file: None,
+            max_bytes: 100 * 1024 * 1024,
+            max_age_secs: 86400,
+            checkpoint_secs: 60,
+            signing_key: None,
+            signing_key_password: None,
+        }
+    }
+}
+
+impl AuditConfig {
+    pub fn enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(file) = &self.file {
+            let dir = file.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if !dir.is_dir() {
+                bail!("audit.file: directory {} does not exist", dir.display());
+            }
+        }
+        if self.max_bytes < 4096 {
+            bail!("audit.max_bytes: must be at least 4096");
+        }
+        if self.checkpoint_secs == 0 {
+            bail!("audit.checkpoint_secs: must be at least 1");
+        }
+        if let Some(key) = &self.signing_key {
+            check_file("audit.signing_key", key)?;
+        }
+        if let Some(password) = &self.signing_key_password {
+            password.validate("audit.signing_key_password")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the callbacks learn about one handshake, kept on its `Ssl`.
+#[derive(Default)]
+struct Handshake {
+    offered_versions: Vec<&'static str>,
+    offered_alpn: Vec<String>,
+    chain: Vec<CertRecord>,
+    verify_error: Option<VerifyError>,
+}
+
+type Slot = Mutex<Handshake>;
+
+fn slot_index() -> Index<Ssl, Slot> {
+    static INDEX: OnceLock<Index<Ssl, Slot>> = OnceLock::new();
+    *INDEX.get_or_init(|| Ssl::new_ex_index().expect("allocating SSL ex_data index"))
+}
+
+/// Prepare `ssl` so the callbacks record what the audit log needs.
+pub fn track(ssl: &mut SslRef) {
+    ssl.set_ex_data(slot_index(), Mutex::new(Handshake::default()));
+}
+
+fn with_slot(ssl: &SslRef, f: impl FnOnce(&mut Handshake)) {
+    if let Some(slot) = ssl.ex_data(slot_index()) {
+        f(&mut slot.lock().unwrap());
+    }
+}
+
+fn with_ctx_slot(x509_ctx: &X509StoreContextRef, f: impl FnOnce(&mut Handshake)) {
+    let ssl = X509StoreContext::ssl_idx().ok().and_then(|idx| x509_ctx.ex_data(idx));
+    if let Some(ssl) = ssl {
+        with_slot(ssl, f);
+    }
+}
+
+/// ClientHello callback: note the versions and ALPN protocols the client offers.
+pub fn client_hello(ssl: &mut SslRef, _alert: &mut SslAlert) -> Result<ClientHelloResponse, ErrorStack> {
+    let versions = match hello_ext(ssl, TLSEXT_SUPPORTED_VERSIONS) {
+        // u8 length, then u16 versions.
+        Some(ext) => ext.get(1..).unwrap_or_default().chunks_exact(2)
+            .filter_map(|v| version_name(u16::from_be_bytes([v[0], v[1]])))
+            .collect(),
+        None => ssl.client_hello_legacy_version().and_then(legacy_version_name).into_iter().collect(),
+    };
+    // u16 length, then u8-length-prefixed names.
+    let mut alpn = Vec::new();
+    let mut rest = hello_ext(ssl, TLSEXT_ALPN).and_then(|ext| ext.get(2..)).unwrap_or_default();
+    while let Some((&len, tail)) = rest.split_first() {
+        let Some(name) = tail.get(..len as usize) else { break };
+        alpn.push(String::from_utf8_lossy(name).into_owned());
+        rest = &tail[len as usize..];
+    }
+    with_slot(ssl, |h| {
+        h.offered_versions = versions;
+        h.offered_alpn = alpn;
+    });
+    Ok(ClientHelloResponse::SUCCESS)
+}
+
+fn hello_ext(ssl: &SslRef, ext: u32) -> Option<&[u8]> {
+    let mut out = std::ptr::null();
+    let mut len = 0;
+    // SAFETY: only called from the ClientHello callback, while the hello and so `out`
+    // are alive; the slice doesn't outlive `ssl`'s borrow.
+    unsafe {
+        if openssl_sys::SSL_client_hello_get0_ext(ssl.as_ptr(), ext, &mut out, &mut len) != 1 || out.is_null() {
+            return None;
+        }
+        Some(std::slice::from_raw_parts(out, len))
+    }
+}
+
+fn version_name(v: u16) -> Option<&'static str> {
+    match v {
+        0x0304 => Some("TLSv1.3"),
+        0x0303 => Some("TLSv1.2"),
+        0x0302 => Some("TLSv1.1"),
+        0x0301 => Some("TLSv1"),
+        0x0300 => Some("SSLv3"),
+        // GREASE and anything unknown
+        _ => None,
+    }
+}
+
+fn legacy_version_name(v: SslVersion) -> Option<&'static str> {
+    [SslVersion::TLS1_3, SslVersion::TLS1_2, SslVersion::TLS1_1, SslVersion::TLS1, SslVersion::SSL3]
+        .into_iter()
+        .zip(["TLSv1.3", "TLSv1.2", "TLSv1.1", "TLSv1", "SSLv3"])
+        .find_map(|(known, name)| (known == v).then_some(name))
+}
+
+extern "C" {
+    fn X509_STORE_CTX_get0_cert(ctx: *const openssl_sys::X509_STORE_CTX) -> *mut openssl_sys::X509;
+    fn X509_STORE_CTX_get0_untrusted(ctx: *const openssl_sys::X509_STORE_CTX) -> *mut openssl_sys::stack_st_X509;
+}
+
+/// Called from the verify callbacks: keep the chain as the client sent it, the first
+/// time round.
+pub fn record_chain(x509_ctx: &X509StoreContextRef) {
+    with_ctx_slot(x509_ctx, |h| {
+        if !h.chain.is_empty() {
+            return;
+        }
+        // SAFETY: the store context is valid for the callback and owns what it returns.
+        let (leaf, untrusted) = unsafe {
+            let leaf = X509_STORE_CTX_get0_cert(x509_ctx.as_ptr());
+            let untrusted = X509_STORE_CTX_get0_untrusted(x509_ctx.as_ptr());
+            ((!leaf.is_null()).then(|| X509Ref::from_ptr(leaf)),
+             (!untrusted.is_null()).then(|| StackRef::<X509>::from_ptr(untrusted)))
+        };
+        // The untrusted list holds the leaf too.
+        h.chain = leaf.into_iter()
+            .chain(untrusted.into_iter().flatten().filter(|c| leaf.is_none_or(|l| *l != **c)))
+            .map(CertRecord::new)
+            .collect();
+    });
+}
+
+/// Called from the verify callbacks when they fail the handshake.
+pub fn record_error(x509_ctx: &X509StoreContextRef) {
+    let err = x509_ctx.error();
+    let depth = x509_ctx.error_depth();
+    with_ctx_slot(x509_ctx, |h| {
+        h.verify_error.get_or_insert(VerifyError { code: err.as_raw(), depth, reason: err.error_string().to_string() });
+    });
+}
+
+#[derive(Serialize)]
+struct CertRecord {
+    subject: String,
+    issuer: String,
+    serial: String,
+    sha256: String,
+}
+
+impl CertRecord {
+    fn new(cert: &X509Ref) -> CertRecord {
+        CertRecord {
+            subject: x509_name_to_string(cert.subject_name()),
+            issuer: x509_name_to_string(cert.issuer_name()),
+            serial: serial_hex(cert),
+            sha256: cert.digest(MessageDigest::sha256()).map(hex::encode).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VerifyError {
+    code: i32,
+    depth: u32,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Position in the log, counting from 0 across rotations.
+    seq: u64,
+    /// SHA-256 of the previous record's line; all zeros for the first.
+    prev: &'a str,
+    ts: String,
+    peer_ip: IpAddr,
+    peer_port: u16,
+    /// Which acceptor took the connection: `main` or `est`.
+    listener: &'a str,
+    sni: Option<&'a str>,
+    offered_versions: &'a [&'static str],
+    offered_alpn: &'a [String],
+    version: Option<&'a str>,
+    alpn: Option<String>,
+    cipher: Option<&'a str>,
+    outcome: &'static str,
+    /// Why the handshake failed, as OpenSSL reported it.
+    error: Option<String>,
+    chain: &'a [CertRecord],
+    verify_error: Option<&'a VerifyError>,
+    /// `accept` or `reject`; unset when the policy never ran.
+    verdict: Option<&'static str>,
+    /// The policy rule that decided; unset for the default.
+    rule: Option<&'a str>,
+}
+
+/// The audit file, rotated by size and age.
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    checkpoint_every: Duration,
+    /// Signs the seal of each rotated file and the checkpoint of the current one.
+    key: PKey<Private>,
+    current: Mutex<Current>,
+}
+
+struct Current {
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    /// `seq` of the file's first record, once it has one.
+    first_seq: Option<u64>,
+    next_seq: u64,
+    /// Hash of the last record written.
+    prev: String,
+    /// When the checkpoint was last signed; `None` until it first is.
+    checkpointed: Option<Instant>,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig, identity: &IdentityConfig) -> Result<Option<AuditLog>> {
+        let Some(path) = &config.file else { return Ok(None) };
+        let key = match &config.signing_key {
+            Some(key) => load_private_key(key, config.signing_key_password.as_ref()).context("audit.signing_key")?,
+            None => load_identity(identity)?.key,
+        };
+        let log = AuditLog {
+            path: path.clone(),
+            max_bytes: config.max_bytes,
+            max_age: (config.max_age_secs > 0).then(|| Duration::from_secs(config.max_age_secs)),
+            checkpoint_every: Duration::from_secs(config.checkpoint_secs),
+            key,
+            current: Mutex::new(open(path)?),
+        };
+        log.resume()?;
+        // Vouch for what is already there, so a file cut while the server was down
+        // can't pass for one that simply ended.
+        log.checkpoint(&mut log.current.lock().unwrap()).context("audit.file")?;
+        Ok(Some(log))
+    }
+
+    /// An empty current file (rotated just before a restart) continues the chain from
+    /// the checkpoint the rotation left.
+    fn resume(&self) -> Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let path = checkpoint_path(&self.path);
+        if current.first_seq.is_some() || !path.exists() {
+            return Ok(());
+        }
+        let checkpoint: Checkpoint = std::fs::read(&path).map_err(anyhow::Error::from)
+            .and_then(|json| Ok(serde_json::from_slice(&json)?))
+            .with_context(|| format!("audit.file: reading {}", path.display()))?;
+        current.next_seq = checkpoint.next_seq;
+        current.prev = checkpoint.last_hash;
+        Ok(())
+    }
+
+    /// Append the record for a finished handshake on `ssl`. Failures are logged; the
+    /// connection goes on either way.
+    pub fn record(&self, listener: &str, peer: SocketAddr, ssl: &SslRef, error: Option<&openssl::ssl::Error>) {
+        let Some(slot) = ssl.ex_data(slot_index()) else { return };
+        let h = slot.lock().unwrap();
+        let verdict = peer::verdict(ssl);
+        // Numbering and hashing the record, and writing it, happen under one lock so
+        // the chain follows the file order.
+        let mut current = self.current.lock().unwrap();
+        let record = Record {
+            seq: current.next_seq,
+            prev: &current.prev,
+            ts: rfc3339(SystemTime::now()),
+            peer_ip: peer.ip(),
+            peer_port: peer.port(),
+            listener,
+            sni: ssl.servername(openssl::ssl::NameType::HOST_NAME),
+            offered_versions: &h.offered_versions,
+            offered_alpn: &h.offered_alpn,
+            version: Some(ssl.version_str()).filter(|v| *v != "unknown"),
+            alpn: ssl.selected_alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+            cipher: ssl.current_cipher().map(|c| c.name()),
+            outcome: if error.is_none() { "accepted" } else { "rejected" },
+            error: error.map(|e| e.to_string()),
+            chain: &h.chain,
+            verify_error: h.verify_error.as_ref(),
+            verdict: verdict.as_ref().map(|v| match v.action {
+                Action::Accept => "accept",
+                Action::Reject => "reject",
+            }),
+            rule: verdict.as_ref().and_then(|v| v.rule.as_deref()),
+        };
+        let result = serde_json::to_vec(&record).map_err(anyhow::Error::from)
+            .and_then(|line| self.append(&mut current, line));
+        if let Err(e) = result {
+            error!("audit: writing {}: {e:#}", self.path.display());
+        }
+    }
+
+    fn append(&self, current: &mut Current, mut line: Vec<u8>) -> Result<()> {
+        let hash = line_hash(&line);
+        line.push(b'\n');
+        let too_big = current.size > 0 && current.size + line.len() as u64 > self.max_bytes;
+        let too_old = self.max_age.is_some_and(|age| current.opened.elapsed().is_ok_and(|e| e >= age));
+        if too_big || (too_old && current.size > 0) {
+            self.rotate(current)?;
+        }
+        current.file.write_all(&line)?;
+        current.size += line.len() as u64;
+        current.first_seq.get_or_insert(current.next_seq);
+        current.next_seq += 1;
+        current.prev = hash;
+        if current.checkpointed.is_none_or(|at| at.elapsed() >= self.checkpoint_every) {
+            self.checkpoint(current)?;
+        }
+        Ok(())
+    }
+
+    /// Move the file aside under a timestamped name, seal it and start a new one that
+    /// continues the chain.
+    fn rotate(&self, current: &mut Current) -> Result<()> {
+        let stamp = compact(SystemTime::now());
+        let mut target = PathBuf::from(format!("{}.{stamp}", self.path.display()));
+        let mut n = 1;
+        while target.exists() {
+            target = PathBuf::from(format!("{}.{stamp}.{n}", self.path.display()));
+            n += 1;
+        }
+        std::fs::rename(&self.path, &target)
+            .with_context(|| format!("rotating {} to {}", self.path.display(), target.display()))?;
+        if let Some(first_seq) = current.first_seq {
+            // An unsealed file still verifies as a chain; don't stop logging over it.
+            if let Err(e) = self.seal(&target, first_seq, current) {
+                error!("audit: sealing {}: {e:#}", target.display());
+            }
+        }
+        *current = Current { next_seq: current.next_seq, prev: std::mem::take(&mut current.prev), ..open(&self.path)? };
+        // The new file's checkpoint covers the file just sealed, so that one can't later
+        // be passed off as the current file.
+        self.checkpoint(current)
+    }
+
+    fn seal(&self, path: &Path, first_seq: u64, current: &Current) -> Result<()> {
+        let last_seq = current.next_seq - 1;
+        let seal = Seal {
+            first_seq,
+            last_seq,
+            last_hash: current.prev.clone(),
+            key_sha256: key_sha256(&self.key)?,
+            signature: self.sign(&seal_message(first_seq, last_seq, &current.prev))?,
+        };
+        let sig = sig_path(path);
+        let mut json = serde_json::to_vec_pretty(&seal)?;
+        json.push(b'\n');
+        std::fs::write(&sig, json).with_context(|| format!("writing {}", sig.display()))
+    }
+
+    /// Sign the chain up to the last record into `<file>.checkpoint`, replacing the
+    /// previous checkpoint in one rename.
+    fn checkpoint(&self, current: &mut Current) -> Result<()> {
+        let ts = rfc3339(SystemTime::now());
+        let checkpoint = Checkpoint {
+            signature: self.sign(&checkpoint_message(&ts, current.next_seq, &current.prev))?,
+            ts,
+            next_seq: current.next_seq,
+            last_hash: current.prev.clone(),
+            key_sha256: key_sha256(&self.key)?,
+        };
+        let path = checkpoint_path(&self.path);
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        let mut json = serde_json::to_vec_pretty(&checkpoint)?;
+        json.push(b'\n');
+        std::fs::write(&tmp, json).with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("replacing {}", path.display()))?;
+        current.checkpointed = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Base64 signature over `message`.
+    fn sign(&self, message: &[u8]) -> Result<String> {
+        let mut signer = match self.key.id() {
+            Id::ED25519 | Id::ED448 => Signer::new_without_digest(&self.key)?,
+            _ => Signer::new(MessageDigest::sha256(), &self.key)?,
+        };
+        Ok(openssl::base64::encode_block(&signer.sign_oneshot_to_vec(message)?))
+    }
+}
+
+/// Signature over the end of one rotated file, stored beside it as `<file>.sig`.
+#[derive(Serialize, Deserialize)]
+struct Seal {
+    first_seq: u64,
+    last_seq: u64,
+    /// Hash of the record `last_seq`, which covers every record before it.
+    last_hash: String,
+    /// SHA-256 of the signing key's SubjectPublicKeyInfo.
+    key_sha256: String,
+    /// Base64 signature over [`seal_message`].
+    signature: String,
+}
+
+fn seal_message(first_seq: u64, last_seq: u64, last_hash: &str) -> Vec<u8> {
+    format!("mtls-audit-seal v1 {first_seq} {last_seq} {last_hash}").into_bytes()
+}
+
+fn sig_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.sig", path.display()))
+}
+
+/// Signature over the whole chain so far, kept beside the current file as
+/// `<file>.checkpoint` and replaced as it grows.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    /// When it was signed; records written after that aren't covered.
+    ts: String,
+    /// Every record before this one is covered.
+    next_seq: u64,
+    /// Hash of the record `next_seq - 1`, or zeros when there is none yet.
+    last_hash: String,
+    /// SHA-256 of the signing key's SubjectPublicKeyInfo.
+    key_sha256: String,
+    /// Base64 signature over [`checkpoint_message`].
+    signature: String,
+}
+
+fn checkpoint_message(ts: &str, next_seq: u64, last_hash: &str) -> Vec<u8> {
+    format!("mtls-audit-checkpoint v1 {ts} {next_seq} {last_hash}").into_bytes()
+}
+
+fn checkpoint_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.checkpoint", path.display()))
+}
+
+fn line_hash(line: &[u8]) -> String {
+    hex::encode(openssl::sha::sha256(line))
+}
+
+fn key_sha256<T: HasPublic>(key: &PKeyRef<T>) -> Result<String> {
+    Ok(hex::encode(openssl::sha::sha256(&key.public_key_to_der()?)))
+}
+
+/// The chain fields of a record, all `audit verify` and a restart need.
+#[derive(Deserialize)]
+struct Link {
+    seq: u64,
+    prev: String,
+}
+
+/// Open `path` for appending, owner-only when created, and pick up the chain from its
+/// last record.
+fn open(path: &Path) -> Result<Current> {
+    let mut options = std::fs::OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path).with_context(|| format!("opening {}", path.display()))?;
+    let meta = file.metadata()?;
+    // Age counts from when the file was started, across restarts where the filesystem
+    // knows.
+    let opened = meta.created().unwrap_or_else(|_| SystemTime::now());
+    let mut current = Current {
+        file,
+        size: meta.len(),
+        opened,
+        first_seq: None,
+        next_seq: 0,
+        prev: "0".repeat(64),
+        checkpointed: None,
+    };
+    if current.size > 0 {
+        let text = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let lines: Vec<&[u8]> = text.split(|&b| b == b'\n').filter(|l| !l.is_empty()).collect();
+        let link = |line: &[u8]| serde_json::from_slice::<Link>(line).map_err(|_| {
+            anyhow!("audit.file: {} holds records without seq and prev (written before chaining?); move it aside", path.display())
+        });
+        if let (Some(first), Some(last)) = (lines.first(), lines.last()) {
+            current.first_seq = Some(link(first)?.seq);
+            current.next_seq = link(last)?.seq + 1;
+            current.prev = line_hash(last);
+        }
+    }
+    Ok(current)
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuditCommand {
+    /// Check the hash chain and seals of audit files
+    Verify(VerifyArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Audit files, current and rotated, in any order (`audit.jsonl*` will do); each
+    /// file's `.sig` and `.checkpoint` are read from beside it
+    #[arg(required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Certificate or public key (PEM) of the key that signed the seals
+    #[arg(long)]
+    pub public_key: PathBuf,
+
+    /// Only rotated files are given, e.g. from an archive: every file must be sealed and
+    /// no checkpoint is expected
+    #[arg(long)]
+    pub sealed_only: bool,
+
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: Format,
+}
+
+pub fn run(command: AuditCommand) -> Result<()> {
+    match command {
+        AuditCommand::Verify(args) => {
+            let report = verify(&args)?;
+            report.print(args.format)?;
+            if report.failures > 0 {
+                bail!("audit log failed verification ({} problem(s))", report.failures);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// One audit file's records: line number, seq, prev and own hash.
+struct Segment {
+    path: PathBuf,
+    records: Vec<(usize, Link, String)>,
+}
+
+/// Walk the chain through every file in `seq` order, then check each file's seal. The
+/// newest file may be unsealed when the current file's checkpoint covers everything
+/// sealed before it; without `--sealed-only` that checkpoint is required.
+fn verify(args: &VerifyArgs) -> Result<Report> {
+    let pem = std::fs::read(&args.public_key).with_context(|| format!("reading {}", args.public_key.display()))?;
+    let public: PKey<Public> = match X509::from_pem(&pem) {
+        Ok(cert) => cert.public_key()?,
+        Err(_) => PKey::public_key_from_pem(&pem)
+            .with_context(|| format!("{} is neither a certificate nor a public key", args.public_key.display()))?,
+    };
+    let public_sha256 = key_sha256(&public)?;
+
+    let mut report = Report::default();
+    let mut segments = Vec::new();
+    let mut checkpoints = Vec::new();
+    let sidecar = |p: &Path| p.extension().is_some_and(|e| e == "sig" || e == "checkpoint" || e == "tmp");
+    for path in args.files.iter().filter(|p| !sidecar(p)) {
+        let item = path.display().to_string();
+        let checkpoint = checkpoint_path(path);
+        if !args.sealed_only && checkpoint.exists() {
+            let json = std::fs::read(&checkpoint).with_context(|| format!("reading {}", checkpoint.display()))?;
+            let parsed: Checkpoint = serde_json::from_slice(&json).with_context(|| format!("parsing {}", checkpoint.display()))?;
+            checkpoints.push((checkpoint, parsed));
+        }
+        let text = std::fs::read(path).with_context(|| format!("reading {item}"))?;
+        let mut records = Vec::new();
+        for (i, line) in text.split(|&b| b == b'\n').enumerate().filter(|(_, l)| !l.is_empty()) {
+            match serde_json::from_slice::<Link>(line) {
+                Ok(link) => records.push((i + 1, link, line_hash(line))),
+                Err(_) => report.push(Status::Fail, &format!("{item}:{}", i + 1), "not an audit record with seq and prev".into()),
+            }
+        }
+        if records.is_empty() {
+            report.push(Status::Warn, &item, "no records".into());
+        } else {
+            segments.push(Segment { path: path.clone(), records });
+        }
+    }
+    segments.sort_by_key(|s| s.records[0].1.seq);
+
+    let mut last: Option<(u64, &str)> = None;
+    for segment in &segments {
+        let item = segment.path.display().to_string();
+        for (line, link, hash) in &segment.records {
+            let at = format!("{item}:{line}");
+            match last {
+                None if link.seq > 0 => report.push(Status::Warn, &at,
+                    format!("the log starts at record {}; the files before it are missing", link.seq)),
+                None => {}
+                Some((seq, _)) if link.seq != seq + 1 => report.push(Status::Fail, &at,
+                    format!("record {} follows record {seq}: records were deleted or reordered", link.seq)),
+                Some((seq, prev)) if link.prev != prev => report.push(Status::Fail, &at,
+                    format!("record {} doesn't chain to record {seq}: a record was changed", link.seq)),
+                Some(_) => {}
+            }
+            last = Some((link.seq, hash));
+        }
+    }
+
+    // Every record up to here is sealed, which the checkpoint must cover.
+    let mut sealed_up_to = None;
+    let mut unsealed = None;
+    for (i, segment) in segments.iter().enumerate() {
+        let item = segment.path.display().to_string();
+        let (first, last) = (&segment.records[0], &segment.records[segment.records.len() - 1]);
+        let range = format!("records {}..={}", first.1.seq, last.1.seq);
+        let sig = sig_path(&segment.path);
+        if !sig.exists() {
+            if i + 1 == segments.len() && !args.sealed_only {
+                // Judged against the checkpoint below.
+                unsealed = Some((item, first.1.seq, last.1.seq));
+            } else {
+                report.push(Status::Fail, &item, format!("{range}, no seal: {} is missing", sig.display()));
+            }
+            continue;
+        }
+        let seal: Seal = serde_json::from_slice(&std::fs::read(&sig).with_context(|| format!("reading {}", sig.display()))?)
+            .with_context(|| format!("parsing {}", sig.display()))?;
+        let problem = if seal.key_sha256 != public_sha256 {
+            Some(format!("sealed by key {}, not {}", seal.key_sha256, args.public_key.display()))
+        } else if !seal_verifies(&public, &seal)? {
+            Some("seal signature doesn't verify".to_string())
+        } else if (seal.first_seq, seal.last_seq, seal.last_hash.as_str()) != (first.1.seq, last.1.seq, last.2.as_str()) {
+            Some(format!("seal covers records {}..={}, with a different last record: the file was cut or changed",
+                seal.first_seq, seal.last_seq))
+        } else {
+            None
+        };
+        match problem {
+            Some(problem) => report.push(Status::Fail, &item, format!("{range}, {problem}")),
+            None => report.push(Status::Ok, &item, format!("{range}, sealed")),
+        }
+        sealed_up_to = Some(last.1.seq);
+    }
+    if args.sealed_only {
+        return Ok(report);
+    }
+
+    // Records that exist, by seq, to match checkpoints against.
+    let hashes: std::collections::HashMap<u64, &str> = segments.iter()
+        .flat_map(|s| s.records.iter().map(|(_, link, hash)| (link.seq, hash.as_str())))
+        .collect();
+    let mut covered: Option<(u64, &str)> = None;
+    for (path, checkpoint) in &checkpoints {
+        let item = path.display().to_string();
+        // The last record it covers, if any.
+        let last_seq = checkpoint.next_seq.checked_sub(1);
+        let problem = if checkpoint.key_sha256 != public_sha256 {
+            Some(format!("signed by key {}, not {}", checkpoint.key_sha256, args.public_key.display()))
+        } else if !checkpoint_verifies(&public, checkpoint)? {
+            Some("checkpoint signature doesn't verify".to_string())
+        } else if let Some(seq) = last_seq.filter(|seq| !hashes.contains_key(seq)) {
+            Some(format!("covers record {seq}, which is missing: the log was cut"))
+        } else if last_seq.is_some_and(|seq| hashes[&seq] != checkpoint.last_hash) {
+            Some(format!("record {} isn't the one signed: the log was changed", checkpoint.next_seq - 1))
+        } else if last_seq.is_none() && checkpoint.last_hash != "0".repeat(64) {
+            Some("covers no record but names a hash".to_string())
+        } else if sealed_up_to.is_some_and(|sealed| last_seq.is_none_or(|seq| seq < sealed)) {
+            Some(format!("signed {} and older than the newest sealed file: a stale checkpoint was put back",
+                checkpoint.ts))
+        } else {
+            None
+        };
+        match problem {
+            Some(problem) => report.push(Status::Fail, &item, problem),
+            None => {
+                let what = match last_seq {
+                    Some(seq) => format!("records ..={seq}"),
+                    None => "an empty log".to_string(),
+                };
+                report.push(Status::Ok, &item, format!("signed {}, covers {what}", checkpoint.ts));
+                if covered.is_none_or(|(next, _)| checkpoint.next_seq > next) {
+                    covered = Some((checkpoint.next_seq, &checkpoint.ts));
+                }
+            }
+        }
+    }
+
+    match (unsealed, covered) {
+        (None, Some(_)) => {}
+        (None, None) => report.push(Status::Fail, "checkpoint",
+            "none found: give the current file too, or --sealed-only for rotated files alone".into()),
+        (Some((item, first, last)), None) => report.push(Status::Fail, &item,
+            format!("records {first}..={last}, neither sealed nor covered by a checkpoint")),
+        (Some((item, first, last)), Some((next, ts))) if next > last => report.push(Status::Ok, &item,
+            format!("records {first}..={last}, covered by the checkpoint signed {ts}")),
+        (Some((item, first, last)), Some((next, ts))) if next <= first => report.push(Status::Skip, &item,
+            format!("records {first}..={last}, written after the checkpoint signed {ts}")),
+        (Some((item, first, last)), Some((next, ts))) => report.push(Status::Skip, &item,
+            format!("records {first}..={last}, of which {next}..={last} were written after the checkpoint signed {ts}")),
+    }
+    Ok(report)
+}
+
+fn seal_verifies(public: &PKey<Public>, seal: &Seal) -> Result<bool> {
+    signature_verifies(public, &seal.signature, &seal_message(seal.first_seq, seal.last_seq, &seal.last_hash))
+}
+
+fn checkpoint_verifies(public: &PKey<Public>, checkpoint: &Checkpoint) -> Result<bool> {
+    let message = checkpoint_message(&checkpoint.ts, checkpoint.next_seq, &checkpoint.last_hash);
+    signature_verifies(public, &checkpoint.signature, &message)
+}
+
+fn signature_verifies(public: &PKey<Public>, signature: &str, message: &[u8]) -> Result<bool> {
+    let Ok(signature) = openssl::base64::decode_block(signature) else { return Ok(false) };
+    let mut verifier = match public.id() {
+        Id::ED25519 | Id::ED448 => Verifier::new_without_digest(public)?,
+        _ => Verifier::new(MessageDigest::sha256(), public)?,
+    };
+    // A malformed signature is an error stack, not a failed check.
+    Ok(verifier.verify_oneshot(&signature, message).unwrap_or(false))
+}
+
+/// `2026-01-31T12:00:00.000000Z`
+fn rfc3339(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (date, secs) = civil(d.as_secs());
+    format!("{date}T{:02}:{:02}:{:02}.{:06}Z", secs / 3600, secs / 60 % 60, secs % 60, d.subsec_micros())
+}
+
+/// `20260131T120000Z`, for rotated file names.
+fn compact(t: SystemTime) -> String {
+    let (date, secs) = civil(t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    format!("{}T{:02}{:02}{:02}Z", date.replace('-', ""), secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// UTC date (`YYYY-MM-DD`) and seconds into the day for seconds since the epoch.
+fn civil(epoch_secs: u64) -> (String, u64) {
+    // Howard Hinnant's days-to-civil algorithm.
+    let days = (epoch_secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (format!("{year:04}-{month:02}-{day:02}"), epoch_secs % 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// A scratch directory holding an Ed25519 signing key and its public half.
+    fn scratch(name: &str) -> PathBuf {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir()
+            .join(format!("audit-{name}-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = PKey::generate_ed25519().unwrap();
+        std::fs::write(dir.join("sign.key"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        std::fs::write(dir.join("sign.pub"), key.public_key_to_pem().unwrap()).unwrap();
+        dir
+    }
+
+    /// The log in `dir`, rotating after two of the records [`write`] adds.
+    fn log(dir: &Path, checkpoint_secs: u64) -> AuditLog {
+        let config = AuditConfig {
+            file: Some(dir.join("audit.jsonl")),
+            max_bytes: 4096,
+            max_age_secs: 0,
+            checkpoint_secs,
+            signing_key: Some(dir.join("sign.key")),
+            signing_key_password: None,
+        };
+        AuditLog::open(&config, &IdentityConfig::default()).unwrap().unwrap()
+    }
+
+    fn write(log: &AuditLog, records: usize) {
+        for _ in 0..records {
+            let mut current = log.current.lock().unwrap();
+            let line = serde_json::to_vec(&serde_json::json!({
+                "seq": current.next_seq,
+                "prev": current.prev,
+                "pad": "x".repeat(1500),
+            }))
+            .unwrap();
+            log.append(&mut current, line).unwrap();
+        }
+    }
+
+    /// `audit verify --public-key sign.pub audit.jsonl*`.
+    fn verify_dir(dir: &Path, sealed_only: bool) -> Report {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("audit.jsonl"))
+            .collect();
+        files.sort();
+        verify(&VerifyArgs { files, public_key: dir.join("sign.pub"), sealed_only, format: Format::Text }).unwrap()
+    }
+
+    fn rotated(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| {
+                let name = p.file_name().unwrap().to_string_lossy().into_owned();
+                name.starts_with("audit.jsonl.") && !name.ends_with(".sig") && !name.ends_with(".checkpoint")
+            })
+            .collect();
+        files.sort_by_key(|p| first_seq(p));
+        files
+    }
+
+    fn first_seq(path: &Path) -> u64 {
+        let text = std::fs::read(path).unwrap();
+        serde_json::from_slice::<Link>(text.split(|&b| b == b'\n').next().unwrap()).unwrap().seq
+    }
+
+    /// Rewrite the records of `path` from line `from` on and re-chain them, as someone
+    /// with write access but no signing key could.
+    fn forge(path: &Path, from: usize) {
+        let text = String::from_utf8(std::fs::read(path).unwrap()).unwrap();
+        let mut prev = None::<String>;
+        let mut out = String::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut record: serde_json::Value = serde_json::from_str(line).unwrap();
+            if i >= from {
+                record["pad"] = "forged".into();
+                if let Some(prev) = &prev {
+                    record["prev"] = prev.clone().into();
+                }
+            }
+            let line = serde_json::to_string(&record).unwrap();
+            prev = Some(line_hash(line.as_bytes()));
+            out.push_str(&line);
+            out.push('\n');
+        }
+        std::fs::write(path, out).unwrap();
+    }
+
+    fn failures(report: &Report) -> Vec<String> {
+        report.findings.iter().filter(|f| f.status == Status::Fail).map(|f| f.message.clone()).collect()
+    }
+
+    #[test]
+    fn intact_log_verifies() {
+        let dir = scratch("intact");
+        write(&log(&dir, 3600), 7);
+        assert_eq!(rotated(&dir).len(), 3);
+        let report = verify_dir(&dir, false);
+        assert_eq!(report.failures, 0, "{:?}", failures(&report));
+        // The seventh record came after the checkpoint signed at rotation.
+        assert!(report.findings.iter().any(|f| f.status == Status::Skip && f.message.contains("records 6..=6, written after")));
+    }
+
+    #[test]
+    fn checkpoint_covers_the_current_file() {
+        let dir = scratch("checkpointed");
+        let log = log(&dir, 1);
+        write(&log, 5);
+        std::thread::sleep(Duration::from_millis(1100));
+        write(&log, 1);
+        let report = verify_dir(&dir, false);
+        assert_eq!(report.failures, 0, "{:?}", failures(&report));
+        assert!(report.findings.iter().any(|f| f.message.contains("records 4..=5, covered by the checkpoint")));
+
+        // Cutting covered records from the current file shows.
+        let current = dir.join("audit.jsonl");
+        let text = std::fs::read_to_string(&current).unwrap();
+        std::fs::write(&current, text.lines().next().unwrap().to_string() + "\n").unwrap();
+        let report = verify_dir(&dir, false);
+        assert!(failures(&report).iter().any(|m| m.contains("covers record 5, which is missing")), "{:?}", failures(&report));
+    }
+
+    #[test]
+    fn rotated_file_passed_off_as_current_fails() {
+        // Delete the current file and the newest seal, then rewrite the newest rotated
+        // file, with and without the checkpoint, under its own name or the current one.
+        for (keep_checkpoint, rename) in [(false, false), (true, false), (false, true), (true, true)] {
+            let dir = scratch("passed-off");
+            write(&log(&dir, 3600), 6);
+            let newest = rotated(&dir).pop().unwrap();
+            std::fs::remove_file(dir.join("audit.jsonl")).unwrap();
+            std::fs::remove_file(sig_path(&newest)).unwrap();
+            if !keep_checkpoint {
+                std::fs::remove_file(dir.join("audit.jsonl.checkpoint")).unwrap();
+            }
+            let target = if rename { dir.join("audit.jsonl") } else { newest.clone() };
+            std::fs::rename(&newest, &target).unwrap();
+            forge(&target, 1);
+
+            let report = verify_dir(&dir, false);
+            assert!(report.failures > 0, "checkpoint kept: {keep_checkpoint}, renamed: {rename}");
+        }
+    }
+
+    #[test]
+    fn stale_checkpoint_fails() {
+        let dir = scratch("stale");
+        let log = log(&dir, 3600);
+        write(&log, 1);
+        let checkpoint = dir.join("audit.jsonl.checkpoint");
+        let stale = std::fs::read(&checkpoint).unwrap();
+        write(&log, 4);
+        std::fs::write(&checkpoint, stale).unwrap();
+        let report = verify_dir(&dir, false);
+        assert!(failures(&report).iter().any(|m| m.contains("stale checkpoint")), "{:?}", failures(&report));
+    }
+
+    #[test]
+    fn checkpoint_is_required_unless_sealed_only() {
+        let dir = scratch("sealed-only");
+        write(&log(&dir, 3600), 5);
+        std::fs::remove_file(dir.join("audit.jsonl")).unwrap();
+        std::fs::remove_file(dir.join("audit.jsonl.checkpoint")).unwrap();
+        assert!(failures(&verify_dir(&dir, false)).iter().any(|m| m.contains("--sealed-only")));
+        assert_eq!(verify_dir(&dir, true).failures, 0);
+
+        // Archived files must all be sealed.
+        std::fs::remove_file(sig_path(&rotated(&dir).pop().unwrap())).unwrap();
+        assert!(failures(&verify_dir(&dir, true)).iter().any(|m| m.contains("no seal")));
+    }
+
+    #[test]
+    fn restart_after_rotation_continues_the_chain() {
+        let dir = scratch("restart");
+        write(&log(&dir, 3600), 2);
+        // The third record rotated the file; drop it so the restart finds it empty.
+        {
+            let log = log(&dir, 3600);
+            write(&log, 1);
+            let current = log.current.lock().unwrap();
+            current.file.set_len(0).unwrap();
+        }
+        let log = log(&dir, 3600);
+        assert_eq!(log.current.lock().unwrap().next_seq, 2);
+        write(&log, 1);
+        let report = verify_dir(&dir, false);
+        assert_eq!(report.failures, 0, "{:?}", failures(&report));
+    }
+}
@@ -0,0 +1,128 @@
+//! Command-line mTLS client: send one request and show what the handshake negotiated,
+//! tunnel local plaintext connections to the server, or renew the client certificate.
+
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use std::io::Write;
+use std::time::Duration;
+
+use tokio_openssl_server::client::{self, Client, ClientConfig, ClientRequest, ClientTunnelArgs};
+use tokio_openssl_server::http::reason;
+use tokio_openssl_server::logging::{self, LogFormat, LogLevel, LoggingConfig};
+use tokio_openssl_server::policy::serial_hex;
+use tokio_openssl_server::renew;
+
+#[derive(Parser)]
+#[command(version, about = "mTLS client with a pinned server CA")]
+struct Cli {
+    /// Log level (error, warn, info, debug, trace)
+    #[arg(long, global = true, env = "MTLS_LOG_LEVEL")]
+    log_level: Option<LogLevel>,
+
+    /// Log format (text, json)
+    #[arg(long, global = true, env = "MTLS_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Send one HTTP request; print the protocol, cipher, server chain and response
+    Request(RequestArgs),
+    /// Accept plaintext locally and tunnel each connection to the server
+    Tunnel(ClientTunnelArgs),
+    /// Re-enroll the client certificate with the server (EST simplereenroll) and replace
+    /// its files
+    Renew(RenewCommandArgs),
+}
+
+#[derive(Args)]
+struct RequestArgs {
+    #[command(flatten)]
+    client: ClientConfig,
+
+    #[arg(short = 'X', long, default_value = "GET")]
+    method: String,
+
+    /// Path and query to request
+    #[arg(long, default_value = "/")]
+    path: String,
+
+    /// Extra request header, `Name: value` (repeatable)
+    #[arg(short = 'H', long = "header")]
+    headers: Vec<String>,
+
+    /// Request body
+    #[arg(short, long)]
+    data: Option<String>,
+
+    /// Time allowed after the handshake for the whole exchange
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout_secs: u64,
+}
+
+#[derive(Args)]
+struct RenewCommandArgs {
+    #[command(flatten)]
+    client: ClientConfig,
+
+    /// Only renew once this fraction of the certificate's lifetime has passed (for cron);
+    /// always when unset
+    #[arg(long, value_parser = renew::parse_fraction)]
+    renew_at: Option<f64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    logging::init(&LoggingConfig {
+        level: cli.log_level.unwrap_or_default(),
+        format: cli.log_format.unwrap_or_default(),
+        ..LoggingConfig::default()
+    })?;
+
+    match cli.command {
+        Command::Request(args) => request(args).await,
+        Command::Tunnel(args) => client::run_tunnel(args).await,
+        Command::Renew(args) => {
+            let client = Client::new(&args.client)?;
+            match renew::renew(&client, args.renew_at).await? {
+                Some(cert) => println!("Renewed: serial={} valid until {}", serial_hex(&cert), cert.not_after()),
+                None => println!("Not due yet"),
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn request(args: RequestArgs) -> Result<()> {
+    let mut headers = Vec::new();
+    for header in &args.headers {
+        let (name, value) = header.split_once(':')
+            .ok_or_else(|| anyhow!("--header: expected `Name: value`, got {header:?}"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let req = ClientRequest {
+        method: args.method,
+        path: args.path,
+        headers,
+        body: args.data.unwrap_or_default().into_bytes(),
+    };
+
+    let client = Client::new(&args.client)?;
+    let (session, resp) = client.request(&req, Duration::from_secs(args.timeout_secs)).await?;
+
+    let mut out = std::io::stdout().lock();
+    write!(out, "{session}")?;
+    writeln!(out, "---")?;
+    writeln!(out, "{} {}", resp.status, reason(resp.status))?;
+    for (name, value) in &resp.headers {
+        writeln!(out, "{name}: {value}")?;
+    }
+    writeln!(out)?;
+    out.write_all(&resp.body)?;
+    out.flush()?;
+    Ok(())
+}
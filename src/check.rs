@@ -0,0 +1,479 @@
+//! Preflight checks of the server's identity and trust material, run at startup and by
+//! the `check` subcommand, so a bad file shows up before the first client does:
+//!
+//! - every identity: the key matches the certificate, the chain verifies to the roots in
+//!   `server_ca`, the leaf has the serverAuth EKU and subjectAltNames covering the
+//!   hostnames clients use, and no certificate expires within `expiry_warn_days`;
+//! - every client CA bundle: it parses, holds only CA certificates and none has expired.
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, ValueEnum};
+use foreign_types::ForeignTypeRef;
+use openssl::asn1::Asn1Time;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509Ref, X509StoreContext, X509VerifyResult, X509};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+use crate::config::{check_file, Config, IdentityConfig, TrustConfig};
+use crate::policy::{extended_key_usage, ip_from_bytes};
+use crate::tls::{host_matches, load_identity};
+use crate::x509_name_to_string;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckConfig {
+    pub startup: Startup,
+    /// Roots (PEM) clients verify the server chain against; the chain isn't verified
+    /// when unset.
+    pub server_ca: Option<PathBuf>,
+    /// Names clients connect to the top-level identity with. `[[sni.hosts]]` identities
+    /// are checked against their own `names`.
+    pub hostnames: Vec<String>,
+    pub expiry_warn_days: u32,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        CheckConfig { startup: Startup::default(), server_ca: None, hostnames: Vec::new(), expiry_warn_days: 30 }
+    }
+}
+
+impl CheckConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(file) = &self.server_ca {
+            check_file("check.server_ca", file)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the checks do when the server starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Startup {
+    /// Print warnings and failures, then start anyway.
+    #[default]
+    Warn,
+    /// Refuse to start if any check fails.
+    Fail,
+    Off,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: Format,
+
+    /// Roots the server chain must verify to; replaces `check.server_ca`
+    #[arg(long)]
+    pub server_ca: Option<PathBuf>,
+
+    /// Hostname the top-level certificate must cover (repeatable); replaces `check.hostnames`
+    #[arg(long = "hostname")]
+    pub hostnames: Vec<String>,
+
+    /// Warn about certificates expiring within this many days; replaces `check.expiry_warn_days`
+    #[arg(long)]
+    pub expiry_warn_days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Not checked, e.g. no roots to verify against.
+    Skip,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub status: Status,
+    /// The config key the finding is about, e.g. `sni.hosts[0].identity`.
+    pub item: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub failures: usize,
+    pub warnings: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    pub(crate) fn push(&mut self, status: Status, item: &str, message: String) {
+        match status {
+            Status::Fail => self.failures += 1,
+            Status::Warn => self.warnings += 1,
+            Status::Ok | Status::Skip => {}
+        }
+        self.findings.push(Finding { status, item: item.to_string(), message });
+    }
+
+    pub fn print(&self, format: Format) -> Result<()> {
+        match format {
+            Format::Json => println!("{}", serde_json::to_string_pretty(self)?),
+            Format::Text => {
+                for f in &self.findings {
+                    println!("{}", f.line());
+                }
+                println!("{} failed, {} warnings", self.failures, self.warnings);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Finding {
+    fn line(&self) -> String {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Skip => "skip",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        format!("{status:<4}  {}: {}", self.item, self.message)
+    }
+}
+
+/// The `check` subcommand: print every finding and fail if any check did.
+pub fn run(config: &Config, args: &CheckArgs) -> Result<()> {
+    let mut settings = CheckConfig {
+        startup: config.check.startup,
+        server_ca: args.server_ca.clone().or_else(|| config.check.server_ca.clone()),
+        hostnames: config.check.hostnames.clone(),
+        expiry_warn_days: args.expiry_warn_days.unwrap_or(config.check.expiry_warn_days),
+    };
+    if !args.hostnames.is_empty() {
+        settings.hostnames = args.hostnames.clone();
+    }
+    let report = check(config, &settings)?;
+    report.print(args.format)?;
+    if report.failures > 0 {
+        bail!("{} check(s) failed", report.failures);
+    }
+    Ok(())
+}
+
+/// Startup preflight per `check.startup`: warnings and failures go to stderr.
+pub fn preflight(config: &Config) -> Result<()> {
+    if config.check.startup == Startup::Off {
+        return Ok(());
+    }
+    let report = check(config, &config.check)?;
+    for f in &report.findings {
+        match f.status {
+            Status::Fail => error!(item = f.item, "check failed: {}", f.message),
+            Status::Warn => warn!(item = f.item, "check: {}", f.message),
+            Status::Ok | Status::Skip => {}
+        }
+    }
+    if report.failures > 0 && config.check.startup == Startup::Fail {
+        bail!("{} startup check(s) failed (check.startup = \"fail\")", report.failures);
+    }
+    Ok(())
+}
+
+/// Run every check on `config`. Only an unreadable `server_ca` is an error; everything
+/// else becomes a finding.
+pub fn check(config: &Config, settings: &CheckConfig) -> Result<Report> {
+    let roots = match &settings.server_ca {
+        Some(path) => {
+            let pem = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+            let certs = X509::stack_from_pem(&pem).with_context(|| format!("loading {}", path.display()))?;
+            Some((path.as_path(), certs))
+        }
+        None => None,
+    };
+    let roots = roots.as_ref().map(|(path, certs)| (*path, certs.as_slice()));
+    let days = settings.expiry_warn_days;
+
+    let mut report = Report::default();
+    check_identity(&mut report, "identity", &config.identity, &settings.hostnames, roots, days)?;
+    for (i, identity) in config.extra_identities.iter().enumerate() {
+        check_identity(&mut report, &format!("extra_identities[{i}]"), identity, &settings.hostnames, roots, days)?;
+    }
+    check_trust(&mut report, "trust.client_ca", &config.trust, days)?;
+    for (i, host) in config.sni.hosts.iter().enumerate() {
+        let key = format!("sni.hosts[{i}]");
+        check_identity(&mut report, &format!("{key}.identity"), &host.identity, &host.names, roots, days)?;
+        for (j, identity) in host.extra_identities.iter().enumerate() {
+            check_identity(&mut report, &format!("{key}.extra_identities[{j}]"), identity, &host.names, roots, days)?;
+        }
+        if let Some(trust) = &host.trust {
+            check_trust(&mut report, &format!("{key}.trust.client_ca"), trust, days)?;
+        }
+    }
+    Ok(report)
+}
+
+fn check_identity(
+    report: &mut Report,
+    item: &str,
+    config: &IdentityConfig,
+    hostnames: &[String],
+    roots: Option<(&Path, &[X509])>,
+    days: u32,
+) -> Result<()> {
+    let identity = match load_identity(config) {
+        Ok(identity) => identity,
+        Err(e) => {
+            report.push(Status::Fail, item, format!("cannot load: {e:#}"));
+            return Ok(());
+        }
+    };
+    let cert = &identity.cert;
+    let subject = x509_name_to_string(cert.subject_name());
+
+    if cert.public_key()?.public_eq(&identity.key) {
+        report.push(Status::Ok, item, format!("private key matches {subject}"));
+    } else {
+        report.push(Status::Fail, item, format!("private key does not match {subject}"));
+    }
+
+    match roots {
+        Some((path, roots)) => match verify_chain(cert, &identity.chain, roots)? {
+            None => report.push(Status::Ok, item, format!("chain verifies to {}", path.display())),
+            Some(e) => report.push(Status::Fail, item, format!("chain does not verify to {}: {e}", path.display())),
+        },
+        None => report.push(Status::Skip, item, "chain not verified: no server_ca given".to_string()),
+    }
+
+    if extension_flags(cert) & openssl_sys::EXFLAG_XKUSAGE == 0 {
+        report.push(Status::Warn, item, "no extendedKeyUsage; the certificate is good for any purpose".to_string());
+    } else if extended_key_usage(cert) & openssl_sys::XKU_SSL_SERVER == 0 {
+        report.push(Status::Fail, item, "extendedKeyUsage lacks serverAuth; clients will reject it".to_string());
+    } else {
+        report.push(Status::Ok, item, "extendedKeyUsage includes serverAuth".to_string());
+    }
+
+    match sans(cert) {
+        None => report.push(Status::Fail, item, "no subjectAltName; clients ignore the CN".to_string()),
+        Some(_) if hostnames.is_empty() => report.push(Status::Skip, item, "no hostnames to match the subjectAltNames against".to_string()),
+        Some((dns, ips)) => {
+            let missing: Vec<&str> = hostnames.iter()
+                .filter(|name| !covers(&dns, &ips, name))
+                .map(String::as_str)
+                .collect();
+            if missing.is_empty() {
+                report.push(Status::Ok, item, format!("subjectAltName covers {}", hostnames.join(", ")));
+            } else {
+                let has: Vec<String> = dns.iter().cloned().chain(ips.iter().map(IpAddr::to_string)).collect();
+                report.push(Status::Fail, item, format!("subjectAltName does not cover {} (has {})",
+                    missing.join(", "), has.join(", ")));
+            }
+        }
+    }
+
+    check_validity(report, item, std::iter::once(cert).chain(&identity.chain), days)
+}
+
+fn check_trust(report: &mut Report, item: &str, trust: &TrustConfig, days: u32) -> Result<()> {
+    let path = &trust.client_ca;
+    let certs = match std::fs::read(path).map_err(anyhow::Error::from).and_then(|pem| Ok(X509::stack_from_pem(&pem)?)) {
+        Ok(certs) if certs.is_empty() => {
+            report.push(Status::Fail, item, format!("{} holds no certificate", path.display()));
+            return Ok(());
+        }
+        Ok(certs) => certs,
+        Err(e) => {
+            report.push(Status::Fail, item, format!("cannot load {}: {e:#}", path.display()));
+            return Ok(());
+        }
+    };
+
+    let not_ca: Vec<String> = certs.iter()
+        .filter(|c| !is_ca(c))
+        .map(|c| x509_name_to_string(c.subject_name()))
+        .collect();
+    if not_ca.is_empty() {
+        report.push(Status::Ok, item, format!("{} CA certificate(s) in {}", certs.len(), path.display()));
+    } else {
+        for subject in not_ca {
+            report.push(Status::Fail, item, format!("{subject} is not a CA certificate"));
+        }
+    }
+    check_validity(report, item, &certs, days)
+}
+
+/// Fail for certificates outside their validity period, warn for those expiring within
+/// `days`; one line for the first certificate when all is well.
+fn check_validity<'a>(report: &mut Report, item: &str, certs: impl IntoIterator<Item = &'a X509>, days: u32) -> Result<()> {
+    let now = Asn1Time::days_from_now(0)?;
+    let soon = Asn1Time::days_from_now(days)?;
+    let mut first = None;
+    let mut clean = true;
+    for cert in certs {
+        let subject = x509_name_to_string(cert.subject_name());
+        let (not_before, not_after) = (cert.not_before(), cert.not_after());
+        first.get_or_insert(not_after);
+        if not_before > now {
+            report.push(Status::Fail, item, format!("{subject} is not valid until {not_before}"));
+        } else if not_after < now {
+            report.push(Status::Fail, item, format!("{subject} expired on {not_after}"));
+        } else if not_after < soon {
+            report.push(Status::Warn, item, format!("{subject} expires in {} day(s), on {not_after}", now.diff(not_after)?.days));
+        } else {
+            continue;
+        }
+        clean = false;
+    }
+    if let (true, Some(not_after)) = (clean, first) {
+        report.push(Status::Ok, item, format!("valid until {not_after}"));
+    }
+    Ok(())
+}
+
+/// The verification error for `leaf` + `chain` against `roots`, if any.
+fn verify_chain(leaf: &X509Ref, chain: &[X509], roots: &[X509]) -> Result<Option<String>> {
+    let mut store = X509StoreBuilder::new()?;
+    for root in roots {
+        store.add_cert(root.clone())?;
+    }
+    let store = store.build();
+    let mut untrusted = Stack::new()?;
+    for cert in chain {
+        untrusted.push(cert.clone())?;
+    }
+    let mut ctx = X509StoreContext::new()?;
+    ctx.init(&store, leaf, &untrusted, |ctx| {
+        Ok(match ctx.verify_cert()? {
+            true => None,
+            false => Some(describe(ctx.error(), ctx.error_depth())),
+        })
+    }).map_err(Into::into)
+}
+
+fn describe(error: X509VerifyResult, depth: u32) -> String {
+    format!("{} ({}) at depth {depth}", error.error_string(), error.as_raw())
+}
+
+/// DNS names and IP addresses among the subjectAltNames, or `None` without the extension.
+fn sans(cert: &X509Ref) -> Option<(Vec<String>, Vec<IpAddr>)> {
+    let names = cert.subject_alt_names()?;
+    let dns = names.iter().filter_map(|n| n.dnsname()).map(str::to_string).collect();
+    let ips = names.iter().filter_map(|n| n.ipaddress()).filter_map(ip_from_bytes).collect();
+    Some((dns, ips))
+}
+
+/// Whether a certificate with these SANs is accepted for `name`. An SNI pattern such as
+/// `*.example.com` needs the same wildcard in the certificate.
+fn covers(dns: &[String], ips: &[IpAddr], name: &str) -> bool {
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return ips.contains(&ip);
+    }
+    if name.starts_with("*.") {
+        return dns.iter().any(|san| san.eq_ignore_ascii_case(name));
+    }
+    dns.iter().any(|san| host_matches(san, name))
+}
+
+fn is_ca(cert: &X509Ref) -> bool {
+    extension_flags(cert) & openssl_sys::EXFLAG_CA != 0
+}
+
+/// `EXFLAG_*` bits: which extensions the certificate has and what they say.
+fn extension_flags(cert: &X509Ref) -> u32 {
+    // SAFETY: the certificate is valid for the call; the flags are cached on it.
+    unsafe { openssl_sys::X509_get_extension_flags(cert.as_ptr()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pki::{self, KeyType, Sans, Usage};
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::X509NameBuilder;
+
+    struct Leaf {
+        cert: X509,
+        key: PKey<Private>,
+        ca: X509,
+    }
+
+    /// A leaf for `localhost` and 127.0.0.1 from a fresh CA, valid for `days`.
+    fn leaf(usage: Usage, days: u32) -> Leaf {
+        let ca_key = KeyType::EcP256.generate().unwrap();
+        let ca = pki::ca_cert(&ca_key, "CAs", "Check CA", 30, None).unwrap();
+        let key = KeyType::EcP256.generate().unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let sans = Sans { dns: vec!["localhost".into()], ip: vec!["127.0.0.1".parse().unwrap()], ..Sans::default() };
+        let cert = pki::sign_leaf(&name.build(), &key, &sans, usage, days, (&ca, &ca_key)).unwrap();
+        Leaf { cert, key, ca }
+    }
+
+    /// The findings for `leaf` served under `hostnames`, with `key` as its private key.
+    fn findings(name: &str, leaf: &Leaf, key: &PKey<Private>, hostnames: &[&str]) -> Report {
+        let dir = std::env::temp_dir().join(format!("check-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, [leaf.cert.to_pem().unwrap(), leaf.ca.to_pem().unwrap()].concat()).unwrap();
+        std::fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let identity = IdentityConfig::Pem { cert, key: key_file, key_password: None };
+        let hostnames: Vec<String> = hostnames.iter().map(|h| h.to_string()).collect();
+
+        let mut report = Report::default();
+        let roots = [leaf.ca.clone()];
+        check_identity(&mut report, "identity", &identity, &hostnames, Some((Path::new("ca.pem"), &roots)), 30).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        report
+    }
+
+    /// The findings of `status`, by message.
+    fn with(report: &Report, status: Status) -> Vec<&str> {
+        report.findings.iter().filter(|f| f.status == status).map(|f| f.message.as_str()).collect()
+    }
+
+    #[test]
+    fn a_good_identity_passes() {
+        let leaf = leaf(Usage::Server, 90);
+        let report = findings("good", &leaf, &leaf.key, &["localhost", "127.0.0.1"]);
+        assert_eq!((report.failures, report.warnings), (0, 0), "{:?}", report.findings);
+    }
+
+    #[test]
+    fn a_mismatched_key_fails() {
+        let leaf = leaf(Usage::Server, 90);
+        let other = KeyType::EcP256.generate().unwrap();
+        let report = findings("mismatched-key", &leaf, &other, &["localhost"]);
+        assert_eq!(with(&report, Status::Fail), ["private key does not match CN=localhost"]);
+    }
+
+    #[test]
+    fn a_missing_server_auth_eku_fails() {
+        let leaf = leaf(Usage::Client, 90);
+        let report = findings("client-eku", &leaf, &leaf.key, &["localhost"]);
+        assert_eq!(with(&report, Status::Fail), ["extendedKeyUsage lacks serverAuth; clients will reject it"]);
+    }
+
+    #[test]
+    fn uncovered_hostnames_fail() {
+        let leaf = leaf(Usage::Server, 90);
+        let report = findings("uncovered", &leaf, &leaf.key, &["localhost", "api.example.com", "10.0.0.1"]);
+        assert_eq!(with(&report, Status::Fail),
+            ["subjectAltName does not cover api.example.com, 10.0.0.1 (has localhost, 127.0.0.1)"]);
+    }
+
+    #[test]
+    fn near_expiry_warns() {
+        let leaf = leaf(Usage::Server, 1);
+        let report = findings("near-expiry", &leaf, &leaf.key, &["localhost"]);
+        assert_eq!(report.failures, 0, "{:?}", report.findings);
+        let warnings = with(&report, Status::Warn);
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].starts_with("CN=localhost expires in "), "{warnings:?}");
+    }
+}
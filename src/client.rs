@@ -0,0 +1,438 @@
+//! The client side: connect to an mTLS server with our client certificate, verifying
+//! the server against a pinned CA only (never the system roots) and its certificate
+//! against the expected hostname.
+//!
+//! [`Client`] opens connections and sends single HTTP requests (over h2 when ALPN picks
+//! it); [`ClientTunnel`] carries plaintext local connections to the server
+//! (stunnel/ghostunnel client mode) for applications that can't speak mTLS themselves.
+//! [`crate::renew`] re-enrolls a client's certificate as it nears expiry; the tunnel
+//! can do that in the background.
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use clap::{ArgGroup, Args};
+use openssl::ssl::{SslConnector, SslMethod, SslRef};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509VerifyResult, X509};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use tokio_openssl::SslStream;
+use tracing::{debug, info, warn, Instrument};
+
+use crate::config::{default_pkcs12_password, IdentityConfig};
+use crate::http::{read_response, Response};
+use crate::logging;
+use crate::renew::{self, RenewArgs};
+use crate::secret::Secret;
+use crate::tls::{load_identity, Identity};
+use crate::tunnel::{pipe, Limits};
+use crate::upstream::{Io, Upstream};
+use crate::x509_name_to_string;
+
+/// Responses with a larger head are rejected.
+const MAX_RESPONSE_HEAD: usize = 64 * 1024;
+/// Responses with a larger body are rejected.
+const MAX_RESPONSE_BODY: usize = 16 * 1024 * 1024;
+
+/// Where to connect and with which identity; the command-line flags of both client
+/// subcommands.
+#[derive(Debug, Clone, Args)]
+#[command(group(ArgGroup::new("identity").required(true).args(["cert", "pkcs12"])))]
+pub struct ClientConfig {
+    /// Server to connect to (`host:port` or `unix:/path`)
+    #[arg(long, value_parser = parse_upstream)]
+    pub connect: Upstream,
+
+    /// Name sent in SNI and matched against the server certificate; defaults to the
+    /// host in --connect
+    #[arg(long)]
+    pub server_name: Option<String>,
+
+    /// CA bundle the server chain must verify against, e.g. pki/ca_server/ca.crt
+    #[arg(long)]
+    pub ca: PathBuf,
+
+    /// Accept a server chain that ends at any certificate in --ca, such as an
+    /// intermediate, rather than only at a self-signed root
+    #[arg(long)]
+    pub partial_chain: bool,
+
+    /// Client certificate chain (PEM)
+    #[arg(long, requires = "key", conflicts_with = "pkcs12")]
+    pub cert: Option<PathBuf>,
+
+    /// Client private key (PEM)
+    #[arg(long, requires = "cert")]
+    pub key: Option<PathBuf>,
+
+    /// Password of an encrypted --key: pass:, env:, file:, stdin or credential:
+    #[arg(long, env = "MTLS_CLIENT_KEY_PASSWORD", hide_env_values = true, requires = "key", value_parser = Secret::parse)]
+    pub key_password: Option<Secret>,
+
+    /// Client PKCS#12 bundle, e.g. client.p12
+    #[arg(long)]
+    pub pkcs12: Option<PathBuf>,
+
+    /// Password for the PKCS#12 bundle: pass:, env:, file:, stdin or credential:
+    #[arg(long, env = "MTLS_CLIENT_PKCS12_PASSWORD", hide_env_values = true, value_parser = Secret::parse)]
+    pub pkcs12_password: Option<Secret>,
+
+    /// ALPN protocols to offer, most preferred first (e.g. h2,http/1.1)
+    #[arg(long, value_delimiter = ',')]
+    pub alpn: Vec<String>,
+
+    /// Time allowed for the TCP connect and, separately, the TLS handshake
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub connect_timeout_secs: u64,
+}
+
+fn parse_upstream(s: &str) -> Result<Upstream> {
+    Upstream::try_from(s.to_string())
+}
+
+impl ClientConfig {
+    /// The identity files named by --cert/--key or --pkcs12.
+    pub fn identity(&self) -> IdentityConfig {
+        match (&self.pkcs12, &self.cert, &self.key) {
+            (Some(path), _, _) => IdentityConfig::Pkcs12 {
+                path: path.clone(),
+                password: self.pkcs12_password.clone().unwrap_or_else(default_pkcs12_password),
+            },
+            (None, Some(cert), Some(key)) => IdentityConfig::Pem {
+                cert: cert.clone(),
+                key: key.clone(),
+                key_password: self.key_password.clone(),
+            },
+            // Only reachable from library callers; the same default as the server's.
+            _ => IdentityConfig::default(),
+        }
+    }
+
+    fn server_name(&self) -> Result<String> {
+        if let Some(name) = &self.server_name {
+            return Ok(name.clone());
+        }
+        match &self.connect {
+            Upstream::Tcp(addr) => {
+                let (host, _) = addr.rsplit_once(':').expect("validated host:port");
+                Ok(host.trim_start_matches('[').trim_end_matches(']').to_string())
+            }
+            #[cfg(unix)]
+            Upstream::Unix(_) => bail!("--server-name: required with a unix: address"),
+        }
+    }
+}
+
+/// TLS client context presenting `identity`, trusting only the CAs in `ca` (as anchors
+/// anywhere in the chain with `partial_chain`) and offering `alpn` (none when empty).
+pub fn connector(identity: &Identity, ca: &Path, partial_chain: bool, alpn: &[String]) -> Result<SslConnector> {
+    let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+    builder.set_private_key(&identity.key)?;
+    builder.set_certificate(&identity.cert)?;
+    for cert in &identity.chain {
+        builder.add_extra_chain_cert(cert.clone())?;
+    }
+    builder.check_private_key().context("client key does not match its certificate")?;
+
+    // Replace the default store, which SslConnector fills from the system roots.
+    let pem = std::fs::read(ca).with_context(|| format!("reading {}", ca.display()))?;
+    let cas = X509::stack_from_pem(&pem).with_context(|| format!("loading {}", ca.display()))?;
+    if cas.is_empty() {
+        bail!("{} has no certificate", ca.display());
+    }
+    let mut store = X509StoreBuilder::new()?;
+    for cert in cas {
+        store.add_cert(cert)?;
+    }
+    builder.set_cert_store(store.build());
+    if partial_chain {
+        builder.verify_param_mut().set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+    }
+
+    if !alpn.is_empty() {
+        let mut wire = Vec::new();
+        for proto in alpn {
+            let len = u8::try_from(proto.len()).ok().filter(|&n| n > 0)
+                .ok_or_else(|| anyhow!("--alpn: {proto:?} must be 1 to 255 bytes"))?;
+            wire.push(len);
+            wire.extend_from_slice(proto.as_bytes());
+        }
+        builder.set_alpn_protos(&wire)?;
+    }
+    Ok(builder.build())
+}
+
+/// Opens verified mTLS connections to one server.
+pub struct Client {
+    config: ClientConfig,
+    server: Upstream,
+    server_name: String,
+    /// Replaced by [`Client::reload`]; connections snapshot it.
+    connector: RwLock<SslConnector>,
+    connect_timeout: Duration,
+}
+
+impl Client {
+    pub fn new(config: &ClientConfig) -> Result<Client> {
+        Ok(Client {
+            config: config.clone(),
+            server: config.connect.clone(),
+            server_name: config.server_name()?,
+            connector: RwLock::new(Client::load_connector(config)?),
+            connect_timeout: Duration::from_secs(config.connect_timeout_secs),
+        })
+    }
+
+    fn load_connector(config: &ClientConfig) -> Result<SslConnector> {
+        let identity = load_identity(&config.identity()).context("loading client identity")?;
+        connector(&identity, &config.ca, config.partial_chain, &config.alpn)
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Load the identity files again, e.g. after renewal; new connections use them and
+    /// open ones are unaffected. On error the previous identity stays in use.
+    pub fn reload(&self) -> Result<()> {
+        let connector = Client::load_connector(&self.config)?;
+        *self.connector.write().unwrap() = connector;
+        Ok(())
+    }
+
+    /// Open a connection and complete the handshake, verifying the server's chain and
+    /// hostname.
+    pub async fn connect(&self) -> Result<SslStream<Box<dyn Io>>> {
+        let io = self.server.connect(self.connect_timeout).await?;
+        let connector = self.connector.read().unwrap().clone();
+        let ssl = connector.configure()?.into_ssl(&self.server_name)?;
+        let mut tls = SslStream::new(ssl, io)?;
+        let handshake = tokio::time::timeout(self.connect_timeout, Pin::new(&mut tls).connect()).await
+            .map_err(|_| anyhow!("TLS handshake with {} timed out", self.server))?;
+        if let Err(e) = handshake {
+            let verify = tls.ssl().verify_result();
+            let mut err = anyhow!(e).context(format!("TLS handshake with {} ({})", self.server, self.server_name));
+            if verify != X509VerifyResult::OK {
+                err = err.context(format!("server certificate rejected: {verify}"));
+            }
+            return Err(err);
+        }
+        Ok(tls)
+    }
+
+    /// Send `req` on a new connection, over HTTP/2 if ALPN picked `h2`, and read the
+    /// whole response. `timeout` covers everything after the handshake.
+    pub async fn request(&self, req: &ClientRequest, timeout: Duration) -> Result<(Session, Response)> {
+        let mut tls = self.connect().await?;
+        let session = Session::from_ssl(tls.ssl());
+        let deadline = Instant::now() + timeout;
+        let authority = match &self.server {
+            Upstream::Tcp(addr) => {
+                let (_, port) = addr.rsplit_once(':').expect("validated host:port");
+                format!("{}:{port}", self.server_name)
+            }
+            #[cfg(unix)]
+            Upstream::Unix(_) => self.server_name.clone(),
+        };
+
+        let resp = if session.alpn.as_deref() == Some("h2") {
+            tokio::time::timeout_at(deadline, send_h2(tls, &authority, req)).await
+                .map_err(|_| anyhow!("timed out waiting for the response"))??
+        } else {
+            let resp = send_http1(&mut tls, &authority, req, deadline).await?;
+            Pin::new(&mut tls).shutdown().await.ok(); // best-effort
+            resp
+        };
+        Ok((session, resp))
+    }
+}
+
+/// One HTTP request for [`Client::request`].
+#[derive(Debug, Clone)]
+pub struct ClientRequest {
+    pub method: String,
+    /// Path and query, e.g. `/status?full=1`.
+    pub path: String,
+    /// Sent as given; Host, Content-Length and Connection are added.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+async fn send_http1<S>(io: &mut S, authority: &str, req: &ClientRequest, deadline: Instant) -> Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {authority}\r\n", req.method, req.path);
+    for (name, value) in &req.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !req.body.is_empty() {
+        head.push_str(&format!("Content-Length: {}\r\n", req.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+
+    let send = async {
+        io.write_all(head.as_bytes()).await?;
+        io.write_all(&req.body).await?;
+        io.flush().await
+    };
+    tokio::time::timeout_at(deadline, send).await
+        .map_err(|_| anyhow!("timed out sending the request"))??;
+    read_response(io, req.method == "HEAD", MAX_RESPONSE_HEAD, MAX_RESPONSE_BODY, deadline).await
+}
+
+async fn send_h2<S>(io: S, authority: &str, req: &ClientRequest) -> Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut client, conn) = h2::client::handshake(io).await?;
+    let driver = tokio::spawn(conn);
+
+    let mut builder = ::http::Request::builder()
+        .method(req.method.as_str())
+        .uri(format!("https://{authority}{}", req.path));
+    for (name, value) in &req.headers {
+        builder = builder.header(name, value);
+    }
+    let request = builder.body(()).context("invalid request")?;
+    let end = req.body.is_empty();
+    let (response, mut stream) = client.send_request(request, end)?;
+    if !end {
+        stream.send_data(Bytes::from(req.body.clone()), true)?;
+    }
+
+    let (parts, mut body) = response.await?.into_parts();
+    let mut out = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        if out.len() + chunk.len() > MAX_RESPONSE_BODY {
+            bail!("response body larger than {MAX_RESPONSE_BODY} bytes");
+        }
+        out.extend_from_slice(&chunk);
+    }
+    driver.abort();
+
+    let headers = parts.headers.iter()
+        .map(|(k, v)| (k.as_str().to_string(), String::from_utf8_lossy(v.as_bytes()).into_owned()))
+        .collect();
+    Ok(Response { status: parts.status.as_u16(), headers, body: out })
+}
+
+/// What a handshake negotiated, printed like `openssl s_client -brief -showcerts`.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub protocol: String,
+    pub cipher: String,
+    /// `None` when ALPN wasn't offered or the server didn't pick a protocol.
+    pub alpn: Option<String>,
+    /// Server chain as sent, leaf first.
+    pub chain: Vec<X509>,
+}
+
+impl Session {
+    pub fn from_ssl(ssl: &SslRef) -> Session {
+        Session {
+            protocol: ssl.version_str().to_string(),
+            cipher: ssl.current_cipher().map(|c| c.name().to_string()).unwrap_or_default(),
+            alpn: ssl.selected_alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+            chain: ssl.peer_cert_chain()
+                .map(|c| c.iter().map(|x| x.to_owned()).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Protocol: {}", self.protocol)?;
+        writeln!(f, "Cipher: {}", self.cipher)?;
+        writeln!(f, "ALPN: {}", self.alpn.as_deref().unwrap_or("(none)"))?;
+        writeln!(f, "Server chain:")?;
+        for (i, cert) in self.chain.iter().enumerate() {
+            writeln!(f, " {i} s:{}", x509_name_to_string(cert.subject_name()))?;
+            writeln!(f, "   i:{}", x509_name_to_string(cert.issuer_name()))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ClientTunnelArgs {
+    /// Local plaintext address to accept connections on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: SocketAddr,
+
+    #[command(flatten)]
+    pub client: ClientConfig,
+
+    /// Close a tunnel when neither side has sent anything for this long
+    #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle_timeout_secs: u64,
+
+    /// Close a tunnel this long after it opened; unlimited when unset
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_lifetime_secs: Option<u64>,
+
+    #[command(flatten)]
+    pub renew: RenewArgs,
+}
+
+pub struct ClientTunnel {
+    client: Arc<Client>,
+    limits: Limits,
+}
+
+impl ClientTunnel {
+    pub fn new(args: &ClientTunnelArgs) -> Result<ClientTunnel> {
+        Ok(ClientTunnel {
+            client: Arc::new(Client::new(&args.client)?),
+            limits: Limits {
+                idle: Duration::from_secs(args.idle_timeout_secs),
+                lifetime: args.max_lifetime_secs.map(Duration::from_secs),
+            },
+        })
+    }
+
+    async fn handle(&self, local: TcpStream) -> Result<()> {
+        local.set_nodelay(true)?;
+        let tls = self.client.connect().await?;
+        logging::record_handshake(tls.ssl());
+        if let Some(cert) = tls.ssl().peer_certificate() {
+            logging::record_subject(&x509_name_to_string(cert.subject_name()));
+        }
+        debug!(server = %self.client.server, "connected");
+        let (up, down) = pipe(local, tls, self.limits).await?;
+        debug!(server = %self.client.server, up, down, "tunnel closed");
+        Ok(())
+    }
+}
+
+/// Accept plaintext connections on `--listen` and tunnel each one to the server.
+pub async fn run_tunnel(args: ClientTunnelArgs) -> Result<()> {
+    let tunnel = Arc::new(ClientTunnel::new(&args)?);
+    let listener = TcpListener::bind(args.listen).await
+        .with_context(|| format!("--listen: binding {}", args.listen))?;
+    info!("Listening on {} (tunnel to {})", args.listen, args.client.connect);
+    if let Some(at) = args.renew.renew_at {
+        tokio::spawn(renew::run(tunnel.client.clone(), at, Duration::from_secs(args.renew.renew_check_secs)));
+    }
+
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let tunnel = tunnel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = tunnel.handle(tcp).await {
+                warn!("{e:#}");
+            }
+        }.instrument(logging::conn_span(peer)));
+    }
+}
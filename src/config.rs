@@ -0,0 +1,461 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::audit::AuditConfig;
+use crate::check::CheckConfig;
+use crate::est::EstConfig;
+use crate::http::HttpConfig;
+use crate::logging::{LogFormat, LogLevel, LoggingConfig};
+use crate::ocsp::OcspConfig;
+use crate::stapling::StaplingConfig;
+use crate::policy::Policy;
+use crate::proxy::ProxyConfig;
+use crate::secret::Secret;
+use crate::tunnel::TunnelConfig;
+
+/// Top-level server configuration, loaded from TOML and then patched by CLI flags / env vars.
+/// Every section has defaults matching the historical hardcoded values, so an empty file works.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub http: HttpConfig,
+    pub proxy: ProxyConfig,
+    pub tunnel: TunnelConfig,
+    pub identity: IdentityConfig,
+    /// Further server certificates with other key types (e.g. ECDSA beside RSA); each
+    /// handshake gets the one matching what the client supports.
+    pub extra_identities: Vec<IdentityConfig>,
+    pub trust: TrustConfig,
+    pub policy: Policy,
+    pub logging: LoggingConfig,
+    pub sni: SniConfig,
+    pub ocsp: OcspConfig,
+    pub stapling: StaplingConfig,
+    pub reload: ReloadConfig,
+    pub admin: AdminConfig,
+    pub est: EstConfig,
+    pub check: CheckConfig,
+    pub audit: AuditConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to accept mTLS connections on.
+    pub listen: Vec<SocketAddr>,
+    pub mode: Mode,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { listen: vec![SocketAddr::from(([0, 0, 0, 0], 8443))], mode: Mode::Ok }
+    }
+}
+
+/// What happens on a connection once the client is authenticated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Answer every HTTP request with `ok`.
+    #[default]
+    Ok,
+    /// Forward HTTP requests to `[proxy] upstream` with identity headers.
+    Proxy,
+    /// Relay raw bytes to `[tunnel] backend`; no HTTP involved.
+    Tunnel,
+}
+
+/// Where the server certificate chain and private key come from.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase", deny_unknown_fields)]
+pub enum IdentityConfig {
+    /// Separate PEM files: `cert` holds leaf + intermediates, `key` the private key,
+    /// encrypted (PKCS#8 or legacy PEM) when `key_password` is set.
+    Pem {
+        cert: PathBuf,
+        key: PathBuf,
+        #[serde(default)]
+        key_password: Option<Secret>,
+    },
+    /// A PKCS#12 bundle holding key, leaf and chain.
+    Pkcs12 {
+        path: PathBuf,
+        #[serde(default = "default_pkcs12_password")]
+        password: Secret,
+    },
+}
+
+pub fn default_pkcs12_password() -> Secret {
+    Secret::literal("changeit")
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        IdentityConfig::Pem { cert: "cert.pem".into(), key: "key.pem".into(), key_password: None }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrustConfig {
+    /// PEM bundle of CAs allowed to issue client certificates.
+    pub client_ca: PathBuf,
+    /// CRL files (PEM or DER) for the client CA hierarchy. Revocation is checked only if set.
+    pub crls: Vec<PathBuf>,
+    pub crl_check: CrlCheck,
+    pub stale_crl: StaleCrl,
+    /// Accept client chains that end at any certificate in `client_ca`, not only at a
+    /// self-signed root, so an intermediate alone can be the trust anchor.
+    pub partial_chain: bool,
+}
+
+impl Default for TrustConfig {
+    fn default() -> Self {
+        TrustConfig {
+            client_ca: "client-ca.pem".into(),
+            crls: Vec::new(),
+            crl_check: CrlCheck::default(),
+            stale_crl: StaleCrl::default(),
+            partial_chain: false,
+        }
+    }
+}
+
+/// Which certificates of the client chain are checked against the CRLs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrlCheck {
+    /// Only the client leaf; needs a CRL from the issuing CA.
+    #[default]
+    Leaf,
+    /// Every certificate below the trust anchor; needs a CRL from each CA.
+    Chain,
+}
+
+/// What to do when the applicable CRL is past its nextUpdate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StaleCrl {
+    /// Fail the handshake.
+    #[default]
+    Reject,
+    /// Log a warning and still honour the revocation entries it has.
+    Warn,
+}
+
+/// Name-based virtual hosting. The top-level identity, trust and policy serve connections
+/// whose SNI matches no host (unless `unknown = "reject"`).
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SniConfig {
+    pub unknown: UnknownSni,
+    pub hosts: Vec<VirtualHostConfig>,
+}
+
+/// What to do with a ClientHello whose server name is missing or matches no host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownSni {
+    /// Serve the top-level identity.
+    #[default]
+    Default,
+    /// Abort the handshake with an `unrecognized_name` alert.
+    Reject,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHostConfig {
+    /// Hostnames served by this entry; `*.example.com` matches one label.
+    pub names: Vec<String>,
+    pub identity: IdentityConfig,
+    #[serde(default)]
+    pub extra_identities: Vec<IdentityConfig>,
+    /// Client trust bundle; defaults to the top-level `[trust]`.
+    pub trust: Option<TrustConfig>,
+    /// Client policy; defaults to the top-level `[policy]`.
+    pub policy: Option<Policy>,
+    /// Pre-fetched DER OCSP response to staple; fetched from the responder when unset.
+    pub ocsp_staple: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReloadConfig {
+    /// Poll the identity and trust files and reload when they change.
+    pub watch: bool,
+    pub poll_interval_secs: u64,
+    /// Rebuild the acceptor this often when CRLs are configured, even if no file changed.
+    pub crl_refresh_secs: u64,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        ReloadConfig { watch: true, poll_interval_secs: 5, crl_refresh_secs: 3600 }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Plaintext admin listener (`POST /reload`). Keep it on loopback.
+    pub listen: Option<SocketAddr>,
+}
+
+/// Command-line / environment overrides. Anything set here wins over the config file.
+#[derive(Debug, Default, Args)]
+pub struct Overrides {
+    /// Listen address (repeatable); replaces `server.listen`
+    #[arg(long = "listen", env = "MTLS_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+
+    /// Server certificate chain (PEM); selects the PEM identity source
+    #[arg(long, env = "MTLS_CERT", requires = "key", conflicts_with = "pkcs12")]
+    pub cert: Option<PathBuf>,
+
+    /// Server private key (PEM)
+    #[arg(long, env = "MTLS_KEY", requires = "cert")]
+    pub key: Option<PathBuf>,
+
+    /// Password of an encrypted server key: pass:, env:, file:, stdin or credential:
+    #[arg(long, env = "MTLS_KEY_PASSWORD", hide_env_values = true, value_parser = Secret::parse)]
+    pub key_password: Option<Secret>,
+
+    /// Server PKCS#12 bundle; selects the PKCS#12 identity source
+    #[arg(long, env = "MTLS_PKCS12")]
+    pub pkcs12: Option<PathBuf>,
+
+    /// Password for the PKCS#12 bundle: pass:, env:, file:, stdin or credential:
+    #[arg(long, env = "MTLS_PKCS12_PASSWORD", hide_env_values = true, value_parser = Secret::parse)]
+    pub pkcs12_password: Option<Secret>,
+
+    /// Client CA bundle used to verify client certificates
+    #[arg(long, env = "MTLS_CLIENT_CA")]
+    pub client_ca: Option<PathBuf>,
+
+    /// Replace the configured policy with a single rule requiring this subject OU
+    #[arg(long, env = "MTLS_REQUIRED_OU")]
+    pub required_ou: Option<String>,
+
+    /// Plaintext admin listener address (e.g. 127.0.0.1:9444)
+    #[arg(long, env = "MTLS_ADMIN_LISTEN")]
+    pub admin_listen: Option<SocketAddr>,
+
+    /// Log level (error, warn, info, debug, trace)
+    #[arg(long, env = "MTLS_LOG_LEVEL")]
+    pub log_level: Option<LogLevel>,
+
+    /// Log format (text, json)
+    #[arg(long, env = "MTLS_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+}
+
+impl Config {
+    /// Load `path` (if given), apply overrides and validate.
+    pub fn load(path: Option<&Path>, overrides: &Overrides) -> Result<Config> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply(overrides);
+        config.validate().context("invalid configuration")?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config {}", path.display()))?;
+        // toml's error already names the offending key and line/column.
+        toml::from_str(&text).with_context(|| format!("parsing config {}", path.display()))
+    }
+
+    fn apply(&mut self, o: &Overrides) {
+        if !o.listen.is_empty() {
+            self.server.listen = o.listen.clone();
+        }
+        if let (Some(cert), Some(key)) = (&o.cert, &o.key) {
+            let key_password = match &self.identity {
+                IdentityConfig::Pem { key_password, .. } => key_password.clone(),
+                IdentityConfig::Pkcs12 { .. } => None,
+            };
+            self.identity = IdentityConfig::Pem { cert: cert.clone(), key: key.clone(), key_password };
+        }
+        if let (Some(pw), IdentityConfig::Pem { key_password, .. }) = (&o.key_password, &mut self.identity) {
+            *key_password = Some(pw.clone());
+        }
+        if let Some(path) = &o.pkcs12 {
+            let password = match &self.identity {
+                IdentityConfig::Pkcs12 { password, .. } => password.clone(),
+                IdentityConfig::Pem { .. } => default_pkcs12_password(),
+            };
+            self.identity = IdentityConfig::Pkcs12 { path: path.clone(), password };
+        }
+        if let (Some(pw), IdentityConfig::Pkcs12 { password, .. }) = (&o.pkcs12_password, &mut self.identity) {
+            *password = pw.clone();
+        }
+        if let Some(ca) = &o.client_ca {
+            self.trust.client_ca = ca.clone();
+        }
+        if let Some(ou) = &o.required_ou {
+            self.policy = Policy::required_ou(ou);
+        }
+        if let Some(addr) = o.admin_listen {
+            self.admin.listen = Some(addr);
+        }
+        if let Some(level) = o.log_level {
+            self.logging.level = level;
+        }
+        if let Some(format) = o.log_format {
+            self.logging.format = format;
+        }
+    }
+
+    /// Semantic checks that serde can't express. Errors are prefixed with the config key.
+    pub fn validate(&self) -> Result<()> {
+        if self.server.listen.is_empty() {
+            bail!("server.listen: at least one listen address is required");
+        }
+        self.identity.validate("identity")?;
+        for (i, identity) in self.extra_identities.iter().enumerate() {
+            identity.validate(&format!("extra_identities[{i}]"))?;
+        }
+        self.trust.validate("trust")?;
+        self.policy.validate("policy")?;
+        for (i, host) in self.sni.hosts.iter().enumerate() {
+            let key = format!("sni.hosts[{i}]");
+            if host.names.is_empty() {
+                bail!("{key}.names: at least one hostname is required");
+            }
+            for name in &host.names {
+                let bare = name.strip_prefix("*.").unwrap_or(name);
+                if bare.is_empty() || bare.contains('*') {
+                    bail!("{key}.names: invalid hostname pattern {name:?}");
+                }
+                if self.sni.hosts[..i].iter().any(|h| h.names.iter().any(|n| n.eq_ignore_ascii_case(name))) {
+                    bail!("{key}.names: {name:?} is already served by an earlier host");
+                }
+            }
+            host.identity.validate(&format!("{key}.identity"))?;
+            for (i, identity) in host.extra_identities.iter().enumerate() {
+                identity.validate(&format!("{key}.extra_identities[{i}]"))?;
+            }
+            if let Some(trust) = &host.trust {
+                trust.validate(&format!("{key}.trust"))?;
+            }
+            if let Some(policy) = &host.policy {
+                policy.validate(&format!("{key}.policy"))?;
+            }
+            if let Some(file) = &host.ocsp_staple {
+                check_file(&format!("{key}.ocsp_staple"), file)?;
+            }
+        }
+        self.http.validate()?;
+        self.proxy.validate()?;
+        if self.server.mode == Mode::Proxy && self.proxy.upstream.is_none() {
+            bail!("proxy.upstream: required when server.mode = \"proxy\"");
+        }
+        self.tunnel.validate()?;
+        if self.server.mode == Mode::Tunnel && self.tunnel.backend.is_none() {
+            bail!("tunnel.backend: required when server.mode = \"tunnel\"");
+        }
+        self.ocsp.validate()?;
+        self.stapling.validate()?;
+        if let Some(file) = &self.stapling.file {
+            check_file("stapling.file", file)?;
+        }
+        if self.reload.poll_interval_secs == 0 {
+            bail!("reload.poll_interval_secs: must be at least 1");
+        }
+        if self.reload.crl_refresh_secs == 0 {
+            bail!("reload.crl_refresh_secs: must be at least 1");
+        }
+        self.est.validate()?;
+        self.check.validate()?;
+        self.logging.validate()?;
+        self.audit.validate()?;
+        Ok(())
+    }
+
+    /// Files whose contents end up in the acceptor; a change to any of them triggers a reload.
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files = self.identity.files();
+        files.extend(self.extra_identities.iter().flat_map(IdentityConfig::files));
+        files.extend(self.trust.files());
+        files.extend(self.stapling.file.clone());
+        for host in &self.sni.hosts {
+            files.extend(host.identity.files());
+            files.extend(host.extra_identities.iter().flat_map(IdentityConfig::files));
+            files.extend(host.trust.iter().flat_map(TrustConfig::files));
+            files.extend(host.ocsp_staple.clone());
+        }
+        files
+    }
+
+    /// True if any trust bundle (top-level or per host) has CRLs.
+    pub fn has_crls(&self) -> bool {
+        !self.trust.crls.is_empty()
+            || self.sni.hosts.iter().any(|h| h.trust.as_ref().is_some_and(|t| !t.crls.is_empty()))
+    }
+}
+
+impl TrustConfig {
+    fn validate(&self, key: &str) -> Result<()> {
+        check_file(&format!("{key}.client_ca"), &self.client_ca)?;
+        for (i, crl) in self.crls.iter().enumerate() {
+            check_file(&format!("{key}.crls[{i}]"), crl)?;
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.client_ca.clone()];
+        files.extend(self.crls.iter().cloned());
+        files
+    }
+}
+
+impl IdentityConfig {
+    fn validate(&self, key: &str) -> Result<()> {
+        match self {
+            IdentityConfig::Pem { cert, key: key_path, key_password } => {
+                check_file(&format!("{key}.cert"), cert)?;
+                check_file(&format!("{key}.key"), key_path)?;
+                match key_password {
+                    Some(password) => password.validate(&format!("{key}.key_password")),
+                    None => Ok(()),
+                }
+            }
+            IdentityConfig::Pkcs12 { path, password } => {
+                check_file(&format!("{key}.path"), path)?;
+                password.validate(&format!("{key}.password"))
+            }
+        }
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        match self {
+            IdentityConfig::Pem { cert, key, key_password } => {
+                let mut files = vec![cert.clone(), key.clone()];
+                files.extend(key_password.as_ref().and_then(Secret::file));
+                files
+            }
+            IdentityConfig::Pkcs12 { path, password } => {
+                let mut files = vec![path.clone()];
+                files.extend(password.file());
+                files
+            }
+        }
+    }
+}
+
+pub(crate) fn check_file(key: &str, path: &Path) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(m) if m.is_file() => Ok(()),
+        Ok(_) => bail!("{key}: {} is not a regular file", path.display()),
+        Err(e) => bail!("{key}: cannot access {}: {e}", path.display()),
+    }
+}
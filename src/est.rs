@@ -0,0 +1,660 @@
+//! EST (RFC 7030) enrollment of device certificates:
+//!
+//! - `GET /.well-known/est/cacerts`: the issuing CA and its chain.
+//! - `POST /.well-known/est/simpleenroll`: a new certificate for a base64 PKCS#10 CSR,
+//!   authorized by a bootstrap certificate or a one-time token (HTTP Basic password or
+//!   Bearer).
+//! - `POST /.well-known/est/simplereenroll`: a fresh certificate for a client that
+//!   authenticates with one this CA issued, keeping its subject. That certificate must
+//!   still pass the `[policy]` and, when enabled, the `[ocsp]` check, as on the main
+//!   listener.
+//!
+//! The paths are served on the main listener in the HTTP modes, where clients already
+//! present a certificate that passed the policy, and on an optional listener of its own
+//! where a client certificate is optional, for devices that have none yet.
+//!
+//! Only the subject and public key are taken from a CSR. The OU is always replaced by
+//! the configured one, so enrolled devices pass the server's OU policy; the rest of the
+//! certificate (client EKU, no SANs, validity) comes from the template here.
+
+use anyhow::{anyhow, bail, Context, Result};
+use foreign_types::ForeignType;
+use openssl::error::ErrorStack;
+use openssl::memcmp;
+use openssl::nid::Nid;
+use openssl::pkcs7::Pkcs7;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{Ssl, SslAcceptor};
+use openssl::x509::{X509Name, X509NameRef, X509Req, X509};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_openssl::SslStream;
+use tracing::{info, warn, Instrument};
+
+use crate::audit::{self, AuditLog};
+use crate::config::check_file;
+use crate::http::{self, Handler, HttpConfig, Request, Response};
+use crate::logging;
+use crate::ocsp::OcspChecker;
+use crate::peer::PeerIdentity;
+use crate::pki::{self, Sans, Usage};
+use crate::secret::Secret;
+use crate::tls::load_private_key;
+use crate::policy::{serial_hex, Policy};
+use crate::x509_name_to_string;
+use crate::TRUST_DEVICES;
+
+const PREFIX: &str = "/.well-known/est/";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EstConfig {
+    /// Extra listener for EST only, where client certificates are optional.
+    pub listen: Option<SocketAddr>,
+    /// Issuing CA certificate (PEM), optionally followed by the rest of its chain. EST is
+    /// off unless this and `ca_key` are set.
+    pub ca_cert: Option<PathBuf>,
+    pub ca_key: Option<PathBuf>,
+    /// Password of an encrypted `ca_key`, in the `pass:`/`env:`/`file:` syntax.
+    pub ca_key_password: Option<Secret>,
+    /// OU stamped into every issued subject, whatever the CSR asks for.
+    pub ou: String,
+    pub validity_days: u32,
+    /// PEM bundle of CAs whose certificates may enroll (`simpleenroll`).
+    pub bootstrap_ca: Option<PathBuf>,
+    /// One-time enrollment tokens, one per line (`#` comments allowed); each is removed
+    /// from the file when used.
+    pub tokens_file: Option<PathBuf>,
+}
+
+impl Default for EstConfig {
+    fn default() -> Self {
+        EstConfig {
+            listen: None,
+            ca_cert: None,
+            ca_key: None,
+            ca_key_password: None,
+            ou: TRUST_DEVICES.to_string(),
+            validity_days: 365,
+            bootstrap_ca: None,
+            tokens_file: None,
+        }
+    }
+}
+
+impl EstConfig {
+    /// Whether EST is served at all.
+    pub fn enabled(&self) -> bool {
+        self.ca_cert.is_some()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.ca_cert.is_none() && self.ca_key.is_none() && self.listen.is_none() {
+            return Ok(());
+        }
+        for (key, path) in [("est.ca_cert", &self.ca_cert), ("est.ca_key", &self.ca_key)] {
+            match path {
+                Some(path) => check_file(key, path)?,
+                None => bail!("{key}: required to serve EST"),
+            }
+        }
+        if let Some(password) = &self.ca_key_password {
+            password.validate("est.ca_key_password")?;
+        }
+        if let Some(path) = &self.bootstrap_ca {
+            check_file("est.bootstrap_ca", path)?;
+        }
+        if let Some(path) = &self.tokens_file {
+            check_file("est.tokens_file", path)?;
+        }
+        if self.ou.is_empty() {
+            bail!("est.ou: must not be empty");
+        }
+        if self.validity_days == 0 {
+            bail!("est.validity_days: must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+pub struct Est {
+    ca_key: PKey<Private>,
+    /// Issuing CA first, then the rest of its chain, as served by `cacerts`.
+    ca_chain: Vec<X509>,
+    bootstrap: Vec<X509>,
+    tokens: Option<Tokens>,
+    ou: String,
+    validity_days: u32,
+    /// The main listener's policy and OCSP check, applied again before re-enrollment.
+    policy: Policy,
+    ocsp: Option<OcspChecker>,
+}
+
+impl Est {
+    /// Load the CA and bootstrap trust; `config` must have passed validation with EST enabled.
+    pub fn new(config: &EstConfig, policy: Policy, ocsp: Option<OcspChecker>) -> Result<Est> {
+        let ca_cert = config.ca_cert.as_ref().ok_or_else(|| anyhow!("est.ca_cert: not set"))?;
+        let ca_key = config.ca_key.as_ref().ok_or_else(|| anyhow!("est.ca_key: not set"))?;
+        let ca_chain = read_certs(ca_cert)?;
+        let key = load_private_key(ca_key, config.ca_key_password.as_ref())?;
+        if !ca_chain[0].public_key()?.public_eq(&key) {
+            bail!("est.ca_key: does not match the first certificate in {}", ca_cert.display());
+        }
+        let bootstrap = match &config.bootstrap_ca {
+            Some(path) => read_certs(path)?,
+            None => Vec::new(),
+        };
+        Ok(Est {
+            ca_key: key,
+            ca_chain,
+            bootstrap,
+            tokens: config.tokens_file.clone().map(|path| Tokens { path, lock: tokio::sync::Mutex::new(()) }),
+            ou: config.ou.clone(),
+            validity_days: config.validity_days,
+            policy,
+            ocsp,
+        })
+    }
+
+    /// CAs a client certificate on the EST listener may chain to.
+    pub fn anchors(&self) -> Vec<X509> {
+        let mut anchors = self.ca_chain.clone();
+        anchors.extend(self.bootstrap.iter().filter(|c| !self.ca_chain.contains(c)).cloned());
+        anchors
+    }
+
+    /// Directly issued by our CA, so it may re-enroll.
+    fn issued(&self, peer: &PeerIdentity) -> bool {
+        peer.chain.get(1) == Some(&self.ca_chain[0])
+    }
+
+    /// The checks the main listener runs on `peer`: a certificate it would refuse, by
+    /// policy or because OCSP says it is revoked, doesn't get a fresh one.
+    async fn still_trusted(&self, peer: &PeerIdentity) -> Result<(), Refused> {
+        let verdict = self.policy.evaluate(&peer.chain[0]);
+        if !verdict.accepted() {
+            return Err(Refused::new(403, format!("current certificate refused by the policy: {verdict}")));
+        }
+        if let Some(ocsp) = &self.ocsp {
+            ocsp.check(&peer.chain).await.map_err(|e| Refused::new(403, format!("{e:#}")))?;
+        }
+        Ok(())
+    }
+
+    /// Chains to a bootstrap CA, so it may enroll.
+    fn bootstrapped(&self, peer: &PeerIdentity) -> bool {
+        peer.chain[1..].iter().any(|c| self.bootstrap.contains(c))
+    }
+
+    async fn enroll(&self, req: &Request, reenroll: bool) -> Result<X509, Refused> {
+        let csr = parse_csr(&req.body).map_err(|e| Refused::new(400, format!("bad CSR: {e:#}")))?;
+        let subject = stamp_ou(csr.subject_name(), &self.ou)
+            .map_err(|e| Refused::new(400, format!("bad CSR subject: {e:#}")))?;
+
+        // A token is only redeemed once the certificate is signed, so a signing error
+        // doesn't use it up.
+        let mut redeem = None;
+        let via = if reenroll {
+            let peer = req.peer.as_deref().filter(|p| self.issued(p))
+                .ok_or_else(|| Refused::new(403, "re-enrollment needs a certificate issued by this CA"))?;
+            let same = csr.subject_name().try_cmp(peer.chain[0].subject_name()).map_err(Refused::internal)?;
+            if same != Ordering::Equal {
+                return Err(Refused::new(400, "CSR subject differs from the current certificate"));
+            }
+            self.still_trusted(peer).await?;
+            "re-enrollment"
+        } else if req.peer.as_deref().is_some_and(|p| self.bootstrapped(p)) {
+            "bootstrap certificate"
+        } else {
+            match (&self.tokens, token(req).filter(|t| !t.is_empty())) {
+                (Some(tokens), Some(token)) => {
+                    redeem = Some((tokens, token));
+                    "token"
+                }
+                _ => return Err(Refused::new(401, "needs a bootstrap certificate or a valid token")),
+            }
+        };
+
+        let key = csr.public_key().map_err(Refused::internal)?;
+        let cert = pki::sign_leaf(&subject, &key, &Sans::default(), Usage::Client, self.validity_days,
+            (&self.ca_chain[0], &self.ca_key)).map_err(Refused::internal)?;
+        if let Some((tokens, token)) = redeem {
+            if !tokens.redeem(&token).await.map_err(Refused::internal)? {
+                return Err(Refused::new(401, "needs a bootstrap certificate or a valid token"));
+            }
+        }
+        info!(serial = serial_hex(&cert), issued = x509_name_to_string(cert.subject_name()), via, "est: issued certificate");
+        Ok(cert)
+    }
+}
+
+impl Handler for Est {
+    async fn handle(&self, req: Request) -> Response {
+        let path = req.target.split('?').next().unwrap_or_default();
+        let Some(op) = path.strip_prefix(PREFIX) else {
+            return Response::text(404, "Not Found\n");
+        };
+        let result = match (req.method.as_str(), op) {
+            ("GET", "cacerts") => Ok(self.ca_chain.clone()),
+            ("POST", "simpleenroll") => self.enroll(&req, false).await.map(|c| vec![c]),
+            ("POST", "simplereenroll") => self.enroll(&req, true).await.map(|c| vec![c]),
+            (_, "cacerts" | "simpleenroll" | "simplereenroll") => return Response::text(405, "Method Not Allowed\n"),
+            _ => return Response::text(404, "Not Found\n"),
+        };
+        match result.and_then(|certs| certs_only(&certs).map_err(Refused::internal)) {
+            Ok(body) => Response {
+                status: 200,
+                headers: vec![
+                    ("Content-Type".to_string(), "application/pkcs7-mime; smime-type=certs-only".to_string()),
+                    ("Content-Transfer-Encoding".to_string(), "base64".to_string()),
+                ],
+                body,
+            },
+            Err(Refused { status, reason }) => {
+                warn!(method = req.method, op, status, "est: refused: {reason}");
+                let mut resp = Response::text(status, format!("{reason}\n"));
+                if status == 401 {
+                    resp.headers.push(("WWW-Authenticate".to_string(), "Basic realm=\"est\"".to_string()));
+                }
+                resp
+            }
+        }
+    }
+}
+
+/// Handler for the main listener: EST paths go to `est` when it is enabled, everything
+/// else to `inner`.
+pub struct Routes<H> {
+    est: Option<Arc<Est>>,
+    inner: Arc<H>,
+}
+
+impl<H> Routes<H> {
+    pub fn new(est: Option<Arc<Est>>, inner: Arc<H>) -> Routes<H> {
+        Routes { est, inner }
+    }
+}
+
+impl<H: Handler + Send + Sync> Handler for Routes<H> {
+    async fn handle(&self, req: Request) -> Response {
+        match &self.est {
+            Some(est) if req.target.starts_with(PREFIX) => est.handle(req).await,
+            _ => self.inner.handle(req).await,
+        }
+    }
+}
+
+/// Why a request got no certificate; `reason` goes to the log and the client.
+struct Refused {
+    status: u16,
+    reason: String,
+}
+
+impl Refused {
+    fn new(status: u16, reason: impl Into<String>) -> Refused {
+        Refused { status, reason: reason.into() }
+    }
+
+    fn internal(e: impl Into<anyhow::Error>) -> Refused {
+        Refused::new(500, format!("{:#}", e.into()))
+    }
+}
+
+/// One-time tokens in a file; redeeming one rewrites the file without it.
+struct Tokens {
+    path: PathBuf,
+    /// Serializes redemptions so a token can't be used twice.
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl Tokens {
+    /// Remove `token` from the file; `false` if it isn't there.
+    async fn redeem(&self, token: &str) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        let text = tokio::fs::read_to_string(&self.path).await
+            .with_context(|| format!("reading {}", self.path.display()))?;
+        let mut found = false;
+        let rest: Vec<&str> = text.lines()
+            .filter(|line| {
+                let t = line.trim();
+                let hit = !found && !t.starts_with('#') && t.len() == token.len() && memcmp::eq(t.as_bytes(), token.as_bytes());
+                found |= hit;
+                !hit
+            })
+            .collect();
+        if !found {
+            return Ok(false);
+        }
+
+        // Write a sibling and rename it over the original, keeping its permissions, so a
+        // crash never leaves a half-written file.
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut contents = rest.join("\n");
+        if !contents.is_empty() {
+            contents.push('\n');
+        }
+        tokio::fs::write(&tmp, contents).await.with_context(|| format!("writing {}", tmp.display()))?;
+        tokio::fs::set_permissions(&tmp, tokio::fs::metadata(&self.path).await?.permissions()).await?;
+        tokio::fs::rename(&tmp, &self.path).await.with_context(|| format!("replacing {}", self.path.display()))?;
+        Ok(true)
+    }
+}
+
+/// The token from `Authorization`: a Bearer token or the password of Basic credentials.
+fn token(req: &Request) -> Option<String> {
+    let (scheme, value) = req.header("authorization")?.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some(value.trim().to_string());
+    }
+    if scheme.eq_ignore_ascii_case("basic") {
+        let credentials = String::from_utf8(openssl::base64::decode_block(value.trim()).ok()?).ok()?;
+        return credentials.split_once(':').map(|(_, password)| password.to_string());
+    }
+    None
+}
+
+/// A base64 DER PKCS#10 request (line breaks allowed) with a valid self-signature.
+fn parse_csr(body: &[u8]) -> Result<X509Req> {
+    let text: String = std::str::from_utf8(body)?.split_ascii_whitespace().collect();
+    let csr = X509Req::from_der(&openssl::base64::decode_block(&text).context("invalid base64")?)?;
+    let key = csr.public_key()?;
+    if !csr.verify(&key)? {
+        bail!("signature does not verify");
+    }
+    Ok(csr)
+}
+
+/// Longest CN X.520 allows (`ub-common-name`).
+const MAX_CN_CHARS: usize = 64;
+
+/// `subject` with every OU replaced by `ou`, placed before the CN. The CSR's attributes
+/// end up in log lines and the proxy's identity headers, so control characters anywhere
+/// are refused, and the CN must be a single, non-blank value of at most 64 characters.
+fn stamp_ou(subject: &X509NameRef, ou: &str) -> Result<X509Name> {
+    let cns: Vec<_> = subject.entries_by_nid(Nid::COMMONNAME).collect();
+    let cn = match cns.as_slice() {
+        [] => bail!("no CN"),
+        [cn] => cn.data().as_utf8().context("CN is not a valid string")?.to_string(),
+        _ => bail!("more than one CN"),
+    };
+    if cn.trim().is_empty() || cn.trim() != cn {
+        bail!("CN {cn:?} is blank or has surrounding whitespace");
+    }
+    if cn.chars().count() > MAX_CN_CHARS {
+        bail!("CN is longer than {MAX_CN_CHARS} characters");
+    }
+    let mut name = X509Name::builder()?;
+    let mut stamped = false;
+    for entry in subject.entries() {
+        let nid = entry.object().nid();
+        if nid == Nid::ORGANIZATIONALUNITNAME {
+            continue;
+        }
+        let value = entry.data().as_utf8()
+            .with_context(|| format!("{} is not a valid string", nid.short_name().unwrap_or("attribute")))?;
+        if value.chars().any(char::is_control) {
+            bail!("{} {:?} contains control characters", nid.short_name().unwrap_or("attribute"), value.to_string());
+        }
+        if nid == Nid::COMMONNAME && !stamped {
+            name.append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, ou)?;
+            stamped = true;
+        }
+        name.append_entry_by_nid(nid, &value)?;
+    }
+    Ok(name.build())
+}
+
+/// Base64 of a degenerate ("certs-only") PKCS#7 SignedData carrying `certs`.
+fn certs_only(certs: &[X509]) -> Result<Vec<u8>> {
+    // SAFETY: `p7` owns the new structure (freed on drop); each call is checked, and
+    // PKCS7_add_certificate takes its own reference to the certificate.
+    let p7 = unsafe {
+        let ptr = openssl_sys::PKCS7_new();
+        if ptr.is_null() {
+            return Err(ErrorStack::get().into());
+        }
+        let p7 = Pkcs7::from_ptr(ptr);
+        if openssl_sys::PKCS7_set_type(p7.as_ptr(), openssl_sys::NID_pkcs7_signed) <= 0
+            || openssl_sys::PKCS7_content_new(p7.as_ptr(), openssl_sys::NID_pkcs7_data) <= 0
+        {
+            return Err(ErrorStack::get().into());
+        }
+        for cert in certs {
+            if openssl_sys::PKCS7_add_certificate(p7.as_ptr(), cert.as_ptr()) <= 0 {
+                return Err(ErrorStack::get().into());
+            }
+        }
+        p7
+    };
+    Ok(openssl::base64::encode_block(&p7.to_der()?).into_bytes())
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<X509>> {
+    let pem = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let certs = X509::stack_from_pem(&pem).with_context(|| format!("loading {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("{} has no certificate", path.display());
+    }
+    Ok(certs)
+}
+
+/// Accept EST clients, with or without a certificate, and serve their requests.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: SslAcceptor,
+    est: Arc<Est>,
+    config: HttpConfig,
+    audit: Option<Arc<AuditLog>>,
+) -> Result<()> {
+    loop {
+        let (tcp, addr) = listener.accept().await?;
+        let (acceptor, est, config, audit) = (acceptor.clone(), est.clone(), config.clone(), audit.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_conn(tcp, addr, &acceptor, &est, &config, audit.as_deref()).await {
+                warn!("est: {e:#}");
+            }
+        }.instrument(logging::conn_span(addr)));
+    }
+}
+
+async fn handle_conn(
+    tcp: TcpStream,
+    addr: SocketAddr,
+    acceptor: &SslAcceptor,
+    est: &Est,
+    config: &HttpConfig,
+    audit: Option<&AuditLog>,
+) -> Result<()> {
+    let mut ssl = Ssl::new(acceptor.context())?;
+    if audit.is_some() {
+        audit::track(&mut ssl);
+    }
+    let mut tls = SslStream::new(ssl, tcp)?;
+    let handshake = Pin::new(&mut tls).accept().await;
+    logging::record_handshake(tls.ssl());
+    if let Some(audit) = audit {
+        audit.record("est", addr, tls.ssl(), handshake.as_ref().err());
+    }
+    handshake.map_err(|e| anyhow!("handshake failed: {e}"))?;
+    let peer = match tls.ssl().peer_certificate() {
+        Some(_) => Some(Arc::new(PeerIdentity::from_ssl(tls.ssl())?)),
+        None => None,
+    };
+    if let Some(peer) = &peer {
+        logging::record_subject(&peer.subject);
+    }
+    http::serve(&mut tls, config, est, peer).await?;
+    Pin::new(&mut tls).shutdown().await.ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Version;
+    use crate::pki::KeyType;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::X509ReqBuilder;
+
+    struct Ca {
+        cert: X509,
+        key: PKey<Private>,
+    }
+
+    impl Ca {
+        fn new(cn: &str) -> Ca {
+            let key = KeyType::EcP256.generate().unwrap();
+            Ca { cert: pki::ca_cert(&key, "CAs", cn, 1, None).unwrap(), key }
+        }
+
+        /// A client of this CA, as it would arrive on the EST listener.
+        fn peer(&self, cn: &str) -> PeerIdentity {
+            let key = KeyType::EcP256.generate().unwrap();
+            let leaf = pki::sign_leaf(&subject(&[("OU", TRUST_DEVICES), ("CN", cn)]), &key, &Sans::default(),
+                Usage::Client, 1, (&self.cert, &self.key)).unwrap();
+            PeerIdentity::from_chain(vec![leaf, self.cert.clone()], None).unwrap()
+        }
+    }
+
+    /// EST for `ca`, with `bootstrap` and the token `tok-1` in a fresh tokens file.
+    fn est(name: &str, ca: &Ca, bootstrap: &Ca) -> Est {
+        let path = std::env::temp_dir().join(format!("est-{name}-{}.tokens", std::process::id()));
+        std::fs::write(&path, "# enrollment tokens\ntok-1\n").unwrap();
+        Est {
+            ca_key: ca.key.clone(),
+            ca_chain: vec![ca.cert.clone()],
+            bootstrap: vec![bootstrap.cert.clone()],
+            tokens: Some(Tokens { path, lock: tokio::sync::Mutex::new(()) }),
+            ou: TRUST_DEVICES.to_string(),
+            validity_days: 1,
+            policy: Policy::required_ou(TRUST_DEVICES),
+            ocsp: None,
+        }
+    }
+
+    fn csr(cn: &str) -> Vec<u8> {
+        let key = KeyType::EcP256.generate().unwrap();
+        let mut req = X509ReqBuilder::new().unwrap();
+        req.set_subject_name(&subject(&[("OU", TRUST_DEVICES), ("CN", cn)])).unwrap();
+        req.set_pubkey(&key).unwrap();
+        req.sign(&key, MessageDigest::sha256()).unwrap();
+        openssl::base64::encode_block(&req.build().to_der().unwrap()).into_bytes()
+    }
+
+    fn request(peer: Option<PeerIdentity>, token: Option<&str>, cn: &str) -> Request {
+        Request {
+            method: "POST".into(),
+            target: format!("{PREFIX}simpleenroll"),
+            version: Version::Http11,
+            headers: token.map(|t| ("Authorization".to_string(), format!("Bearer {t}"))).into_iter().collect(),
+            body: csr(cn),
+            peer: peer.map(Arc::new),
+        }
+    }
+
+    fn status(result: Result<X509, Refused>) -> u16 {
+        result.map_or_else(|r| r.status, |_| 200)
+    }
+
+
+    fn subject(entries: &[(&str, &str)]) -> X509Name {
+        let mut name = X509Name::builder().unwrap();
+        for (field, value) in entries {
+            name.append_entry_by_text(field, value).unwrap();
+        }
+        name.build()
+    }
+
+    #[test]
+    fn ou_is_stamped_before_the_cn() {
+        let name = stamp_ou(&subject(&[("C", "US"), ("OU", "Admins"), ("CN", "dev-1")]), TRUST_DEVICES).unwrap();
+        assert_eq!(x509_name_to_string(&name), "C=US, OU=TrustedDevices, CN=dev-1");
+    }
+
+    #[test]
+    fn bad_subjects_are_refused() {
+        for entries in [
+            vec![("O", "Example Org")],
+            vec![("CN", "a"), ("CN", "b")],
+            vec![("CN", "evil\r\nX-Client-Subject-DN: CN=admin")],
+            vec![("CN", "dev-1"), ("O", "Org\nX: y")],
+            vec![("CN", "tab\there")],
+            vec![("CN", " dev-1")],
+            vec![("CN", "   ")],
+        ] {
+            assert!(stamp_ou(&subject(&entries), TRUST_DEVICES).is_err(), "{entries:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_token_enrolls_once() {
+        let (ca, bootstrap) = (Ca::new("Issuing CA"), Ca::new("Factory CA"));
+        let est = est("token", &ca, &bootstrap);
+
+        let cert = est.enroll(&request(None, Some("tok-1"), "dev-1"), false).await.ok().unwrap();
+        assert_eq!(x509_name_to_string(cert.subject_name()), "OU=TrustedDevices, CN=dev-1");
+        assert_eq!(status(est.enroll(&request(None, Some("tok-1"), "dev-2"), false).await), 401);
+        assert_eq!(status(est.enroll(&request(None, Some("tok-2"), "dev-2"), false).await), 401);
+        assert_eq!(status(est.enroll(&request(None, None, "dev-2"), false).await), 401);
+        let left = std::fs::read_to_string(&est.tokens.as_ref().unwrap().path).unwrap();
+        assert_eq!(left, "# enrollment tokens\n");
+    }
+
+    #[tokio::test]
+    async fn a_signing_error_keeps_the_token() {
+        let (ca, bootstrap) = (Ca::new("Issuing CA"), Ca::new("Factory CA"));
+        // Past the last date a certificate can carry.
+        let est = Est { validity_days: u32::MAX, ..est("unsigned", &ca, &bootstrap) };
+
+        assert_eq!(status(est.enroll(&request(None, Some("tok-1"), "dev-1"), false).await), 500);
+        let left = std::fs::read_to_string(&est.tokens.as_ref().unwrap().path).unwrap();
+        assert!(left.contains("tok-1"), "{left:?}");
+    }
+
+    #[tokio::test]
+    async fn a_bootstrap_certificate_enrolls() {
+        let (ca, bootstrap) = (Ca::new("Issuing CA"), Ca::new("Factory CA"));
+        let est = est("bootstrap", &ca, &bootstrap);
+
+        let cert = est.enroll(&request(Some(bootstrap.peer("factory-1")), None, "dev-1"), false).await.ok().unwrap();
+        assert_eq!(cert.issuer_name().try_cmp(ca.cert.subject_name()).unwrap(), Ordering::Equal);
+        // A certificate of our own CA is for re-enrollment, not a bootstrap one.
+        assert_eq!(status(est.enroll(&request(Some(ca.peer("dev-2")), None, "dev-3"), false).await), 401);
+    }
+
+    #[tokio::test]
+    async fn reenrollment_keeps_the_subject() {
+        let (ca, bootstrap) = (Ca::new("Issuing CA"), Ca::new("Factory CA"));
+        let est = est("reenroll", &ca, &bootstrap);
+
+        est.enroll(&request(Some(ca.peer("dev-1")), None, "dev-1"), true).await.ok().unwrap();
+        assert_eq!(status(est.enroll(&request(Some(ca.peer("dev-1")), None, "dev-2"), true).await), 400);
+    }
+
+    #[tokio::test]
+    async fn reenrollment_needs_a_certificate_of_this_ca() {
+        let (ca, bootstrap) = (Ca::new("Issuing CA"), Ca::new("Factory CA"));
+        let est = est("other-ca", &ca, &bootstrap);
+
+        let other = Ca::new("Issuing CA");
+        assert_eq!(status(est.enroll(&request(Some(other.peer("dev-1")), None, "dev-1"), true).await), 403);
+        assert_eq!(status(est.enroll(&request(Some(bootstrap.peer("dev-1")), None, "dev-1"), true).await), 403);
+        assert_eq!(status(est.enroll(&request(None, Some("tok-1"), "dev-1"), true).await), 403);
+    }
+
+    #[tokio::test]
+    async fn reenrollment_applies_the_policy() {
+        let (ca, bootstrap) = (Ca::new("Issuing CA"), Ca::new("Factory CA"));
+        let est = Est { policy: Policy::required_ou("Admins"), ..est("policy", &ca, &bootstrap) };
+
+        assert_eq!(status(est.enroll(&request(Some(ca.peer("dev-1")), None, "dev-1"), true).await), 403);
+    }
+}
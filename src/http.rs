@@ -0,0 +1,740 @@
+//! Minimal HTTP/1.1 server for the mTLS connection: request-line and header parsing,
+//! Content-Length and chunked request bodies, keep-alive and pipelining.
+//!
+//! Malformed requests get a 400, oversized heads a 431 and oversized bodies a 413; the
+//! connection is closed after any error response since the stream can't be resynchronised.
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::peer::PeerIdentity;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// How long a kept-alive connection may sit between requests.
+    pub idle_timeout_secs: u64,
+    /// Time allowed to receive a complete request head once its first byte arrived.
+    pub header_timeout_secs: u64,
+    /// Time allowed to receive the request body after the head.
+    pub body_timeout_secs: u64,
+    /// Request line plus headers (and chunked trailers); larger heads get a 431.
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    /// Larger bodies get a 413.
+    pub max_body_bytes: usize,
+    /// Requests served on one connection before it is closed.
+    pub max_requests: usize,
+    /// Requests answered back to back from already-buffered input before the connection
+    /// is closed; the client has to resend the rest.
+    pub max_pipelined: usize,
+    /// Offer `h2` in ALPN next to `http/1.1`.
+    pub http2: bool,
+    /// Streams an HTTP/2 client may have open at once.
+    pub max_concurrent_streams: u32,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            idle_timeout_secs: 60,
+            header_timeout_secs: 10,
+            body_timeout_secs: 30,
+            max_header_bytes: 16 * 1024,
+            max_headers: 100,
+            max_body_bytes: 1024 * 1024,
+            max_requests: 1000,
+            max_pipelined: 16,
+            http2: true,
+            max_concurrent_streams: 100,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (key, value) in [
+            ("idle_timeout_secs", self.idle_timeout_secs as usize),
+            ("header_timeout_secs", self.header_timeout_secs as usize),
+            ("body_timeout_secs", self.body_timeout_secs as usize),
+            ("max_header_bytes", self.max_header_bytes),
+            ("max_headers", self.max_headers),
+            ("max_requests", self.max_requests),
+            ("max_pipelined", self.max_pipelined),
+            ("max_concurrent_streams", self.max_concurrent_streams as usize),
+        ] {
+            if value == 0 {
+                bail!("http.{key}: must be at least 1");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    Http2,
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: Version,
+    /// Header names as sent; look them up with [`Request::header`].
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// The verified client of the connection the request arrived on; `None` only on
+    /// listeners where a client certificate is optional.
+    pub peer: Option<Arc<PeerIdentity>>,
+}
+
+impl Request {
+    /// First value of a header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Client subject for log lines, `-` without a certificate.
+    pub fn subject(&self) -> &str {
+        subject(&self.peer)
+    }
+
+    /// Whether the client allows the connection to stay open after this request.
+    pub fn keep_alive(&self) -> bool {
+        let has = |token: &str| self.headers.iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token));
+        match self.version {
+            Version::Http11 | Version::Http2 => !has("close"),
+            Version::Http10 => has("keep-alive"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// Content-Length, Transfer-Encoding and Connection are managed by the server.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: body.into().into_bytes(),
+        }
+    }
+}
+
+/// Subject of an optional peer as in the log lines, `-` when there is none.
+pub fn subject(peer: &Option<Arc<PeerIdentity>>) -> &str {
+    peer.as_deref().map_or("-", |p| p.subject.as_str())
+}
+
+/// Serves the requests of one connection.
+pub trait Handler {
+    fn handle(&self, req: Request) -> impl Future<Output = Response> + Send;
+}
+
+/// The historical behaviour: answer every request with `ok`.
+pub struct OkHandler;
+
+impl Handler for OkHandler {
+    async fn handle(&self, _req: Request) -> Response {
+        Response::text(200, "ok\n")
+    }
+}
+
+/// Why reading a request stopped.
+enum Error {
+    /// Answer with this status and close.
+    Status(u16),
+    /// The peer went away (or stayed idle); close without a response.
+    Closed,
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Longest chunk-size line accepted, extensions included.
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// Run the request/response loop until the client closes, a limit is hit or an error
+/// response has been sent.
+pub async fn serve<S, H>(io: S, config: &HttpConfig, handler: &H, peer: Option<Arc<PeerIdentity>>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: Handler,
+{
+    let mut conn = Conn { io, buf: Vec::new() };
+    let mut served = 0;
+    let mut pipelined = 0;
+    loop {
+        // Input already waiting means the client pipelined this request behind the last one.
+        pipelined = if conn.buf.is_empty() { 0 } else { pipelined + 1 };
+
+        let req = match conn.read_request(config, peer.clone()).await {
+            Ok(req) => req,
+            Err(Error::Closed) => return Ok(()),
+            Err(Error::Status(status)) => {
+                debug!(status, "bad request");
+                conn.write_response(&Response::text(status, format!("{}\n", reason(status))), false, false).await?;
+                return Ok(());
+            }
+            Err(Error::Io(e)) => return Err(e.into()),
+        };
+        served += 1;
+
+        let keep_alive = req.keep_alive() && served < config.max_requests && pipelined < config.max_pipelined;
+        let head = req.method == "HEAD";
+        let (method, target) = (req.method.clone(), req.target.clone());
+        let resp = handler.handle(req).await;
+        debug!(method, path = target, status = resp.status, "request");
+        conn.write_response(&resp, keep_alive, head).await?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+struct Conn<S> {
+    io: S,
+    /// Received but not yet consumed bytes.
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Conn<S> {
+    /// Read more input, failing with `on_timeout` at `deadline`. EOF is `Closed`.
+    async fn fill(&mut self, deadline: Instant, on_timeout: Error) -> Result<(), Error> {
+        let mut chunk = [0u8; 8192];
+        match tokio::time::timeout_at(deadline, self.io.read(&mut chunk)).await {
+            Err(_) => Err(on_timeout),
+            Ok(Ok(0)) => Err(Error::Closed),
+            Ok(Ok(n)) => {
+                self.buf.extend_from_slice(&chunk[..n]);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e.into()),
+        }
+    }
+
+    async fn read_request(&mut self, config: &HttpConfig, peer: Option<Arc<PeerIdentity>>) -> Result<Request, Error> {
+        // Wait for the first byte; blank lines between requests are tolerated.
+        let idle = Instant::now() + Duration::from_secs(config.idle_timeout_secs);
+        loop {
+            let blank = self.buf.iter().take_while(|b| matches!(b, b'\r' | b'\n')).count();
+            self.buf.drain(..blank);
+            if !self.buf.is_empty() {
+                break;
+            }
+            self.fill(idle, Error::Closed).await?;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(config.header_timeout_secs);
+        let head = self.head(config.max_header_bytes, deadline).await?;
+        let mut req = parse_head(&head, config, peer)?;
+
+        let framing = framing(&req, config)?;
+        if framing != Framing::None {
+            match req.header("expect") {
+                Some(e) if e.trim().eq_ignore_ascii_case("100-continue") && req.version == Version::Http11 => {
+                    self.io.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+                }
+                Some(_) => return Err(Error::Status(417)),
+                None => {}
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(config.body_timeout_secs);
+        req.body = match framing {
+            Framing::None => Vec::new(),
+            Framing::Length(len) => self.take(len, deadline).await?,
+            Framing::Chunked => self.read_chunked(config.max_body_bytes, config.max_header_bytes, deadline).await?,
+        };
+        Ok(req)
+    }
+
+    /// Take a complete message head (start line and headers) of at most `max` bytes.
+    async fn head(&mut self, max: usize, deadline: Instant) -> Result<Vec<u8>, Error> {
+        let end = loop {
+            if let Some(end) = head_end(&self.buf) {
+                break end;
+            }
+            if self.buf.len() > max {
+                return Err(Error::Status(431));
+            }
+            self.fill(deadline, Error::Status(408)).await?;
+        };
+        if end > max {
+            return Err(Error::Status(431));
+        }
+        Ok(self.buf.drain(..end).collect())
+    }
+
+    /// Take exactly `len` bytes of input.
+    async fn take(&mut self, len: usize, deadline: Instant) -> Result<Vec<u8>, Error> {
+        while self.buf.len() < len {
+            self.fill(deadline, Error::Status(408)).await?;
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Take one line (without its line ending) of at most `max` bytes.
+    async fn line(&mut self, max: usize, deadline: Instant, too_long: u16) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|&b| b == b'\n') {
+                if i > max {
+                    return Err(Error::Status(too_long));
+                }
+                let mut line: Vec<u8> = self.buf.drain(..=i).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            }
+            if self.buf.len() > max {
+                return Err(Error::Status(too_long));
+            }
+            self.fill(deadline, Error::Status(408)).await?;
+        }
+    }
+
+    async fn read_chunked(&mut self, max_body: usize, max_header: usize, deadline: Instant) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.line(MAX_CHUNK_LINE, deadline, 400).await?;
+            let size = std::str::from_utf8(&line).ok()
+                .map(|l| l.split(';').next().unwrap_or("").trim())
+                .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|s| usize::from_str_radix(s, 16).ok())
+                .ok_or(Error::Status(400))?;
+            if size == 0 {
+                break;
+            }
+            // `body` never exceeds `max_body`, so this can't overflow, and a size beyond
+            // the limit is refused before anything is buffered.
+            if size > max_body - body.len() {
+                return Err(Error::Status(413));
+            }
+            body.extend(self.take(size, deadline).await?);
+            if !self.line(2, deadline, 400).await?.is_empty() {
+                return Err(Error::Status(400));
+            }
+        }
+        // Trailers are read and dropped; they count against the header limit.
+        let mut trailers = 0;
+        loop {
+            let line = self.line(max_header, deadline, 431).await?;
+            if line.is_empty() {
+                return Ok(body);
+            }
+            trailers += line.len() + 2;
+            if trailers > max_header {
+                return Err(Error::Status(431));
+            }
+        }
+    }
+
+    async fn write_response(&mut self, resp: &Response, keep_alive: bool, head: bool) -> std::io::Result<()> {
+        let mut out = format!("HTTP/1.1 {} {}\r\n", resp.status, reason(resp.status));
+        for (name, value) in &resp.headers {
+            if ["content-length", "transfer-encoding", "connection"].iter().any(|h| name.eq_ignore_ascii_case(h)) {
+                continue;
+            }
+            out.push_str(&format!("{name}: {value}\r\n"));
+        }
+        let bodyless = matches!(resp.status, 100..=199 | 204 | 304);
+        if !bodyless {
+            out.push_str(&format!("Content-Length: {}\r\n", resp.body.len()));
+        }
+        out.push_str(if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" });
+
+        let mut out = out.into_bytes();
+        if !head && !bodyless {
+            out.extend_from_slice(&resp.body);
+        }
+        self.io.write_all(&out).await?;
+        self.io.flush().await
+    }
+}
+
+/// Read one response from an upstream that was asked to close the connection after it.
+/// `head` says the request was HEAD, so no body follows. Interim 1xx responses are skipped.
+pub async fn read_response<S>(io: S, head: bool, max_header: usize, max_body: usize, deadline: Instant) -> Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = Conn { io, buf: Vec::new() };
+    let explain = |e: Error| match e {
+        Error::Status(408) => anyhow!("timed out reading the response"),
+        Error::Status(413) => anyhow!("response body larger than {max_body} bytes"),
+        Error::Status(431) => anyhow!("response head larger than {max_header} bytes"),
+        Error::Status(_) => anyhow!("malformed response"),
+        Error::Closed => anyhow!("connection closed before the response was complete"),
+        Error::Io(e) => e.into(),
+    };
+
+    let (status, headers) = loop {
+        let raw = conn.head(max_header, deadline).await.map_err(explain)?;
+        let (status, headers) = parse_status_head(&raw).ok_or_else(|| anyhow!("malformed response head"))?;
+        if !(100..200).contains(&status) || status == 101 {
+            break (status, headers);
+        }
+    };
+    let mut resp = Response { status, headers, body: Vec::new() };
+
+    let chunked = resp.headers.iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .last()
+        .is_some_and(|e| e.trim().eq_ignore_ascii_case("chunked"));
+    let length = resp.headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .map(|(_, v)| v.trim().parse::<usize>().map_err(|_| anyhow!("invalid Content-Length {v:?}")))
+        .transpose()?;
+
+    resp.body = if head || matches!(status, 101 | 204 | 304) {
+        Vec::new()
+    } else if chunked {
+        conn.read_chunked(max_body, max_header, deadline).await.map_err(explain)?
+    } else if let Some(len) = length {
+        if len > max_body {
+            return Err(explain(Error::Status(413)));
+        }
+        conn.take(len, deadline).await.map_err(explain)?
+    } else {
+        // No framing: the body runs until the upstream closes.
+        loop {
+            if conn.buf.len() > max_body {
+                return Err(explain(Error::Status(413)));
+            }
+            match conn.fill(deadline, Error::Status(408)).await {
+                Ok(()) => {}
+                Err(Error::Closed) => break std::mem::take(&mut conn.buf),
+                Err(e) => return Err(explain(e)),
+            }
+        }
+    };
+    Ok(resp)
+}
+
+fn parse_status_head(head: &[u8]) -> Option<(u16, Vec<(String, String)>)> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+    let mut status_line = lines.next()?.splitn(3, ' ');
+    if !status_line.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let status = status_line.next()?.parse().ok()?;
+    let headers = lines.take_while(|l| !l.is_empty())
+        .map(|l| l.split_once(':').map(|(k, v)| (k.trim().to_string(), v.trim().to_string())))
+        .collect::<Option<Vec<_>>>()?;
+    Some((status, headers))
+}
+
+/// Length of the request head including the blank line that ends it.
+fn head_end(buf: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while let Some(n) = buf[i..].iter().position(|&b| b == b'\n') {
+        let next = i + n + 1;
+        match &buf[next..] {
+            [b'\n', ..] => return Some(next + 1),
+            [b'\r', b'\n', ..] => return Some(next + 2),
+            _ => i = next,
+        }
+    }
+    None
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn parse_head(head: &[u8], config: &HttpConfig, peer: Option<Arc<PeerIdentity>>) -> Result<Request, Error> {
+    let head = std::str::from_utf8(head).map_err(|_| Error::Status(400))?;
+    let mut lines = head.lines();
+
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(Error::Status(400));
+    };
+    if !is_token(method) || target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(Error::Status(400));
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.starts_with("HTTP/") => return Err(Error::Status(505)),
+        _ => return Err(Error::Status(400)),
+    };
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        // Obsolete line folding and whitespace before the colon are both rejected.
+        let (name, value) = line.split_once(':').ok_or(Error::Status(400))?;
+        if !is_token(name) {
+            return Err(Error::Status(400));
+        }
+        // `lines()` only strips a CR before the LF; a bare CR or any other control byte
+        // left in a value could split it into two headers further down the line.
+        if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+            return Err(Error::Status(400));
+        }
+        if headers.len() == config.max_headers {
+            return Err(Error::Status(431));
+        }
+        headers.push((name.to_string(), value.trim_matches([' ', '\t']).to_string()));
+    }
+
+    let req = Request { method: method.to_string(), target: target.to_string(), version, headers, body: Vec::new(), peer };
+    if version == Version::Http11 && req.header("host").is_none() {
+        return Err(Error::Status(400));
+    }
+    Ok(req)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    None,
+    Length(usize),
+    Chunked,
+}
+
+/// How the request body is delimited. Conflicting or ambiguous framing is refused, since
+/// a proxy in front of us might have read it differently.
+fn framing(req: &Request, config: &HttpConfig) -> Result<Framing, Error> {
+    let values = |name: &str| -> Vec<&str> {
+        req.headers.iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .collect()
+    };
+    let encodings = values("transfer-encoding");
+    let lengths = values("content-length");
+
+    if !encodings.is_empty() {
+        if !lengths.is_empty() || req.version == Version::Http10 {
+            return Err(Error::Status(400));
+        }
+        return match encodings.as_slice() {
+            [e] if e.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+            _ => Err(Error::Status(501)),
+        };
+    }
+    let Some(first) = lengths.first() else {
+        return Ok(Framing::None);
+    };
+    if lengths.iter().any(|l| l != first) || first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::Status(400));
+    }
+    match first.parse::<usize>() {
+        Ok(0) => Ok(Framing::None),
+        Ok(len) if len <= config.max_body_bytes => Ok(Framing::Length(len)),
+        _ => Err(Error::Status(413)),
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        417 => "Expectation Failed",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers with the target and body it got, so responses show which request they belong to.
+    struct Echo;
+
+    impl Handler for Echo {
+        async fn handle(&self, req: Request) -> Response {
+            Response::text(200, format!("{} {}", req.target, String::from_utf8_lossy(&req.body)))
+        }
+    }
+
+    /// Everything the server writes for `raw`, sent at once and followed by EOF.
+    async fn serve_raw(config: &HttpConfig, raw: &[u8]) -> String {
+        let (mut client, io) = tokio::io::duplex(64 * 1024);
+        client.write_all(raw).await.unwrap();
+        client.shutdown().await.unwrap();
+        serve(io, config, &Echo, None).await.unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        out
+    }
+
+    fn statuses(out: &str) -> Vec<&str> {
+        out.match_indices("HTTP/1.1 ").map(|(i, _)| &out[i + 9..i + 12]).collect()
+    }
+
+    #[tokio::test]
+    async fn malformed_heads_get_400() {
+        let config = HttpConfig::default();
+        for raw in [
+            &b"GET /\r\nHost: a\r\n\r\n"[..],
+            b"GET  / HTTP/1.1\r\nHost: a\r\n\r\n",
+            b"G(T / HTTP/1.1\r\nHost: a\r\n\r\n",
+            b"GET /a\x01 HTTP/1.1\r\nHost: a\r\n\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nNo-Colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-A : b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-A: b\rX-Injected: 1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-A: b\r\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-A: b\x00c\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-A: b\x7f\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n",
+        ] {
+            let out = serve_raw(&config, raw).await;
+            assert_eq!(statuses(&out), ["400"], "{:?}: {out}", String::from_utf8_lossy(raw));
+            assert!(out.contains("Connection: close\r\n"), "{out}");
+        }
+    }
+
+    #[tokio::test]
+    async fn tabs_and_obs_text_in_values_are_kept() {
+        let out = serve_raw(&HttpConfig::default(), "GET / HTTP/1.1\r\nHost: a\r\nX-A: b\tc é\r\n\r\n".as_bytes()).await;
+        assert_eq!(statuses(&out), ["200"], "{out}");
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_get_413() {
+        let config = HttpConfig { max_body_bytes: 4, ..HttpConfig::default() };
+        for raw in [
+            &b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nabcde"[..],
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        ] {
+            let out = serve_raw(&config, raw).await;
+            assert_eq!(statuses(&out), ["413"], "{:?}: {out}", String::from_utf8_lossy(raw));
+        }
+        let out = serve_raw(&config, b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nabcd").await;
+        assert!(out.ends_with("/ abcd"), "{out}");
+    }
+
+    #[tokio::test]
+    async fn large_heads_get_431() {
+        let config = HttpConfig { max_headers: 2, max_header_bytes: 64, ..HttpConfig::default() };
+        let out = serve_raw(&config, b"GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\n\r\n").await;
+        assert_eq!(statuses(&out), ["431"], "{out}");
+        let raw = format!("GET / HTTP/1.1\r\nHost: a\r\nA: {}\r\n\r\n", "x".repeat(64));
+        assert_eq!(statuses(&serve_raw(&config, raw.as_bytes()).await), ["431"]);
+        // Without the blank line that ends it, the head is refused once it outgrows the limit.
+        let raw = format!("GET / HTTP/1.1\r\nHost: a\r\nA: {}", "x".repeat(64));
+        assert_eq!(statuses(&serve_raw(&config, raw.as_bytes()).await), ["431"]);
+    }
+
+    #[tokio::test]
+    async fn other_versions_get_505() {
+        let out = serve_raw(&HttpConfig::default(), b"GET / HTTP/2.0\r\nHost: a\r\n\r\n").await;
+        assert_eq!(statuses(&out), ["505"], "{out}");
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        let raw = b"POST /a HTTP/1.1\r\nHost: h\r\nContent-Length: 3\r\n\r\n123\
+                    POST /b HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n45\r\n0\r\n\r\n";
+        let out = serve_raw(&HttpConfig::default(), raw).await;
+        assert_eq!(statuses(&out), ["200", "200"], "{out}");
+        let (a, b) = (out.find("/a 123").unwrap(), out.find("/b 45").unwrap());
+        assert!(a < b, "{out}");
+    }
+
+    #[tokio::test]
+    async fn keep_alive_limits_close_the_connection() {
+        let get = "GET / HTTP/1.1\r\nHost: h\r\n\r\n";
+
+        // The last request allowed is answered with Connection: close; the rest is dropped.
+        let config = HttpConfig { max_requests: 2, ..HttpConfig::default() };
+        let out = serve_raw(&config, get.repeat(3).as_bytes()).await;
+        assert_eq!(statuses(&out), ["200", "200"], "{out}");
+        assert_eq!(out.matches("Connection: keep-alive").count(), 1, "{out}");
+        assert!(out.trim_end_matches("/ ").ends_with("Connection: close\r\n\r\n"), "{out}");
+
+        // Only one request may be answered from already-buffered input.
+        let config = HttpConfig { max_pipelined: 1, ..HttpConfig::default() };
+        assert_eq!(statuses(&serve_raw(&config, get.repeat(3).as_bytes()).await), ["200", "200"]);
+
+        // The client asked for it to close, or didn't ask to keep it open.
+        for raw in ["GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n", "GET / HTTP/1.0\r\n\r\n"] {
+            let out = serve_raw(&HttpConfig::default(), raw.repeat(2).as_bytes()).await;
+            assert_eq!(statuses(&out), ["200"], "{raw:?}: {out}");
+        }
+        let out = serve_raw(&HttpConfig::default(), "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n".repeat(2).as_bytes()).await;
+        assert_eq!(statuses(&out), ["200", "200"], "{out}");
+    }
+
+    async fn response(raw: &[u8]) -> Result<Response> {
+        let (mut upstream, io) = tokio::io::duplex(64 * 1024);
+        upstream.write_all(raw).await.unwrap();
+        drop(upstream);
+        read_response(io, false, 8192, 1024, Instant::now() + Duration::from_secs(5)).await
+    }
+
+    #[tokio::test]
+    async fn chunked_body_within_limit() {
+        let resp = response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n").await.unwrap();
+        assert_eq!(resp.body, b"abcde");
+    }
+
+    #[tokio::test]
+    async fn chunk_size_overflow_is_refused() {
+        for size in ["ffffffffffffffff", "fffffffffffffff0", "401"] {
+            let raw = format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n10\r\n0123456789abcdef\r\n{size}\r\n");
+            let err = response(raw.as_bytes()).await.unwrap_err();
+            assert!(err.to_string().contains("larger than 1024"), "{size}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_adding_up_past_the_limit_are_refused() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n200\r\n".to_string()
+            + &"x".repeat(0x200) + "\r\n201\r\n";
+        assert!(response(raw.as_bytes()).await.is_err());
+    }
+}
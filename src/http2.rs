@@ -0,0 +1,155 @@
+//! HTTP/2 for connections that negotiated `h2` in ALPN.
+//!
+//! Every stream runs as its own task and is handed to the same [`Handler`] as HTTP/1.1
+//! requests, carrying the connection's verified peer identity. The `[http]` limits
+//! apply per stream (body size and timeout) and per connection (idle time, requests).
+
+use anyhow::Result;
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::RecvStream;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinSet;
+
+use tracing::{debug, error, Instrument};
+
+use crate::http::{Handler, HttpConfig, Request, Response, Version};
+use crate::peer::PeerIdentity;
+
+/// Headers that are meaningless (and forbidden) in HTTP/2 responses.
+const CONNECTION_HEADERS: [&str; 6] =
+    ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade", "content-length"];
+
+/// Serve streams until the client goes away, the connection idles out or `max_requests`
+/// streams have been accepted (then GOAWAY lets the open ones finish).
+pub async fn serve<S, H>(io: S, config: &HttpConfig, handler: Arc<H>, peer: Option<Arc<PeerIdentity>>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: Handler + Send + Sync + 'static,
+{
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .max_header_list_size(config.max_header_bytes.try_into().unwrap_or(u32::MAX))
+        .handshake(io);
+    let mut conn = tokio::time::timeout(Duration::from_secs(config.header_timeout_secs), handshake).await
+        .map_err(|_| anyhow::anyhow!("HTTP/2 preface timed out"))??;
+
+    let idle = Duration::from_secs(config.idle_timeout_secs);
+    let limits = Limits { max_body: config.max_body_bytes, body_timeout: Duration::from_secs(config.body_timeout_secs) };
+    let mut streams = JoinSet::new();
+    let mut served = 0;
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            next = conn.accept() => match next {
+                Some(Ok((req, respond))) => {
+                    served += 1;
+                    if served >= config.max_requests && !closing {
+                        conn.graceful_shutdown();
+                        closing = true;
+                    }
+                    streams.spawn(stream(req, respond, handler.clone(), peer.clone(), limits).in_current_span());
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+            Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            // Nothing in flight, so skip the PING round trip of a graceful shutdown.
+            _ = tokio::time::sleep(idle), if streams.is_empty() && !closing => {
+                conn.abrupt_shutdown(h2::Reason::NO_ERROR);
+                closing = true;
+            }
+        }
+    }
+    while streams.join_next().await.is_some() {}
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    max_body: usize,
+    body_timeout: Duration,
+}
+
+async fn stream<H: Handler>(
+    req: ::http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    handler: Arc<H>,
+    peer: Option<Arc<PeerIdentity>>,
+    limits: Limits,
+) {
+    let (parts, mut body) = req.into_parts();
+    let (method, uri) = (parts.method.to_string(), parts.uri.to_string());
+    let head = parts.method == ::http::Method::HEAD;
+
+    let resp = match tokio::time::timeout(limits.body_timeout, read_body(&mut body, &parts.headers, limits.max_body)).await {
+        Err(_) => Response::text(408, "Request Timeout\n"),
+        Ok(Err(status)) => Response::text(status, format!("{}\n", crate::http::reason(status))),
+        Ok(Ok(body)) => {
+            let mut headers: Vec<(String, String)> = parts.headers.iter()
+                .map(|(k, v)| (k.as_str().to_string(), String::from_utf8_lossy(v.as_bytes()).into_owned()))
+                .collect();
+            // Handlers look for Host as they would on HTTP/1.1.
+            if let Some(authority) = parts.uri.authority().filter(|_| !parts.headers.contains_key("host")) {
+                headers.push(("host".to_string(), authority.to_string()));
+            }
+            let target = parts.uri.path_and_query().map(|p| p.to_string()).unwrap_or_else(|| "/".to_string());
+            let req = Request { method: parts.method.to_string(), target, version: Version::Http2, headers, body, peer };
+            handler.handle(req).await
+        }
+    };
+
+    debug!(method, path = uri, status = resp.status, "request");
+    if let Err(e) = send(&mut respond, resp, head) {
+        debug!(method, path = uri, "sending response failed: {e}");
+    }
+}
+
+/// Collect the request body, returning the status to answer with if it is too large.
+async fn read_body(body: &mut RecvStream, headers: &::http::HeaderMap, max: usize) -> Result<Vec<u8>, u16> {
+    let declared = headers.get("content-length").and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > max) {
+        return Err(413);
+    }
+    let mut out = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| 400u16)?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        if out.len() + chunk.len() > max {
+            return Err(413);
+        }
+        out.extend_from_slice(&chunk);
+    }
+    Ok(out)
+}
+
+fn send(respond: &mut SendResponse<Bytes>, resp: Response, head: bool) -> Result<(), h2::Error> {
+    let bodyless = matches!(resp.status, 100..=199 | 204 | 304);
+    let mut builder = ::http::Response::builder().status(resp.status);
+    for (name, value) in &resp.headers {
+        if !CONNECTION_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            builder = builder.header(name, value);
+        }
+    }
+    if !bodyless {
+        builder = builder.header("content-length", resp.body.len());
+    }
+    let (response, body) = match builder.body(()) {
+        Ok(response) => (response, resp.body),
+        Err(e) => {
+            error!("handler produced an invalid response: {e}");
+            let mut response = ::http::Response::new(());
+            *response.status_mut() = ::http::StatusCode::INTERNAL_SERVER_ERROR;
+            (response, Vec::new())
+        }
+    };
+
+    let end = head || bodyless || body.is_empty();
+    let mut stream = respond.send_response(response, end)?;
+    if !end {
+        stream.send_data(Bytes::from(body), true)?;
+    }
+    Ok(())
+}
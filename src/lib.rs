@@ -0,0 +1,135 @@
+//! mTLS server and client built on tokio-openssl.
+//!
+//! The `tokio_openssl_server` binary is the server; `mtls-client` uses [`client`] to
+//! connect to it the way any other client would.
+
+use openssl::nid::Nid;
+use openssl::x509::{X509NameRef, X509StoreContextRef, X509VerifyResult};
+
+pub mod admin;
+pub mod audit;
+pub mod check;
+pub mod client;
+pub mod config;
+pub mod est;
+pub mod http;
+pub mod http2;
+pub mod logging;
+pub mod ocsp;
+pub mod peer;
+pub mod pki;
+pub mod policy;
+pub mod proxy;
+pub mod reload;
+pub mod renew;
+pub mod secret;
+pub mod stapling;
+pub mod tls;
+pub mod tunnel;
+pub mod upstream;
+
+use config::StaleCrl;
+use policy::Policy;
+use tracing::{debug, info, warn, Level};
+
+pub const TRUST_DEVICES: &str = "TrustedDevices";
+
+/// `C=US, O=..., CN=...`, the form used in every log line.
+pub fn x509_name_to_string(name: &X509NameRef) -> String {
+    let mut parts = Vec::new();
+    for e in name.entries() {
+        let key = e.object().nid().short_name().unwrap_or("UNKNOWN");
+        let val = e.data().as_utf8()
+            .map(|s| s.to_string())                // owned String
+            .unwrap_or_else(|_| hex::encode(e.data().as_slice()));
+        parts.push(format!("{key}={val}"));
+    }
+    parts.join(", ")
+}
+
+
+
+pub fn verifier_always_true_cb(_preverified: bool, _x509_ctx: &mut X509StoreContextRef) -> bool {
+    warn!("always verified true!");
+    true
+}
+
+pub fn verifier_cb(preverified: bool, x509_ctx: &mut X509StoreContextRef, policy: &Policy, stale_crl: StaleCrl) -> bool {
+    audit::record_chain(x509_ctx);
+
+    // display the chain
+    if let Some(chain) = x509_ctx.chain().filter(|_| tracing::enabled!(Level::DEBUG)) {
+        for (i, c) in chain.iter().enumerate() {
+            debug!(depth = i, subject = x509_name_to_string(c.subject_name()), "chain");
+        }
+    }
+
+    // You can inspect the "current" cert in the chain:
+    if let Some(cert) = x509_ctx.current_cert() {
+        if let Some(cn) = cert.subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .next()
+            .and_then(|e| e.data().as_utf8().ok())
+        {
+            debug!(depth = x509_ctx.error_depth(), cn = %cn, "verifying peer certificate");
+        }
+    }
+
+    // Keep OpenSSL's verdict unless you have a strong reason to override:
+    if !preverified {
+        let err = x509_ctx.error();
+        let depth = x509_ctx.error_depth();
+        let subject = x509_ctx.current_cert()
+            .map(|c| x509_name_to_string(c.subject_name()))
+            .unwrap_or_default();
+        match err.as_raw() {
+            openssl_sys::X509_V_ERR_CRL_HAS_EXPIRED if stale_crl == StaleCrl::Warn => {
+                warn!(depth, subject, "stale CRL; accepting per trust.stale_crl");
+                return true;
+            }
+            openssl_sys::X509_V_ERR_CERT_REVOKED => {
+                let serial = x509_ctx.current_cert().map(policy::serial_hex).unwrap_or_default();
+                warn!(depth, serial, subject, "certificate revoked");
+            }
+            _ => warn!(depth, error = %err, code = err.as_raw(), subject, "peer verification failed"),
+        }
+        audit::record_error(x509_ctx);
+        return false;
+    }
+
+    // Only ENFORCE our policy on the LEAF certificate (depth 0).
+    if x509_ctx.error_depth() != 0 {
+        // Log if you want visibility, but don't enforce OU here.
+        if let Some(c) = x509_ctx.current_cert().filter(|_| tracing::enabled!(Level::DEBUG)) {
+            // small helper to print CN
+            let cn = c.subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .next()
+                .and_then(|e| e.data().as_utf8().ok())
+                .map(|s| s.to_string())
+                .unwrap_or_default();
+            debug!(depth = x509_ctx.error_depth(), cn, "intermediate verified");
+        }
+        return true;
+    }
+
+    // depth == 0 (leaf) — ENFORCE the configured policy
+    let Some(leaf) = x509_ctx.current_cert() else {
+        warn!("no current cert at depth 0");
+        audit::record_error(x509_ctx);
+        return false;
+    };
+
+    let verdict = policy.evaluate(leaf);
+    peer::record_verdict(x509_ctx, &verdict);
+    if verdict.accepted() {
+        info!(verdict = %verdict, subject = x509_name_to_string(leaf.subject_name()), "accept leaf");
+    } else {
+        // Helpful: print the full subject so you can see what’s actually there
+        warn!(verdict = %verdict, subject = x509_name_to_string(leaf.subject_name()), "reject leaf");
+        x509_ctx.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+        audit::record_error(x509_ctx);
+    }
+
+    verdict.accepted()
+}
@@ -0,0 +1,144 @@
+//! Logging through `tracing`, as human-readable text or JSON lines on stderr.
+//!
+//! Every accepted connection runs inside a `conn` span carrying a connection ID and the
+//! peer address; the SNI, TLS version, cipher and client subject are added as the
+//! handshake learns them, so every event about a connection, a failed handshake
+//! included, can be told apart from those of its neighbours.
+
+use anyhow::{Context, Result};
+use openssl::ssl::{NameType, SslRef};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::field::Empty;
+use tracing::level_filters::LevelFilter;
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("unknown log level {s:?} (expected error, warn, info, debug or trace)")),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per event, with the fields of its spans.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {s:?} (expected text or json)")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Levels for individual modules, overriding `level`: the event target, e.g.
+    /// `tokio_openssl_server::http` or `h2`, and everything below it.
+    pub modules: BTreeMap<String, LogLevel>,
+}
+
+impl LoggingConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.filter().context("logging.modules")?;
+        Ok(())
+    }
+
+    fn filter(&self) -> Result<EnvFilter> {
+        let mut filter = EnvFilter::default().add_directive(LevelFilter::from(self.level).into());
+        for (target, level) in &self.modules {
+            let directive = format!("{target}={}", LevelFilter::from(*level));
+            filter = filter.add_directive(directive.parse().with_context(|| format!("invalid module {target:?}"))?);
+        }
+        Ok(filter)
+    }
+}
+
+/// Install the global subscriber. `RUST_LOG`, when set, replaces the configured levels.
+pub fn init(config: &LoggingConfig) -> Result<()> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.is_empty() => EnvFilter::try_new(&directives).context("RUST_LOG")?,
+        _ => config.filter()?,
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    match config.format {
+        LogFormat::Text => builder.with_ansi(std::io::stderr().is_terminal()).try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    }
+    .map_err(|e| anyhow::anyhow!(e))
+}
+
+static NEXT_CONN: AtomicU64 = AtomicU64::new(1);
+
+/// Span for one accepted connection; the handshake fields are filled in later by
+/// [`record_handshake`] and [`record_subject`].
+pub fn conn_span(peer: SocketAddr) -> Span {
+    let id = NEXT_CONN.fetch_add(1, Ordering::Relaxed);
+    tracing::info_span!("conn", id, %peer, sni = Empty, tls = Empty, cipher = Empty, subject = Empty)
+}
+
+/// Record what the handshake negotiated on the current span, whether or not it completed.
+pub fn record_handshake(ssl: &SslRef) {
+    let span = Span::current();
+    if let Some(name) = ssl.servername(NameType::HOST_NAME) {
+        span.record("sni", name);
+    }
+    span.record("tls", ssl.version_str());
+    if let Some(cipher) = ssl.current_cipher() {
+        span.record("cipher", cipher.name());
+    }
+}
+
+/// Record the authenticated client on the current span.
+pub fn record_subject(subject: &str) {
+    Span::current().record("subject", subject);
+}
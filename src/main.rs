@@ -1,220 +1,252 @@
-use anyhow::{Result};
-use anyhow::anyhow;
+use anyhow::{anyhow, Result};
 use anyhow::Context;
 
 
-use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
-use openssl::ssl::SslAcceptorBuilder;
-use openssl::pkcs12::{Pkcs12};
-use openssl::string::OpensslString;
-use openssl::x509::{X509StoreContextRef, X509Ref, X509VerifyResult};
-use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslAcceptor};
 use std::pin::Pin;
 
 use tokio::net::{TcpListener, TcpStream};
 use tokio_openssl::SslStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
+
+use tokio_openssl_server::{admin, audit, check, est, http, http2, logging, peer, pki, tls};
+use tokio_openssl_server::audit::{AuditCommand, AuditLog};
+use tokio_openssl_server::check::CheckArgs;
+use tokio_openssl_server::config::{Config, Mode, Overrides};
+use tokio_openssl_server::est::{Est, Routes};
+use tokio_openssl_server::http::{Handler, HttpConfig, OkHandler};
+use tokio_openssl_server::logging::LoggingConfig;
+use tokio_openssl_server::ocsp::OcspChecker;
+use tokio_openssl_server::peer::PeerIdentity;
+use tokio_openssl_server::pki::PkiCommand;
+use tokio_openssl_server::reload::ReloadableAcceptor;
+use tokio_openssl_server::stapling::Stapler;
+use tokio_openssl_server::proxy::Proxy;
+use tokio_openssl_server::tunnel::Tunnel;
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn, Instrument};
+
+/// State shared by every listener and connection.
+struct Server {
+    acceptor: Arc<ReloadableAcceptor>,
+    ocsp: Option<OcspChecker>,
+    http: HttpConfig,
+    /// Set in proxy mode; otherwise requests are answered with `ok`.
+    proxy: Option<Arc<Proxy>>,
+    /// Set in tunnel mode, where connections carry no HTTP at all.
+    tunnel: Option<Tunnel>,
+    /// Serves `/.well-known/est/` in the HTTP modes when `[est]` is configured.
+    est: Option<Arc<Est>>,
+    /// Set when `[audit] file` is configured.
+    audit: Option<Arc<AuditLog>>,
+}
 
-pub const TRUST_DEVICES: &str = "TrustedDevices";
+#[derive(Parser)]
+#[command(version, about = "mTLS server with client-certificate policy")]
+struct Cli {
+    /// TOML configuration file
+    #[arg(short, long, env = "MTLS_CONFIG")]
+    config: Option<PathBuf>,
 
-#[tokio::main]
-async fn main() -> Result<()> {
+    #[command(flatten)]
+    overrides: Overrides,
 
-    let addr = "0.0.0.0:8443";
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let ca = X509::from_pem(&std::fs::read("client-ca.pem")?)?;
+#[derive(Subcommand)]
+enum Command {
+    /// Create CAs and issue server and client certificates (replaces mk-mtls-certs.sh)
+    #[command(subcommand)]
+    Pki(PkiCommand),
+    /// Check the configured certificates, keys and client CAs, then exit
+    Check(CheckArgs),
+    /// Work with the handshake audit log
+    #[command(subcommand)]
+    Audit(AuditCommand),
+}
 
-    let use_pem = true;
-    let mut builder:SslAcceptorBuilder;
-    
-    // Build TLS acceptor (server config)
-    if use_pem {
-        builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-        builder.set_private_key_file("key.pem", SslFiletype::PEM)?;
-        builder.set_certificate_chain_file("cert.pem")?;
-        builder.set_ca_file("client-ca.pem")?;
-        builder.add_client_ca(&ca)?;
-    } else {
-        builder = build_acceptor_from_pkcs12("server.p12", "changeit")?;
-        builder.set_ca_file("client-ca.pem")?;
-        builder.add_client_ca(&ca)?;
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if let Some(Command::Pki(command)) = cli.command {
+        // No config file here; progress goes to stderr at the default level.
+        logging::init(&LoggingConfig::default())?;
+        return pki::run(command);
+    }
+    if let Some(Command::Audit(command)) = cli.command {
+        return audit::run(command);
+    }
+    let config = Config::load(cli.config.as_deref(), &cli.overrides)?;
+    if let Some(Command::Check(args)) = &cli.command {
+        return check::run(&config, args);
+    }
+    logging::init(&config.logging)?;
+    check::preflight(&config)?;
+
+    let config = Arc::new(config);
+    let stapler = Stapler::new(&config.stapling);
+    let acceptor = Arc::new(ReloadableAcceptor::new(config.clone(), stapler.clone())?);
+    if let Some(stapler) = stapler {
+        tokio::spawn(stapler.run());
     }
 
-    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    // Bind every configured listener up front so a bad address fails at startup.
+    let mut listeners = Vec::new();
+    for addr in &config.server.listen {
+        let listener = TcpListener::bind(addr).await
+            .with_context(|| format!("server.listen: binding {}", addr))?;
+        info!("Listening on {}", addr);
+        listeners.push(listener);
+    }
 
-    builder.set_verify_callback(
-    SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
-    |preverified: bool, x509_ctx: &mut X509StoreContextRef| verifier_cb(preverified, x509_ctx));
+    let admin = match config.admin.listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await
+                .with_context(|| format!("admin.listen: binding {}", addr))?;
+            info!("Admin listening on {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
 
-    let acceptor = builder.build();
+    let est = if config.est.enabled() {
+        Some(Arc::new(Est::new(&config.est, config.policy.clone(), OcspChecker::new(&config.ocsp))?))
+    } else {
+        None
+    };
+    // The EST listener has its own acceptor (client certificates optional, no policy),
+    // built once; re-enrollment applies the policy itself.
+    let est_listener = match (&est, config.est.listen) {
+        (Some(est), Some(addr)) => {
+            let acceptor = tls::build_est_acceptor(&config, &est.anchors())?;
+            let listener = TcpListener::bind(addr).await
+                .with_context(|| format!("est.listen: binding {}", addr))?;
+            info!("EST listening on {}", addr);
+            Some((listener, acceptor, est.clone()))
+        }
+        _ => None,
+    };
 
-    // Bind TCP listener
-    let listener = TcpListener::bind(addr).await?;
-    println!("Listening on {}", addr);
+    if config.reload.watch {
+        let interval = Duration::from_secs(config.reload.poll_interval_secs);
+        tokio::spawn(acceptor.clone().watch(interval));
+    }
+    if config.has_crls() {
+        let interval = Duration::from_secs(config.reload.crl_refresh_secs);
+        tokio::spawn(acceptor.clone().periodic("CRL refresh", interval));
+    }
+    #[cfg(unix)]
+    tokio::spawn(acceptor.clone().on_sighup());
+
+    let audit = AuditLog::open(&config.audit, &config.identity)?.map(Arc::new);
+    let server = Arc::new(Server {
+        acceptor: acceptor.clone(),
+        ocsp: OcspChecker::new(&config.ocsp),
+        http: config.http.clone(),
+        proxy: match config.server.mode {
+            Mode::Proxy => Proxy::new(&config.proxy, config.http.max_header_bytes).map(Arc::new),
+            Mode::Ok | Mode::Tunnel => None,
+        },
+        tunnel: match config.server.mode {
+            Mode::Tunnel => Tunnel::new(&config.tunnel),
+            Mode::Ok | Mode::Proxy => None,
+        },
+        est,
+        audit: audit.clone(),
+    });
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for listener in listeners {
+        tasks.spawn(accept_loop(listener, server.clone()));
+    }
+    if let Some(listener) = admin {
+        tasks.spawn(admin::serve(listener, acceptor.clone()));
+    }
+    if let Some((listener, acceptor, est)) = est_listener {
+        tasks.spawn(est::serve(listener, acceptor, est, config.http.clone(), audit));
+    }
+    while let Some(res) = tasks.join_next().await {
+        res??;
+    }
+    Ok(())
+}
 
+async fn accept_loop(listener: TcpListener, server: Arc<Server>) -> Result<()> {
     loop {
         let (tcp, peer) = listener.accept().await?;
-        let acceptor = acceptor.clone();
+        // Snapshot the acceptor: a later reload doesn't affect this connection.
+        let acceptor = server.acceptor.current();
+        let server = server.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_conn(tcp, acceptor).await {
-                eprintln!("{}: {e:?}", peer);
+            if let Err(e) = handle_conn(tcp, peer, acceptor, &server).await {
+                warn!("{e:#}");
             }
-        });
+        }.instrument(logging::conn_span(peer)));
     }
 }
 
-async fn handle_conn(tcp: TcpStream, acceptor: SslAcceptor) -> Result<()> {
+async fn handle_conn(tcp: TcpStream, addr: SocketAddr, acceptor: SslAcceptor, server: &Server) -> Result<()> {
     // Create Ssl from the acceptor’s context
-    let ssl = Ssl::new(acceptor.context())?;
+    let mut ssl = Ssl::new(acceptor.context())?;
+    peer::track_verdict(&mut ssl);
+    if server.audit.is_some() {
+        audit::track(&mut ssl);
+    }
 
     // Wrap the TCP stream
     let mut tls = SslStream::new(ssl, tcp)?;
 
     // Async server-side handshake
-    Pin::new(&mut tls).accept().await?; // <- correct call
-
-    // (Optional) read the HTTP request so clients don't see EOF immediately
-    let mut buf = [0u8; 4096];
-    let _n = Pin::new(&mut tls).read(&mut buf).await.unwrap_or(0);
-
-    // Write a simple HTTP response
-    let body = b"ok\n";
-    let resp = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n",
-        body.len()
-    );
-    Pin::new(&mut tls).write_all(resp.as_bytes()).await?;
-    Pin::new(&mut tls).write_all(body).await?;
-    Pin::new(&mut tls).shutdown().await.ok(); // best-effort
-
-    Ok(())
-}
-
-use openssl::x509::{X509, X509NameRef};
-
-fn x509_name_to_string(name: &X509NameRef) -> String {
-    let mut parts = Vec::new();
-    for e in name.entries() {
-        let key = e.object().nid().short_name().unwrap_or("UNKNOWN");
-        let val = e.data().as_utf8()
-            .map(|s| s.to_string())                // owned String
-            .unwrap_or_else(|_| hex::encode(e.data().as_slice()));
-        parts.push(format!("{key}={val}"));
+    let handshake = Pin::new(&mut tls).accept().await; // <- correct call
+    logging::record_handshake(tls.ssl());
+    if let Some(audit) = &server.audit {
+        audit.record("main", addr, tls.ssl(), handshake.as_ref().err());
     }
-    parts.join(", ")
-}
+    handshake.map_err(|e| anyhow!("handshake failed: {e}"))?;
 
+    let peer = Arc::new(PeerIdentity::from_ssl(tls.ssl())?);
+    logging::record_subject(&peer.subject);
+    debug!("peer: {peer}");
 
-
-/// Heuristic to skip a root CA (self-signed) if it appears in the P12.
-/// This keeps the server from sending the root to clients.
-
-/// Robust self-signed check that works on a reference.
-fn is_self_signed(cert: &X509Ref) -> bool {
-    // OpenSSL will report OK if `cert` is issued by itself.
-    cert.issued(cert) == X509VerifyResult::OK
-}
-
-/// Build an SslAcceptor from a PKCS#12 bundle (server key + leaf + chain).
-/// Uses `parse2`, whose fields are: pkey: Option<...>, cert: Option<...>, ca: Stack<X509>.
-pub fn build_acceptor_from_pkcs12(p12_path: &str, password: &str) -> Result<SslAcceptorBuilder> {
-    let der = std::fs::read(p12_path)
-        .with_context(|| format!("reading {}", p12_path))?;
-    let p12 = Pkcs12::from_der(&der)?;
-    let mut parsed = p12.parse2(password)?; // pkey: Option<PKey<Private>>, cert: Option<X509>, ca: Stack<X509>
-
-    let pkey = parsed.pkey.ok_or_else(|| anyhow!("PKCS#12 has no private key"))?;
-    let cert = parsed.cert.ok_or_else(|| anyhow!("PKCS#12 has no end-entity certificate"))?;
-
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-    builder.set_private_key(&pkey)?;
-    builder.set_certificate(&cert)?;
-
-    // IMPORTANT: iterate the *elements* with `.iter()` so each item is `&X509Ref`.
-    for cert_ref in parsed.ca.iter_mut() {
-
-        if let Some(cr) = cert_ref.pop() {
-            if is_self_signed(cr.as_ref()) {
-                continue; // don't send a self-signed root to clients
-            }
-            // Clone the element to an owned X509; add_extra_chain_cert takes ownership.
-            let owned: X509 = cr.to_owned();
-            builder.add_extra_chain_cert(owned)?;
-        }
+    // Post-handshake revocation check of the client leaf; an error drops the connection.
+    if let Some(ocsp) = &server.ocsp {
+        ocsp.check(&peer.chain).await?;
     }
 
-    Ok(builder)
-}
-
-pub fn verifier_always_true_cb(_preverified: bool, _x509_ctx: &mut X509StoreContextRef) -> bool {
-    eprintln!("always verified true!");
-    true
-}
-
-pub fn verifier_cb(preverified: bool, x509_ctx: &mut X509StoreContextRef) -> bool {
-    // display the chain
-    if let Some(chain) = x509_ctx.chain() {
-        for (i, c) in chain.iter().enumerate() {
-            eprintln!("chain[{i}] subject={}", x509_name_to_string(c.subject_name()));
-        }
+    if let Some(tunnel) = &server.tunnel {
+        return tunnel.run(tls).await;
     }
 
-    // You can inspect the "current" cert in the chain:
-    if let Some(cert) = x509_ctx.current_cert() {
-        if let Some(cn) = cert.subject_name()
-            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
-            .next()
-            .and_then(|e| e.data().as_utf8().ok())
-        {
-            eprintln!("Verifying peer cert CN={}", cn);
+    // Serve requests until the client closes or a keep-alive limit is reached
+    match &server.proxy {
+        Some(proxy) => {
+            let handler = Arc::new(Routes::new(server.est.clone(), proxy.clone()));
+            serve_http(&mut tls, &server.http, handler, Some(peer)).await?
         }
-    }
-
-    // Keep OpenSSL's verdict unless you have a strong reason to override:
-    if !preverified {
-        eprintln!("FAILED: PREVERIFIED: Verifying peer");
-        return false;
-    }
-
-    // Only ENFORCE our policy on the LEAF certificate (depth 0).
-    if x509_ctx.error_depth() != 0 {
-        // Log if you want visibility, but don't enforce OU here.
-        if let Some(c) = x509_ctx.current_cert() {
-            eprintln!("chain[{}] subject={}", x509_ctx.error_depth(), {
-                // small helper to print CN
-                let cn = c.subject_name()
-                    .entries_by_nid(Nid::COMMONNAME)
-                    .next()
-                    .and_then(|e| e.data().as_utf8().ok())
-                    .map(|s| s.to_string())
-                    .unwrap_or_default();
-                format!("CN={}", cn)
-            });
+        None => {
+            let handler = Arc::new(Routes::new(server.est.clone(), Arc::new(OkHandler)));
+            serve_http(&mut tls, &server.http, handler, Some(peer)).await?
         }
-        return true;
     }
+    Pin::new(&mut tls).shutdown().await.ok(); // best-effort
 
-    // depth == 0 (leaf) — ENFORCE OU policy
-    let Some(leaf) = x509_ctx.current_cert() else {
-        eprintln!("no current cert at depth 0");
-        return false;
-    };
-
-
-    let has_ou = leaf.subject_name()
-        .entries_by_nid(Nid::ORGANIZATIONALUNITNAME)
-        .any(|e| matches!(e.data().as_utf8(), Ok(s) if <OpensslString as AsRef<str>>::as_ref(&s) == TRUST_DEVICES));
+    Ok(())
+}
 
-    if !has_ou {
-        // Helpful: print the full subject so you can see what’s actually there
-        eprintln!("reject leaf: missing OU={}; subject={:?}", TRUST_DEVICES, {
-            leaf.subject_name().entries()
-                .filter_map(|e| e.data().as_utf8().ok()
-                    .map(|v| format!("{}={}", e.object().nid().short_name().unwrap_or("?"), v)))
-                .collect::<Vec<_>>()
-                .join(", ")
-        });
+/// HTTP/2 if ALPN picked it, HTTP/1.1 otherwise.
+async fn serve_http<H>(tls: &mut SslStream<TcpStream>, config: &HttpConfig, handler: Arc<H>, peer: Option<Arc<PeerIdentity>>) -> Result<()>
+where
+    H: Handler + Send + Sync + 'static,
+{
+    if tls.ssl().selected_alpn_protocol() == Some(b"h2") {
+        http2::serve(tls, config, handler, peer).await
+    } else {
+        http::serve(tls, config, handler.as_ref(), peer).await
     }
-
-    has_ou
 }
@@ -0,0 +1,346 @@
+//! OCSP status checks for client leaves, run right after the handshake.
+//!
+//! The responder is the `[ocsp] responder` override or the first http:// URL in the leaf's
+//! AIA extension. Good/revoked answers are cached per certificate ID until their nextUpdate;
+//! answers without one are used once and never cached.
+
+use anyhow::{anyhow, bail, Context, Result};
+use foreign_types::ForeignTypeRef;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{
+    OcspBasicResponse, OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509Ref, X509};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::policy::serial_hex;
+use crate::x509_name_to_string;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OcspConfig {
+    pub mode: OcspMode,
+    /// Responder URL used instead of the one in the certificate's AIA extension.
+    pub responder: Option<String>,
+    pub timeout_secs: u64,
+}
+
+impl Default for OcspConfig {
+    fn default() -> Self {
+        OcspConfig { mode: OcspMode::Off, responder: None, timeout_secs: 5 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OcspMode {
+    #[default]
+    Off,
+    /// Reject revoked clients; accept (with a warning) when no answer can be obtained.
+    Soft,
+    /// Reject unless the responder says the certificate is good.
+    Hard,
+}
+
+impl OcspConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(url) = &self.responder {
+            validate_url(url).context("ocsp.responder")?;
+        }
+        if self.timeout_secs == 0 {
+            bail!("ocsp.timeout_secs: must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Slack for thisUpdate/nextUpdate on freshly fetched responses, to absorb clock skew.
+const CLOCK_SKEW_SECS: u32 = 300;
+
+/// How long after its thisUpdate a response without nextUpdate still counts as current.
+pub const MAX_AGE_SECS: u32 = 3600;
+
+/// Largest responder reply read, head included. Real responses are a few KiB.
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+/// Cached answer, good until the response's nextUpdate. Timed on tokio's clock.
+struct Cached {
+    status: Status,
+    expires: Instant,
+}
+
+pub struct OcspChecker {
+    mode: OcspMode,
+    responder: Option<String>,
+    timeout: Duration,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+/// What the responder (or cache) said about a certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+impl OcspChecker {
+    /// `None` when OCSP checking is turned off.
+    pub fn new(config: &OcspConfig) -> Option<OcspChecker> {
+        if config.mode == OcspMode::Off {
+            return None;
+        }
+        Some(OcspChecker {
+            mode: config.mode,
+            responder: config.responder.clone(),
+            timeout: Duration::from_secs(config.timeout_secs),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Check the leaf of a verified chain (leaf first, issuer next). Returns an error when
+    /// the connection must be dropped.
+    pub async fn check(&self, chain: &[X509]) -> Result<()> {
+        let (Some(leaf), Some(issuer)) = (chain.first(), chain.get(1)) else {
+            bail!("OCSP: verified chain has no issuer for the leaf");
+        };
+        let subject = x509_name_to_string(leaf.subject_name());
+
+        let status = match self.status(leaf, issuer, chain).await {
+            Ok(status) => status,
+            Err(e) if self.mode == OcspMode::Soft => {
+                warn!(subject, "OCSP check failed, accepting (soft-fail): {e:#}");
+                return Ok(());
+            }
+            Err(e) => return Err(e.context(format!("OCSP check failed for {subject} (hard-fail)"))),
+        };
+
+        match (status, self.mode) {
+            (Status::Good, _) => Ok(()),
+            (Status::Revoked, _) => {
+                warn!(serial = serial_hex(leaf), subject, "OCSP says certificate revoked");
+                bail!("client certificate revoked (OCSP)")
+            }
+            (Status::Unknown, OcspMode::Soft) => {
+                warn!(subject, "OCSP status unknown, accepting (soft-fail)");
+                Ok(())
+            }
+            (Status::Unknown, _) => bail!("OCSP status unknown for {subject} (hard-fail)"),
+        }
+    }
+
+    async fn status(&self, leaf: &X509, issuer: &X509, chain: &[X509]) -> Result<Status> {
+        let key = cache_key(leaf, issuer)?;
+        if let Some(status) = self.cached(&key) {
+            return Ok(status);
+        }
+
+        let url = match &self.responder {
+            Some(url) => url.clone(),
+            None => responder_url(leaf)?,
+        };
+        let mut req = OcspRequest::new()?;
+        req.add_id(OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)?)?;
+        let body = http_post(&url, "application/ocsp-request", &req.to_der()?, self.timeout).await?;
+
+        let response = verify_response(&body, chain)?;
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)?;
+        let status = current_status(&response, &id, CLOCK_SKEW_SECS)
+            .ok_or_else(|| anyhow!("OCSP response from {url} has no current status for the certificate"))?;
+        let ttl = next_update_in(&response, &id).filter(|secs| *secs > 0);
+        if let (Some(secs), true) = (ttl, status != Status::Unknown) {
+            let now = Instant::now();
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, entry| entry.expires > now);
+            cache.insert(key, Cached { status, expires: now + Duration::from_secs(secs as u64) });
+        }
+        Ok(status)
+    }
+
+    fn cached(&self, key: &str) -> Option<Status> {
+        let mut cache = self.cache.lock().unwrap();
+        let entry = cache.get(key)?;
+        if entry.expires > Instant::now() {
+            return Some(entry.status);
+        }
+        cache.remove(key);
+        None
+    }
+}
+
+/// Parse a DER OCSPResponse and verify its signature against the certificates in `chain`.
+pub fn verify_response(der: &[u8], chain: &[X509]) -> Result<OcspBasicResponse> {
+    let response = OcspResponse::from_der(der).context("parsing OCSP response")?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        bail!("OCSP responder returned status {}", response.status().as_raw());
+    }
+    let basic = response.basic()?;
+
+    // The chain may end at an intermediate; let it anchor verification.
+    let mut store = X509StoreBuilder::new()?;
+    store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+    let mut certs = Stack::new()?;
+    for cert in chain {
+        store.add_cert(cert.clone())?;
+        certs.push(cert.clone())?;
+    }
+    basic.verify(&certs, &store.build(), OcspFlag::empty())
+        .context("OCSP response signature does not verify")?;
+    Ok(basic)
+}
+
+/// Status for `id` if the response covers it and is within thisUpdate..nextUpdate
+/// (widened by `slack` seconds), or at most `MAX_AGE_SECS` past thisUpdate when it has no
+/// nextUpdate.
+fn current_status(response: &OcspBasicResponse, id: &OcspCertId, slack: u32) -> Option<Status> {
+    let status = response.find_status(id)?;
+    let max_age = next_update_in(response, id).is_none().then_some(MAX_AGE_SECS);
+    status.check_validity(slack, max_age).ok()?;
+    Some(match status.status {
+        OcspCertStatus::GOOD => Status::Good,
+        OcspCertStatus::REVOKED => Status::Revoked,
+        _ => Status::Unknown,
+    })
+}
+
+/// True if the response has a status for `id` that is currently within its validity window.
+pub fn is_current(response: &OcspBasicResponse, id: &OcspCertId, slack: u32) -> bool {
+    current_status(response, id, slack).is_some()
+}
+
+/// Seconds from now until the nextUpdate of `id`'s status (negative once past); `None` when
+/// the response has no status for `id` or no nextUpdate.
+fn next_update_in(response: &OcspBasicResponse, id: &OcspCertId) -> Option<i64> {
+    status_times(response, id)?.1
+}
+
+/// Seconds from now until `id`'s status stops being current: its nextUpdate, or
+/// `MAX_AGE_SECS` past its thisUpdate when it has none. `None` without a status for `id`.
+pub fn current_for(response: &OcspBasicResponse, id: &OcspCertId) -> Option<i64> {
+    let (this_update, next_update) = status_times(response, id)?;
+    Some(next_update.unwrap_or(this_update + i64::from(MAX_AGE_SECS)))
+}
+
+/// Seconds from now until the thisUpdate and the nextUpdate (if any) of `id`'s status.
+fn status_times(response: &OcspBasicResponse, id: &OcspCertId) -> Option<(i64, Option<i64>)> {
+    let (mut status, mut reason) = (0, 0);
+    let (mut revoked, mut this_update, mut next_update) = (ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+    // SAFETY: both objects are valid for the call; the returned times point into the
+    // response, which outlives their use here. A null `from` means the current time.
+    unsafe {
+        let found = openssl_sys::OCSP_resp_find_status(
+            response.as_ptr(), id.as_ptr(), &mut status, &mut reason,
+            &mut revoked, &mut this_update, &mut next_update,
+        );
+        if found != 1 || this_update.is_null() {
+            return None;
+        }
+        let from_now = |t: *mut openssl_sys::ASN1_GENERALIZEDTIME| {
+            let (mut days, mut secs) = (0, 0);
+            (openssl_sys::ASN1_TIME_diff(&mut days, &mut secs, ptr::null(), t.cast()) == 1)
+                .then(|| i64::from(days) * 86400 + i64::from(secs))
+        };
+        let next_update = if next_update.is_null() { None } else { Some(from_now(next_update)?) };
+        Some((from_now(this_update)?, next_update))
+    }
+}
+
+/// Issuer fingerprint + serial identifies a certificate the same way an OCSP CertID does.
+fn cache_key(leaf: &X509Ref, issuer: &X509Ref) -> Result<String> {
+    Ok(format!("{}:{}", hex::encode(issuer.digest(MessageDigest::sha256())?), serial_hex(leaf)))
+}
+
+/// First http:// OCSP responder in the certificate's AIA extension.
+pub fn responder_url(cert: &X509Ref) -> Result<String> {
+    cert.ocsp_responders()
+        .ok()
+        .and_then(|urls| urls.iter().map(|u| u.to_string()).find(|u| u.starts_with("http://")))
+        .ok_or_else(|| anyhow!("certificate has no http OCSP responder in AIA and none is configured"))
+}
+
+pub fn validate_url(url: &str) -> Result<()> {
+    parse_http_url(url).map(|_| ())
+}
+
+/// Split `http://host[:port]/path` into (host, port, path).
+fn parse_http_url(url: &str) -> Result<(String, u16, String)> {
+    let rest = url.strip_prefix("http://")
+        .ok_or_else(|| anyhow!("{url:?}: only http:// responder URLs are supported"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(v6) => {
+            let (h, p) = v6.split_once(']').ok_or_else(|| anyhow!("{url:?}: unterminated IPv6 address"))?;
+            (h, p.strip_prefix(':'))
+        }
+        None => match authority.split_once(':') {
+            Some((h, p)) => (h, Some(p)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(p) => p.parse().map_err(|_| anyhow!("{url:?}: invalid port"))?,
+        None => 80,
+    };
+    if host.is_empty() {
+        bail!("{url:?}: missing host");
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// Minimal HTTP/1.0 POST, enough for OCSP responders. Returns the response body; replies
+/// over `MAX_RESPONSE_SIZE` are refused.
+pub async fn http_post(url: &str, content_type: &str, body: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let (host, port, path) = parse_http_url(url)?;
+    let exchange = async {
+        let mut tcp = TcpStream::connect((host.as_str(), port)).await
+            .with_context(|| format!("connecting to {url}"))?;
+        let head = format!(
+            "POST {path} HTTP/1.0\r\nHost: {host}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        tcp.write_all(head.as_bytes()).await?;
+        tcp.write_all(body).await?;
+        let mut resp = Vec::new();
+        (&mut tcp).take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut resp).await?;
+        if resp.len() as u64 > MAX_RESPONSE_SIZE {
+            bail!("{url}: response larger than {MAX_RESPONSE_SIZE} bytes");
+        }
+        Ok::<_, anyhow::Error>(resp)
+    };
+    let resp = tokio::time::timeout(timeout, exchange).await
+        .map_err(|_| anyhow!("{url}: timed out after {timeout:?}"))??;
+
+    let split = resp.windows(4).position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("{url}: malformed HTTP response"))?;
+    let head = String::from_utf8_lossy(&resp[..split]);
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        bail!("{url}: HTTP status {status}");
+    }
+    let mut body = resp[split + 4..].to_vec();
+    let content_length = head.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.trim().parse::<usize>().ok());
+    if let Some(len) = content_length {
+        if body.len() < len {
+            bail!("{url}: truncated response body");
+        }
+        body.truncate(len);
+    }
+    Ok(body)
+}
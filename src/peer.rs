@@ -0,0 +1,142 @@
+//! The verified client behind a connection, as established by the handshake.
+//!
+//! Built once per connection and shared (behind an `Arc`) with everything that serves it,
+//! so request handlers can authorize on the certificate without re-parsing it.
+
+use anyhow::{anyhow, Context, Result};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{Ssl, SslRef};
+use openssl::x509::{X509StoreContext, X509StoreContextRef, X509};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::policy::{ip_from_bytes, serial_hex, Verdict};
+use crate::x509_name_to_string;
+
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    /// Subject DN, formatted like the log lines (`C=US, O=..., CN=...`).
+    pub subject: String,
+    pub issuer: String,
+    pub san_dns: Vec<String>,
+    pub san_uri: Vec<String>,
+    pub san_email: Vec<String>,
+    pub san_ip: Vec<IpAddr>,
+    /// Lowercase hex without leading zeros.
+    pub serial: String,
+    /// SHA-256 of the DER certificate, lowercase hex.
+    pub fingerprint: String,
+    /// SHA-256 of the DER SubjectPublicKeyInfo, lowercase hex; stable across re-issues
+    /// with the same key.
+    pub spki_sha256: String,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    /// Policy rule that accepted the certificate; `None` when the policy default did.
+    pub rule: Option<String>,
+    /// Verified chain, leaf first; never empty.
+    pub chain: Vec<X509>,
+}
+
+/// Slot on each `Ssl` where the verify callback records the leaf's policy verdict.
+type VerdictSlot = Mutex<Option<Verdict>>;
+
+fn verdict_index() -> Index<Ssl, VerdictSlot> {
+    static INDEX: OnceLock<Index<Ssl, VerdictSlot>> = OnceLock::new();
+    *INDEX.get_or_init(|| Ssl::new_ex_index().expect("allocating SSL ex_data index"))
+}
+
+/// Prepare `ssl` so the verify callback can record the policy verdict on it.
+pub fn track_verdict(ssl: &mut SslRef) {
+    ssl.set_ex_data(verdict_index(), Mutex::new(None));
+}
+
+/// Called from the verify callback with the leaf's verdict.
+pub fn record_verdict(x509_ctx: &X509StoreContextRef, verdict: &Verdict) {
+    let slot = X509StoreContext::ssl_idx().ok()
+        .and_then(|idx| x509_ctx.ex_data(idx))
+        .and_then(|ssl| ssl.ex_data(verdict_index()));
+    if let Some(slot) = slot {
+        *slot.lock().unwrap() = Some(verdict.clone());
+    }
+}
+
+/// The verdict recorded on `ssl`, if the policy ran.
+pub fn verdict(ssl: &SslRef) -> Option<Verdict> {
+    ssl.ex_data(verdict_index()).and_then(|slot| slot.lock().unwrap().clone())
+}
+
+impl PeerIdentity {
+    /// Read the identity of the client from a completed handshake.
+    pub fn from_ssl(ssl: &SslRef) -> Result<PeerIdentity> {
+        let cert = ssl.peer_certificate().ok_or_else(|| anyhow!("no client certificate after handshake"))?;
+        let chain = ssl.verified_chain()
+            .map(|c| c.iter().map(|x| x.to_owned()).collect())
+            .filter(|c: &Vec<X509>| !c.is_empty())
+            .unwrap_or_else(|| vec![cert]);
+        PeerIdentity::from_chain(chain, verdict(ssl).and_then(|v| v.rule))
+    }
+
+    /// The identity of the leaf of `chain` (leaf first), accepted by `rule`.
+    pub(crate) fn from_chain(chain: Vec<X509>, rule: Option<String>) -> Result<PeerIdentity> {
+        let cert = chain.first().ok_or_else(|| anyhow!("empty certificate chain"))?.clone();
+        let sans: Vec<_> = cert.subject_alt_names().map(|s| s.into_iter().collect()).unwrap_or_default();
+        let spki = cert.public_key()?.public_key_to_der()?;
+        Ok(PeerIdentity {
+            subject: x509_name_to_string(cert.subject_name()),
+            issuer: x509_name_to_string(cert.issuer_name()),
+            san_dns: sans.iter().filter_map(|g| g.dnsname()).map(str::to_owned).collect(),
+            san_uri: sans.iter().filter_map(|g| g.uri()).map(str::to_owned).collect(),
+            san_email: sans.iter().filter_map(|g| g.email()).map(str::to_owned).collect(),
+            san_ip: sans.iter().filter_map(|g| g.ipaddress()).filter_map(ip_from_bytes).collect(),
+            serial: serial_hex(&cert),
+            fingerprint: hex::encode(cert.digest(MessageDigest::sha256())?),
+            spki_sha256: hex::encode(openssl::sha::sha256(&spki)),
+            not_before: system_time(cert.not_before()).context("certificate notBefore")?,
+            not_after: system_time(cert.not_after()).context("certificate notAfter")?,
+            rule,
+            chain,
+        })
+    }
+}
+
+impl fmt::Display for PeerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subject={}; issuer={}; serial={}; sha256={}; spki_sha256={}; valid {} .. {}",
+            self.subject, self.issuer, self.serial, self.fingerprint, self.spki_sha256,
+            asn1_display(self.not_before), asn1_display(self.not_after))?;
+        let sans: Vec<String> = self.san_dns.iter().map(|v| format!("DNS:{v}"))
+            .chain(self.san_uri.iter().map(|v| format!("URI:{v}")))
+            .chain(self.san_email.iter().map(|v| format!("email:{v}")))
+            .chain(self.san_ip.iter().map(|v| format!("IP:{v}")))
+            .collect();
+        if !sans.is_empty() {
+            write!(f, "; san={}", sans.join(","))?;
+        }
+        match &self.rule {
+            Some(rule) => write!(f, "; rule={rule:?}"),
+            None => write!(f, "; rule=(default)"),
+        }
+    }
+}
+
+/// OpenSSL's own notation (`Jan  1 00:00:00 2030 GMT`), as `openssl x509 -dates` prints.
+fn asn1_display(t: SystemTime) -> String {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    Asn1Time::from_unix(secs).map(|t| t.to_string()).unwrap_or_default()
+}
+
+fn system_time(t: &Asn1TimeRef) -> Result<SystemTime> {
+    let d = Asn1Time::from_unix(0)?.diff(t)?;
+    let secs = i64::from(d.days) * 86400 + i64::from(d.secs);
+    Ok(match u64::try_from(secs) {
+        Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+        Err(_) => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+    })
+}
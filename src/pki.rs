@@ -0,0 +1,491 @@
+//! `pki` subcommands: a server CA, a client CA and leaves signed by them, written in the
+//! layout `mk-mtls-certs.sh` produces and the server's defaults expect. Everything is
+//! done with the `openssl` crate, so the `openssl` binary isn't needed.
+//!
+//! ```text
+//! pki/ca_server/ca.{key,crt}    pki/ca_client/ca.{key,crt}
+//! pki/ca_*/intermediate.{key,crt}  with `init --intermediate`; then signs the leaves
+//! pki/server/server.{key,crt}   server-fullchain.crt, server.p12
+//! pki/client/client.{key,crt}   client-fullchain.crt, client.p12
+//! key.pem, cert.pem             server key and full chain
+//! client-ca.pem, cert/ca.crt    client CA, for verifying client certificates
+//! ```
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Subcommand, ValueEnum};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private};
+use openssl::rsa::Rsa;
+use openssl::stack::Stack;
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
+};
+use openssl::x509::{X509Builder, X509Name, X509NameRef, X509Ref, X509};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Organization and country of every subject, as in `mk-mtls-certs.sh`.
+const ORG: &str = "Example Org";
+const COUNTRY: &str = "US";
+
+#[derive(Debug, Subcommand)]
+pub enum PkiCommand {
+    /// Create the server and client CAs and install the client CA for the server
+    Init(InitArgs),
+    /// Issue the server certificate and install it as key.pem / cert.pem
+    IssueServer(ServerArgs),
+    /// Issue a client certificate with its full chain and a PKCS#12 bundle
+    IssueClient(ClientArgs),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyType {
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    EcP256,
+    EcP384,
+    Ed25519,
+}
+
+impl KeyType {
+    pub fn generate(self) -> Result<PKey<Private>> {
+        let ec = |nid| -> Result<PKey<Private>> {
+            let group = EcGroup::from_curve_name(nid)?;
+            Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+        };
+        Ok(match self {
+            KeyType::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?)?,
+            KeyType::Rsa3072 => PKey::from_rsa(Rsa::generate(3072)?)?,
+            KeyType::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?)?,
+            KeyType::EcP256 => ec(Nid::X9_62_PRIME256V1)?,
+            KeyType::EcP384 => ec(Nid::SECP384R1)?,
+            KeyType::Ed25519 => PKey::generate_ed25519()?,
+        })
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct InitArgs {
+    /// Directory holding `pki/` and the server's files
+    #[arg(long, default_value = ".")]
+    pub dir: PathBuf,
+
+    #[arg(long, value_enum, default_value = "rsa4096")]
+    pub key_type: KeyType,
+
+    /// CA validity in days
+    #[arg(long, default_value_t = 3650, value_parser = clap::value_parser!(u32).range(1..))]
+    pub days: u32,
+
+    /// Also create an intermediate CA under each root; leaves are then issued by the
+    /// intermediate and their chains include it
+    #[arg(long)]
+    pub intermediate: bool,
+
+    /// Replace existing CAs; every certificate they issued stops verifying
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Options shared by both kinds of leaf.
+#[derive(Debug, Args)]
+pub struct LeafArgs {
+    /// Directory holding `pki/` and the server's files
+    #[arg(long, default_value = ".")]
+    pub dir: PathBuf,
+
+    #[arg(long, value_enum, default_value = "rsa2048")]
+    pub key_type: KeyType,
+
+    /// Validity in days
+    #[arg(long, default_value_t = 825, value_parser = clap::value_parser!(u32).range(1..))]
+    pub days: u32,
+
+    /// URI subjectAltName (repeatable)
+    #[arg(long)]
+    pub uri: Vec<String>,
+
+    /// email subjectAltName (repeatable)
+    #[arg(long)]
+    pub email: Vec<String>,
+
+    /// Password for the PKCS#12 bundle
+    #[arg(long, env = "MTLS_PKI_P12_PASSWORD", hide_env_values = true, default_value = "changeit")]
+    pub p12_password: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ServerArgs {
+    /// Subject CN; also the first DNS subjectAltName
+    #[arg(long, default_value = "localhost")]
+    pub cn: String,
+
+    #[arg(long, default_value = "Infra")]
+    pub ou: String,
+
+    /// Additional DNS subjectAltName (repeatable)
+    #[arg(long, default_values_t = ["localhost".to_string()])]
+    pub dns: Vec<String>,
+
+    /// IP subjectAltName (repeatable)
+    #[arg(long, default_values_t = [IpAddr::from([127, 0, 0, 1])])]
+    pub ip: Vec<IpAddr>,
+
+    /// Base name of the files under pki/server/. Only the default `server` is also
+    /// installed as key.pem / cert.pem; use another (e.g. `server-ecdsa`) for a second
+    /// certificate to list under `extra_identities`
+    #[arg(long, default_value = "server")]
+    pub name: String,
+
+    #[command(flatten)]
+    pub leaf: LeafArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct ClientArgs {
+    #[arg(long, default_value = "client1")]
+    pub cn: String,
+
+    /// Subject OU; the default policy accepts `TrustedDevices`
+    #[arg(long, default_value = crate::TRUST_DEVICES)]
+    pub ou: String,
+
+    /// DNS subjectAltName (repeatable)
+    #[arg(long)]
+    pub dns: Vec<String>,
+
+    /// IP subjectAltName (repeatable)
+    #[arg(long)]
+    pub ip: Vec<IpAddr>,
+
+    #[command(flatten)]
+    pub leaf: LeafArgs,
+}
+
+pub fn run(command: PkiCommand) -> Result<()> {
+    match command {
+        PkiCommand::Init(args) => init(&args),
+        PkiCommand::IssueServer(args) => issue_server(&args),
+        PkiCommand::IssueClient(args) => issue_client(&args),
+    }
+}
+
+fn init(args: &InitArgs) -> Result<()> {
+    let dir = &args.dir;
+    for ca in ["ca_server", "ca_client"] {
+        let key = dir.join("pki").join(ca).join("ca.key");
+        if key.exists() && !args.force {
+            bail!("{} exists; pass --force to replace the CAs", key.display());
+        }
+    }
+
+    for (ca, side) in [("ca_server", "Server"), ("ca_client", "Client")] {
+        let ou = format!("{side} CA");
+        let cn = format!("{side} Root CA");
+        info!(cn, "generating CA");
+        let key = args.key_type.generate()?;
+        let cert = ca_cert(&key, &ou, &cn, args.days, None)?;
+        let ca_dir = dir.join("pki").join(ca);
+        write_file(&ca_dir.join("ca.key"), &key.private_key_to_pem_pkcs8()?, true)?;
+        write_file(&ca_dir.join("ca.crt"), &cert.to_pem()?, false)?;
+        if ca == "ca_client" {
+            // Where the server looks for the client CA by default. Clients send the
+            // intermediate in their chain, so the root is enough here either way.
+            write_file(&dir.join("client-ca.pem"), &cert.to_pem()?, false)?;
+            write_file(&dir.join("cert").join("ca.crt"), &cert.to_pem()?, false)?;
+        }
+
+        let (int_key, int_cert) = (ca_dir.join("intermediate.key"), ca_dir.join("intermediate.crt"));
+        if args.intermediate {
+            let cn = format!("{side} Intermediate CA");
+            info!(cn, "generating intermediate CA");
+            let int = args.key_type.generate()?;
+            let int_ca = ca_cert(&int, &ou, &cn, args.days, Some((&cert, &key)))?;
+            write_file(&int_key, &int.private_key_to_pem_pkcs8()?, true)?;
+            write_file(&int_cert, &int_ca.to_pem()?, false)?;
+        } else {
+            // Left over from an earlier `init --intermediate`; it no longer chains to the root.
+            for path in [int_key, int_cert] {
+                if path.exists() {
+                    std::fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn issue_server(args: &ServerArgs) -> Result<()> {
+    let dir = &args.leaf.dir;
+    let ca = load_ca(dir, "ca_server")?;
+
+    let mut dns = vec![args.cn.clone()];
+    dns.extend(args.dns.iter().filter(|d| **d != args.cn).cloned());
+    let sans = Sans { dns, ip: args.ip.clone(), uri: args.leaf.uri.clone(), email: args.leaf.email.clone() };
+
+    info!(cn = args.cn, "issuing server certificate");
+    let key = args.leaf.key_type.generate()?;
+    let cert = leaf_cert(&key, &args.ou, &args.cn, &sans, Usage::Server, args.leaf.days, (&ca.cert, &ca.key))?;
+    let fullchain = ca.fullchain(&cert)?;
+
+    let (out, name) = (dir.join("pki").join("server"), &args.name);
+    write_file(&out.join(format!("{name}.key")), &key.private_key_to_pem_pkcs8()?, true)?;
+    write_file(&out.join(format!("{name}.crt")), &cert.to_pem()?, false)?;
+    write_file(&out.join(format!("{name}-fullchain.crt")), &fullchain, false)?;
+    write_file(&out.join(format!("{name}.p12")), &pkcs12(name, &key, &cert, &ca.chain, &args.leaf.p12_password)?, true)?;
+    if name == "server" {
+        // Where the server looks for its identity by default.
+        write_file(&dir.join("key.pem"), &key.private_key_to_pem_pkcs8()?, true)?;
+        write_file(&dir.join("cert.pem"), &fullchain, false)?;
+    }
+    Ok(())
+}
+
+fn issue_client(args: &ClientArgs) -> Result<()> {
+    let dir = &args.leaf.dir;
+    let ca = load_ca(dir, "ca_client")?;
+    let sans = Sans { dns: args.dns.clone(), ip: args.ip.clone(), uri: args.leaf.uri.clone(), email: args.leaf.email.clone() };
+
+    info!(ou = args.ou, cn = args.cn, "issuing client certificate");
+    let key = args.leaf.key_type.generate()?;
+    let cert = leaf_cert(&key, &args.ou, &args.cn, &sans, Usage::Client, args.leaf.days, (&ca.cert, &ca.key))?;
+
+    let out = dir.join("pki").join("client");
+    write_file(&out.join("client.key"), &key.private_key_to_pem_pkcs8()?, true)?;
+    write_file(&out.join("client.crt"), &cert.to_pem()?, false)?;
+    write_file(&out.join("client-fullchain.crt"), &ca.fullchain(&cert)?, false)?;
+    write_file(&out.join("client.p12"), &pkcs12(&args.cn, &key, &cert, &ca.chain, &args.leaf.p12_password)?, true)?;
+    Ok(())
+}
+
+/// The CA that signs leaves on one side: the intermediate if there is one, else the root.
+struct Ca {
+    key: PKey<Private>,
+    cert: X509,
+    /// What follows a leaf in its chain: the signing CA, then up to the root.
+    chain: Vec<X509>,
+}
+
+impl Ca {
+    fn fullchain(&self, leaf: &X509Ref) -> Result<Vec<u8>> {
+        let mut pem = leaf.to_pem()?;
+        for cert in &self.chain {
+            pem.extend(cert.to_pem()?);
+        }
+        Ok(pem)
+    }
+}
+
+fn load_ca(dir: &Path, ca: &str) -> Result<Ca> {
+    let ca_dir = dir.join("pki").join(ca);
+    if !ca_dir.join("ca.key").exists() {
+        bail!("{} not found; run `pki init` first", ca_dir.join("ca.key").display());
+    }
+    let root = read_cert(&ca_dir.join("ca.crt"))?;
+    if ca_dir.join("intermediate.key").exists() {
+        let cert = read_cert(&ca_dir.join("intermediate.crt"))?;
+        let key = read_key(&ca_dir.join("intermediate.key"))?;
+        Ok(Ca { key, chain: vec![cert.clone(), root], cert })
+    } else {
+        let key = read_key(&ca_dir.join("ca.key"))?;
+        Ok(Ca { key, chain: vec![root.clone()], cert: root })
+    }
+}
+
+fn read_key(path: &Path) -> Result<PKey<Private>> {
+    let pem = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    PKey::private_key_from_pem(&pem).with_context(|| format!("loading {}", path.display()))
+}
+
+fn read_cert(path: &Path) -> Result<X509> {
+    let pem = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    X509::from_pem(&pem).with_context(|| format!("loading {}", path.display()))
+}
+
+fn subject(ou: &str, cn: &str) -> Result<X509Name> {
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COUNTRYNAME, COUNTRY)?;
+    name.append_entry_by_nid(Nid::ORGANIZATIONNAME, ORG)?;
+    name.append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, ou)?;
+    name.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+    Ok(name.build())
+}
+
+/// Subject, key, random serial and validity starting now.
+fn builder<T: HasPublic>(subject: &X509NameRef, key: &PKeyRef<T>, days: u32) -> Result<X509Builder> {
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(subject)?;
+    builder.set_pubkey(key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(days)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    Ok(builder)
+}
+
+/// Signature digest for `key`; Ed25519 signs without a separate digest.
+pub fn digest(key: &PKeyRef<Private>) -> MessageDigest {
+    match key.id() {
+        Id::ED25519 => MessageDigest::null(),
+        Id::EC if key.bits() > 256 => MessageDigest::sha384(),
+        _ => MessageDigest::sha256(),
+    }
+}
+
+/// A self-signed root, or with `issuer` an intermediate that may only issue leaves.
+pub(crate) fn ca_cert(key: &PKeyRef<Private>, ou: &str, cn: &str, days: u32, issuer: Option<(&X509Ref, &PKeyRef<Private>)>) -> Result<X509> {
+    let name = subject(ou, cn)?;
+    let mut builder = builder(&name, key, days)?;
+    let mut constraints = BasicConstraints::new();
+    constraints.critical().ca();
+    match issuer {
+        Some((issuer_cert, _)) => {
+            builder.set_issuer_name(issuer_cert.subject_name())?;
+            constraints.pathlen(0);
+        }
+        None => builder.set_issuer_name(&name)?,
+    }
+    builder.append_extension(constraints.build()?)?;
+    builder.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+    let ski = SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(ski)?;
+    match issuer {
+        Some((issuer_cert, issuer_key)) => {
+            let aki = AuthorityKeyIdentifier::new().keyid(true).build(&builder.x509v3_context(Some(issuer_cert), None))?;
+            builder.append_extension(aki)?;
+            builder.sign(issuer_key, digest(issuer_key))?;
+        }
+        None => builder.sign(key, digest(key))?,
+    }
+    Ok(builder.build())
+}
+
+/// Subject alternative names of a leaf; none at all when every list is empty.
+#[derive(Debug, Default)]
+pub struct Sans {
+    pub dns: Vec<String>,
+    pub ip: Vec<IpAddr>,
+    pub uri: Vec<String>,
+    pub email: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Usage {
+    Server,
+    Client,
+}
+
+/// A leaf for `key` signed by the `(certificate, key)` of a CA.
+fn leaf_cert(
+    key: &PKeyRef<Private>,
+    ou: &str,
+    cn: &str,
+    sans: &Sans,
+    usage: Usage,
+    days: u32,
+    ca: (&X509Ref, &PKeyRef<Private>),
+) -> Result<X509> {
+    let name = subject(ou, cn)?;
+    sign_leaf(&name, key, sans, usage, days, ca)
+}
+
+/// A leaf with `subject` for the public half of `key`, signed by the `(certificate, key)`
+/// of a CA. Used for CSRs as well, where only the public key is known.
+pub fn sign_leaf<T: HasPublic>(
+    subject: &X509NameRef,
+    key: &PKeyRef<T>,
+    sans: &Sans,
+    usage: Usage,
+    days: u32,
+    (ca_cert, ca_key): (&X509Ref, &PKeyRef<Private>),
+) -> Result<X509> {
+    let mut builder = builder(subject, key, days)?;
+    builder.set_issuer_name(ca_cert.subject_name())?;
+    builder.append_extension(BasicConstraints::new().build()?)?;
+    let mut key_usage = KeyUsage::new();
+    key_usage.critical().digital_signature();
+    // Key encipherment only means something for RSA key transport.
+    if key.id() == Id::RSA {
+        key_usage.key_encipherment();
+    }
+    builder.append_extension(key_usage.build()?)?;
+    let eku = match usage {
+        Usage::Server => ExtendedKeyUsage::new().server_auth().build()?,
+        Usage::Client => ExtendedKeyUsage::new().client_auth().build()?,
+    };
+    builder.append_extension(eku)?;
+
+    if !(sans.dns.is_empty() && sans.ip.is_empty() && sans.uri.is_empty() && sans.email.is_empty()) {
+        let mut san = SubjectAlternativeName::new();
+        for dns in &sans.dns {
+            san.dns(dns);
+        }
+        for ip in &sans.ip {
+            san.ip(&ip.to_string());
+        }
+        for uri in &sans.uri {
+            san.uri(uri);
+        }
+        for email in &sans.email {
+            san.email(email);
+        }
+        let san = san.build(&builder.x509v3_context(Some(ca_cert), None))?;
+        builder.append_extension(san)?;
+    }
+    let ski = SubjectKeyIdentifier::new().build(&builder.x509v3_context(Some(ca_cert), None))?;
+    builder.append_extension(ski)?;
+    let aki = AuthorityKeyIdentifier::new().keyid(true).build(&builder.x509v3_context(Some(ca_cert), None))?;
+    builder.append_extension(aki)?;
+
+    builder.sign(ca_key, digest(ca_key))?;
+    Ok(builder.build())
+}
+
+/// PKCS#12 bundle of `key`, `cert` and `chain`, named `name`.
+pub fn pkcs12(name: &str, key: &PKeyRef<Private>, cert: &X509Ref, chain: &[X509], password: &str) -> Result<Vec<u8>> {
+    let mut cas = Stack::new()?;
+    for ca in chain {
+        cas.push(ca.clone())?;
+    }
+    let p12 = Pkcs12::builder().name(name).pkey(key).cert(cert).ca(cas).build2(password)?;
+    Ok(p12.to_der()?)
+}
+
+/// Write `data` to `path`, creating parent directories; `secret` files are owner-only.
+fn write_file(path: &Path, data: &[u8], secret: bool) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    let mut file = options.open(path).with_context(|| format!("writing {}", path.display()))?;
+    // The mode above only applies to new files.
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::io::Write::write_all(&mut file, data).with_context(|| format!("writing {}", path.display()))?;
+    info!(path = %path.display(), "wrote");
+    Ok(())
+}
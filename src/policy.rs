@@ -0,0 +1,703 @@
+//! Declarative client-certificate policy.
+//!
+//! A policy is an ordered list of named rules. The first rule whose `match` condition holds
+//! for the client leaf decides the verdict; if none match, `default` applies.
+//!
+//! ```toml
+//! [policy]
+//! default = "reject"
+//!
+//! [[policy.rules]]
+//! name = "devices"
+//! verdict = "accept"
+//! match = { all = [
+//!     { subject = { ou = "TrustedDevices", o = "Example Org" } },
+//!     { eku = ["client_auth"] },
+//!     { not = { serial = ["0badc0de"] } },
+//! ] }
+//! ```
+
+use anyhow::{bail, Result};
+use foreign_types::ForeignTypeRef;
+use openssl::asn1::Asn1Time;
+use openssl::nid::Nid;
+use openssl::pkey::Id;
+use openssl::x509::{X509NameRef, X509Ref};
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::TRUST_DEVICES;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Verdict when no rule matches.
+    pub default: Action,
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Policy {
+    /// The historical behaviour: accept leaves carrying `OU=TrustedDevices`, reject the rest.
+    fn default() -> Self {
+        Policy::required_ou(TRUST_DEVICES)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub verdict: Action,
+    #[serde(rename = "match")]
+    pub condition: Condition,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Accept,
+    #[default]
+    Reject,
+}
+
+/// A single test against the client leaf. Written in TOML as a one-key table,
+/// e.g. `{ san_dns = "*.devices.example.com" }` or `{ any = [ ... ] }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Condition {
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+    /// Every given subject component must have an entry matching the pattern.
+    Subject(NameMatch),
+    Issuer(NameMatch),
+    /// Some SAN of the given type matches the pattern.
+    SanDns(Pattern),
+    SanUri(Pattern),
+    SanEmail(Pattern),
+    /// Some IP SAN falls inside the network (`10.0.0.0/8`, `::1`).
+    SanIp(IpNet),
+    /// Serial number (hex, case and leading zeros ignored) is one of these.
+    Serial(Vec<String>),
+    /// The certificate allows every listed extended key usage.
+    Eku(Vec<Eku>),
+    /// The public key is one of these types.
+    KeyType(Vec<KeyType>),
+    /// The public key has at least this many bits.
+    MinKeyBits(u32),
+    Validity(ValidityWindow),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NameMatch {
+    pub cn: Option<Pattern>,
+    pub o: Option<Pattern>,
+    pub ou: Option<Pattern>,
+    pub c: Option<Pattern>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidityWindow {
+    /// notAfter - notBefore must not exceed this many days.
+    pub max_lifetime_days: Option<u32>,
+    /// At least this many days must remain before notAfter.
+    pub min_remaining_days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eku {
+    ServerAuth,
+    ClientAuth,
+    EmailProtection,
+    CodeSigning,
+    OcspSigning,
+    TimeStamping,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyType {
+    Rsa,
+    Ec,
+    Ed25519,
+    Ed448,
+}
+
+/// Glob-style string pattern; `*` matches any run of characters, everything else is literal.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "String")]
+pub struct Pattern(String);
+
+impl From<String> for Pattern {
+    fn from(s: String) -> Self {
+        Pattern(s)
+    }
+}
+
+impl Pattern {
+    pub fn matches(&self, value: &str) -> bool {
+        glob_match(self.0.as_bytes(), value.as_bytes())
+    }
+}
+
+/// Iterative wildcard match, O(pattern × value): on a mismatch only the latest `*` is
+/// retried, one byte further on, since earlier stars can't do better than it.
+fn glob_match(pat: &[u8], val: &[u8]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    // The latest `*` seen and the value position it currently stretches to.
+    let mut star = None;
+    while v < val.len() {
+        if p < pat.len() && pat[p] == b'*' {
+            star = Some((p, v));
+            p += 1;
+        } else if p < pat.len() && pat[p] == val[v] {
+            p += 1;
+            v += 1;
+        } else if let Some((sp, sv)) = star {
+            star = Some((sp, sv + 1));
+            p = sp + 1;
+            v = sv + 1;
+        } else {
+            return false;
+        }
+    }
+    pat[p..].iter().all(|&c| c == b'*')
+}
+
+/// An IP network in CIDR notation; a bare address is a /32 or /128.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s.as_str(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("invalid IP network {s:?}: {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {s:?}"))?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let full = (prefix / 8) as usize;
+    let rem = prefix % 8;
+    if a[..full] != b[..full] {
+        return false;
+    }
+    rem == 0 || (a[full] ^ b[full]) & (0xffu8 << (8 - rem)) == 0
+}
+
+/// Outcome of evaluating a policy against a leaf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub action: Action,
+    /// Name of the rule that decided, or `None` when the default applied.
+    pub rule: Option<String>,
+}
+
+impl Verdict {
+    pub fn accepted(&self) -> bool {
+        self.action == Action::Accept
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Accept => "accept",
+            Action::Reject => "reject",
+        };
+        match &self.rule {
+            Some(rule) => write!(f, "{action} (rule {rule:?})"),
+            None => write!(f, "{action} (default)"),
+        }
+    }
+}
+
+impl Policy {
+    /// Single-rule policy equivalent to the old fixed OU check.
+    pub fn required_ou(ou: &str) -> Policy {
+        Policy {
+            default: Action::Reject,
+            rules: vec![Rule {
+                name: "required-ou".to_string(),
+                verdict: Action::Accept,
+                condition: Condition::Subject(NameMatch {
+                    ou: Some(Pattern(ou.to_string())),
+                    ..NameMatch::default()
+                }),
+            }],
+        }
+    }
+
+    /// `key` is the config path of this policy, used to prefix errors.
+    pub fn validate(&self, key: &str) -> Result<()> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.name.is_empty() {
+                bail!("{key}.rules[{i}].name: must not be empty");
+            }
+            if self.rules[..i].iter().any(|r| r.name == rule.name) {
+                bail!("{key}.rules[{i}].name: duplicate rule name {:?}", rule.name);
+            }
+            rule.condition.validate(&format!("{key}.rules[{i}].match"))?;
+        }
+        Ok(())
+    }
+
+    pub fn evaluate(&self, leaf: &X509Ref) -> Verdict {
+        for rule in &self.rules {
+            if rule.condition.matches(leaf) {
+                return Verdict { action: rule.verdict, rule: Some(rule.name.clone()) };
+            }
+        }
+        Verdict { action: self.default, rule: None }
+    }
+}
+
+impl Condition {
+    fn validate(&self, key: &str) -> Result<()> {
+        match self {
+            Condition::All(cs) | Condition::Any(cs) => {
+                for (i, c) in cs.iter().enumerate() {
+                    c.validate(&format!("{key}[{i}]"))?;
+                }
+            }
+            Condition::Not(c) => c.validate(key)?,
+            Condition::Subject(n) | Condition::Issuer(n)
+                if n.cn.is_none() && n.o.is_none() && n.ou.is_none() && n.c.is_none() =>
+            {
+                bail!("{key}: name match needs at least one of cn, o, ou, c");
+            }
+            Condition::Serial(serials) => {
+                for s in serials {
+                    if s.is_empty() || !s.chars().all(|c| c.is_ascii_hexdigit() || c == ':') {
+                        bail!("{key}: serial {s:?} is not hex");
+                    }
+                }
+            }
+            Condition::Eku(list) if list.is_empty() => bail!("{key}: eku list is empty"),
+            Condition::KeyType(list) if list.is_empty() => bail!("{key}: key_type list is empty"),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, cert: &X509Ref) -> bool {
+        match self {
+            Condition::All(cs) => cs.iter().all(|c| c.matches(cert)),
+            Condition::Any(cs) => cs.iter().any(|c| c.matches(cert)),
+            Condition::Not(c) => !c.matches(cert),
+            Condition::Subject(n) => n.matches(cert.subject_name()),
+            Condition::Issuer(n) => n.matches(cert.issuer_name()),
+            Condition::SanDns(p) => sans(cert).iter().filter_map(|g| g.dnsname()).any(|v| p.matches(v)),
+            Condition::SanUri(p) => sans(cert).iter().filter_map(|g| g.uri()).any(|v| p.matches(v)),
+            Condition::SanEmail(p) => sans(cert).iter().filter_map(|g| g.email()).any(|v| p.matches(v)),
+            Condition::SanIp(net) => sans(cert).iter()
+                .filter_map(|g| g.ipaddress())
+                .filter_map(ip_from_bytes)
+                .any(|ip| net.contains(ip)),
+            Condition::Serial(list) => {
+                let serial = serial_hex(cert);
+                list.iter().any(|s| normalize_hex(s) == serial)
+            }
+            Condition::Eku(list) => {
+                let have = extended_key_usage(cert);
+                list.iter().all(|e| have & e.flag() != 0)
+            }
+            Condition::KeyType(list) => cert.public_key()
+                .map(|k| list.iter().any(|t| t.id() == k.id()))
+                .unwrap_or(false),
+            Condition::MinKeyBits(bits) => cert.public_key()
+                .map(|k| k.bits() >= *bits)
+                .unwrap_or(false),
+            Condition::Validity(w) => w.matches(cert),
+        }
+    }
+}
+
+impl NameMatch {
+    fn matches(&self, name: &X509NameRef) -> bool {
+        [
+            (Nid::COMMONNAME, &self.cn),
+            (Nid::ORGANIZATIONNAME, &self.o),
+            (Nid::ORGANIZATIONALUNITNAME, &self.ou),
+            (Nid::COUNTRYNAME, &self.c),
+        ]
+        .into_iter()
+        .all(|(nid, pat)| match pat {
+            None => true,
+            Some(p) => name.entries_by_nid(nid)
+                .filter_map(|e| e.data().as_utf8().ok())
+                .any(|v| p.matches(&v)),
+        })
+    }
+}
+
+impl ValidityWindow {
+    fn matches(&self, cert: &X509Ref) -> bool {
+        if let Some(max) = self.max_lifetime_days {
+            match cert.not_before().diff(cert.not_after()) {
+                Ok(d) if d.days < max as i32 || (d.days == max as i32 && d.secs == 0) => {}
+                _ => return false,
+            }
+        }
+        if let Some(min) = self.min_remaining_days {
+            let Ok(now) = Asn1Time::days_from_now(0) else { return false };
+            match now.diff(cert.not_after()) {
+                Ok(d) if d.days >= min as i32 => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Eku {
+    fn flag(self) -> u32 {
+        match self {
+            Eku::ServerAuth => openssl_sys::XKU_SSL_SERVER,
+            Eku::ClientAuth => openssl_sys::XKU_SSL_CLIENT,
+            Eku::EmailProtection => openssl_sys::XKU_SMIME,
+            Eku::CodeSigning => openssl_sys::XKU_CODE_SIGN,
+            Eku::OcspSigning => openssl_sys::XKU_OCSP_SIGN,
+            Eku::TimeStamping => openssl_sys::XKU_TIMESTAMP,
+        }
+    }
+}
+
+impl KeyType {
+    fn id(self) -> Id {
+        match self {
+            KeyType::Rsa => Id::RSA,
+            KeyType::Ec => Id::EC,
+            KeyType::Ed25519 => Id::ED25519,
+            KeyType::Ed448 => Id::ED448,
+        }
+    }
+}
+
+/// EKU bitmask (`XKU_*`). A certificate without the extension is unrestricted and
+/// reports every bit set, matching OpenSSL's own purpose checks.
+pub fn extended_key_usage(cert: &X509Ref) -> u32 {
+    // SAFETY: `cert` is a valid X509; the call only caches decoded extensions on it.
+    unsafe { openssl_sys::X509_get_extended_key_usage(cert.as_ptr()) }
+}
+
+fn sans(cert: &X509Ref) -> Vec<openssl::x509::GeneralName> {
+    cert.subject_alt_names().map(|s| s.into_iter().collect()).unwrap_or_default()
+}
+
+pub fn ip_from_bytes(b: &[u8]) -> Option<IpAddr> {
+    match b.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(b).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(b).ok()?)),
+        _ => None,
+    }
+}
+
+/// Serial number as lowercase hex without leading zeros.
+pub fn serial_hex(cert: &X509Ref) -> String {
+    cert.serial_number().to_bn()
+        .and_then(|bn| bn.to_hex_str().map(|s| normalize_hex(&s)))
+        .unwrap_or_default()
+}
+
+fn normalize_hex(s: &str) -> String {
+    let s: String = s.chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase();
+    let trimmed = s.trim_start_matches('0');
+    if trimmed.is_empty() { "0".to_string() } else { trimmed.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Integer;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const DAY: i64 = 86400;
+
+    /// Self-signed EC leaf with `OU`/`CN`, serial `serial` (hex), valid from `start` to
+    /// `end` seconds relative to now.
+    fn leaf(ou: &str, cn: &str, serial: &str, start: i64, end: i64) -> X509 {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("O", "Example Org").unwrap();
+        name.append_entry_by_text("OU", ou).unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = Asn1Integer::from_bn(&BigNum::from_hex_str(serial).unwrap()).unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::from_unix(now + start).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::from_unix(now + end).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        cert.build()
+    }
+
+    fn condition(toml: &str) -> Condition {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            #[serde(rename = "match")]
+            condition: Condition,
+        }
+        toml::from_str::<Wrapper>(&format!("match = {toml}")).unwrap().condition
+    }
+
+    #[test]
+    fn glob_patterns() {
+        for (pat, val, want) in [
+            ("", "", true),
+            ("", "a", false),
+            ("abc", "abc", true),
+            ("abc", "abd", false),
+            ("abc", "ab", false),
+            ("abc", "abcd", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("*.example.com", "a.example.com", true),
+            ("*.example.com", "a.b.example.com", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", "a.example.com.evil", false),
+            ("dev-*-prod", "dev-7-prod", true),
+            ("dev-*-prod", "dev--prod", true),
+            ("dev-*-prod", "dev-7-staging", false),
+            ("a*b*c", "aXbYc", true),
+            ("a*b*c", "acb", false),
+            ("**", "x", true),
+            ("?", "x", false),
+            ("Trusted*", "trustedDevices", false),
+        ] {
+            assert_eq!(Pattern::from(pat.to_string()).matches(val), want, "{pat:?} vs {val:?}");
+        }
+    }
+
+    #[test]
+    fn many_stars_match_in_linear_passes() {
+        // Exponential for a backtracking matcher: every star retries every split.
+        let pat = Pattern::from(format!("{}b", "a*".repeat(30)));
+        assert!(!pat.matches(&"a".repeat(100)));
+        assert!(pat.matches(&format!("{}b", "a".repeat(100))));
+    }
+
+    #[test]
+    fn ip_networks() {
+        for (net, ip, want) in [
+            ("10.0.0.0/8", "10.255.1.2", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("192.168.1.0/24", "192.168.1.255", true),
+            ("192.168.1.0/24", "192.168.2.0", false),
+            ("192.168.1.128/25", "192.168.1.200", true),
+            ("192.168.1.128/25", "192.168.1.127", false),
+            ("172.16.0.0/12", "172.31.255.255", true),
+            ("172.16.0.0/12", "172.32.0.0", false),
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("127.0.0.1", "127.0.0.1", true),
+            ("127.0.0.1", "127.0.0.2", false),
+            ("127.0.0.1/32", "127.0.0.1", true),
+            ("::1", "::1", true),
+            ("::1", "::2", false),
+            ("2001:db8::/32", "2001:db8:ffff::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("fe80::/10", "febf::1", true),
+            ("fe80::/10", "fec0::1", false),
+            ("::/0", "2001:db8::1", true),
+            ("::/0", "10.0.0.1", false),
+            ("0.0.0.0/0", "::ffff:10.0.0.1", false),
+        ] {
+            let parsed = IpNet::try_from(net.to_string()).unwrap();
+            assert_eq!(parsed.contains(ip.parse().unwrap()), want, "{net} contains {ip}");
+        }
+    }
+
+    #[test]
+    fn invalid_ip_networks_are_refused() {
+        for net in ["", "10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0/8", "host/8"] {
+            assert!(IpNet::try_from(net.to_string()).is_err(), "{net:?}");
+        }
+    }
+
+    #[test]
+    fn prefix_comparison() {
+        for (a, b, prefix, want) in [
+            ([0b1010_1010, 0], [0b1010_1010, 0xff], 8, true),
+            ([0b1010_1010, 0], [0b1010_1011, 0], 8, false),
+            ([0b1010_1010, 0], [0b1010_1011, 0], 7, true),
+            ([0b1010_1010, 0], [0b1011_1010, 0], 3, true),
+            ([0b1010_1010, 0], [0b1011_1010, 0], 4, false),
+            ([0xff, 0b1000_0000], [0xff, 0b1111_1111], 9, true),
+            ([0xff, 0b1000_0000], [0xff, 0b0111_1111], 9, false),
+            ([0xff, 0xff], [0xff, 0xfe], 16, false),
+            ([0xff, 0xff], [0x00, 0x00], 0, true),
+        ] {
+            assert_eq!(prefix_eq(&a, &b, prefix), want, "{a:?} {b:?} /{prefix}");
+        }
+    }
+
+    #[test]
+    fn serials_are_normalized() {
+        for (input, want) in [
+            ("0BADC0DE", "badc0de"),
+            ("00:0b:ad:c0:de", "badc0de"),
+            ("badc0de", "badc0de"),
+            ("0", "0"),
+            ("0000", "0"),
+            ("00:00", "0"),
+            ("10", "10"),
+            ("A0:00", "a000"),
+        ] {
+            assert_eq!(normalize_hex(input), want, "{input:?}");
+        }
+
+        let cert = leaf("TrustedDevices", "a", "0BADC0DE", -DAY, DAY);
+        assert_eq!(serial_hex(&cert), "badc0de");
+        for (listed, want) in [
+            ("badc0de", true),
+            ("0BADC0DE", true),
+            ("0b:ad:c0:de", true),
+            ("00:0b:ad:c0:de", true),
+            ("badc0df", false),
+            ("badc0de0", false),
+        ] {
+            assert_eq!(Condition::Serial(vec![listed.into()]).matches(&cert), want, "{listed:?}");
+        }
+    }
+
+    #[test]
+    fn validity_windows() {
+        for (start, end, max_lifetime, min_remaining, want) in [
+            (-DAY, 30 * DAY, Some(31), None, true),
+            (-DAY, 30 * DAY, Some(30), None, false),
+            // Exactly the maximum lifetime is allowed, a second more is not.
+            (-DAY, 29 * DAY, Some(30), None, true),
+            (-DAY, 29 * DAY + 1, Some(30), None, false),
+            (-DAY, 30 * DAY, None, Some(29), true),
+            (-DAY, 30 * DAY, None, Some(31), false),
+            (-DAY, DAY / 2, None, Some(1), false),
+            (-DAY, DAY / 2, None, Some(0), true),
+            (-2 * DAY, -DAY, None, Some(0), false),
+            (-DAY, 30 * DAY, Some(31), Some(29), true),
+            (-DAY, 30 * DAY, Some(31), Some(31), false),
+            (-DAY, 30 * DAY, Some(30), Some(29), false),
+            (-DAY, 400 * DAY, None, None, true),
+        ] {
+            let window = ValidityWindow { max_lifetime_days: max_lifetime, min_remaining_days: min_remaining };
+            let cert = leaf("TrustedDevices", "a", "1", start, end);
+            assert_eq!(
+                window.matches(&cert), want,
+                "{start}..{end} max_lifetime={max_lifetime:?} min_remaining={min_remaining:?}",
+            );
+        }
+    }
+
+    #[test]
+    fn all_any_and_not() {
+        let cert = leaf("TrustedDevices", "sensor-7", "0badc0de", -DAY, 30 * DAY);
+        for (toml, want) in [
+            (r#"{ subject = { ou = "TrustedDevices" } }"#, true),
+            (r#"{ subject = { ou = "Other" } }"#, false),
+            (r#"{ all = [] }"#, true),
+            (r#"{ any = [] }"#, false),
+            (r#"{ all = [{ subject = { cn = "sensor-*" } }, { serial = ["badc0de"] }] }"#, true),
+            (r#"{ all = [{ subject = { cn = "sensor-*" } }, { serial = ["1"] }] }"#, false),
+            (r#"{ any = [{ subject = { cn = "camera-*" } }, { serial = ["badc0de"] }] }"#, true),
+            (r#"{ any = [{ subject = { cn = "camera-*" } }, { serial = ["1"] }] }"#, false),
+            (r#"{ not = { serial = ["badc0de"] } }"#, false),
+            (r#"{ not = { serial = ["1"] } }"#, true),
+            (r#"{ not = { not = { serial = ["badc0de"] } } }"#, true),
+            (r#"{ not = { any = [] } }"#, true),
+            (r#"{ not = { all = [] } }"#, false),
+            (
+                r#"{ all = [
+                    { subject = { ou = "TrustedDevices", o = "Example Org" } },
+                    { any = [{ subject = { cn = "camera-*" } }, { subject = { cn = "sensor-*" } }] },
+                    { not = { serial = ["0badc0de"] } },
+                ] }"#,
+                false,
+            ),
+            (
+                r#"{ all = [
+                    { subject = { ou = "TrustedDevices", o = "Example Org" } },
+                    { any = [{ subject = { cn = "camera-*" } }, { subject = { cn = "sensor-*" } }] },
+                    { not = { serial = ["dead"] } },
+                ] }"#,
+                true,
+            ),
+            (r#"{ any = [{ not = { subject = { ou = "TrustedDevices" } } }, { key_type = ["rsa"] }] }"#, false),
+            (r#"{ all = [{ key_type = ["ec"] }, { min_key_bits = 256 }, { not = { min_key_bits = 384 } }] }"#, true),
+        ] {
+            assert_eq!(condition(toml).matches(&cert), want, "{toml}");
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy: Policy = toml::from_str(
+            r#"
+            default = "reject"
+
+            [[rules]]
+            name = "blocked"
+            verdict = "reject"
+            match = { serial = ["dead"] }
+
+            [[rules]]
+            name = "devices"
+            verdict = "accept"
+            match = { subject = { ou = "TrustedDevices" } }
+            "#,
+        )
+        .unwrap();
+        policy.validate("policy").unwrap();
+        for (ou, serial, action, rule) in [
+            ("TrustedDevices", "1", Action::Accept, Some("devices")),
+            ("TrustedDevices", "dead", Action::Reject, Some("blocked")),
+            ("Other", "dead", Action::Reject, Some("blocked")),
+            ("Other", "1", Action::Reject, None),
+        ] {
+            let verdict = policy.evaluate(&leaf(ou, "a", serial, -DAY, DAY));
+            assert_eq!(verdict, Verdict { action, rule: rule.map(str::to_string) }, "{ou} {serial}");
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! Reverse proxy mode: forward each authenticated request to a plaintext upstream with
+//! the client's identity in request headers.
+//!
+//! Client-supplied copies of the identity headers are always removed first, so the
+//! upstream can trust them, and a request with control characters in its line or headers
+//! is refused rather than passed on. Every request uses a fresh upstream connection.
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::http::{read_response, Handler, Request, Response};
+use crate::peer::PeerIdentity;
+use crate::upstream::Upstream;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// `host:port` or `unix:/path/to.sock`; required when `server.mode = "proxy"`.
+    pub upstream: Option<Upstream>,
+    pub connect_timeout_secs: u64,
+    /// Time allowed, after connecting, for the upstream to take the request and send its
+    /// complete response; 504 when exceeded.
+    pub timeout_secs: u64,
+    /// Larger upstream responses are answered with 502.
+    pub max_response_bytes: usize,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig { upstream: None, connect_timeout_secs: 5, timeout_secs: 60, max_response_bytes: 16 * 1024 * 1024 }
+    }
+}
+
+impl ProxyConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.connect_timeout_secs == 0 {
+            bail!("proxy.connect_timeout_secs: must be at least 1");
+        }
+        if self.timeout_secs == 0 {
+            bail!("proxy.timeout_secs: must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Subject DN of the client leaf, as in the log lines.
+pub const SUBJECT_HEADER: &str = "X-Client-Subject-DN";
+/// SHA-256 fingerprint of the client leaf, lowercase hex.
+pub const FINGERPRINT_HEADER: &str = "X-Client-Cert-Fingerprint";
+/// URL-encoded PEM of the client leaf (like nginx's `$ssl_client_escaped_cert`).
+pub const CERT_HEADER: &str = "X-Client-Cert";
+/// Envoy's `x-forwarded-client-cert`: `Hash=..;Cert="..";Subject="..";URI=..;DNS=..`.
+pub const XFCC_HEADER: &str = "X-Forwarded-Client-Cert";
+
+const IDENTITY_HEADERS: [&str; 4] = [SUBJECT_HEADER, FINGERPRINT_HEADER, CERT_HEADER, XFCC_HEADER];
+
+/// Hop-by-hop headers, never forwarded in either direction.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade",
+    "content-length", "expect",
+];
+
+pub struct Proxy {
+    upstream: Upstream,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_response: usize,
+    max_header: usize,
+}
+
+impl Proxy {
+    /// `None` unless an upstream is configured.
+    pub fn new(config: &ProxyConfig, max_header: usize) -> Option<Proxy> {
+        Some(Proxy {
+            upstream: config.upstream.clone()?,
+            connect_timeout: Duration::from_secs(config.connect_timeout_secs),
+            timeout: Duration::from_secs(config.timeout_secs),
+            max_response: config.max_response_bytes,
+            max_header,
+        })
+    }
+
+    async fn forward(&self, req: Request, head: Vec<u8>, deadline: Instant) -> Result<Response> {
+        let mut upstream = self.upstream.connect(self.connect_timeout).await?;
+
+        let send = async {
+            upstream.write_all(&head).await?;
+            upstream.write_all(&req.body).await?;
+            upstream.flush().await
+        };
+        tokio::time::timeout_at(deadline, send).await
+            .map_err(|_| anyhow!("timed out sending the request"))??;
+
+        let mut resp = read_response(upstream, req.method == "HEAD", self.max_header, self.max_response, deadline).await?;
+        resp.headers.retain(|(k, _)| !HOP_BY_HOP.iter().any(|h| k.eq_ignore_ascii_case(h)));
+        Ok(resp)
+    }
+}
+
+impl Handler for Proxy {
+    async fn handle(&self, req: Request) -> Response {
+        let (method, target) = (req.method.clone(), req.target.clone());
+        if let Err(e) = check_request(&req) {
+            warn!(method, path = target, "proxy: refusing to forward: {e:#}");
+            return Response::text(400, "Bad Request\n");
+        }
+        let head = match request_head(&req) {
+            Ok(head) => head,
+            Err(e) => {
+                warn!(method, path = target, "proxy: refusing to forward: {e:#}");
+                return Response::text(403, "Forbidden\n");
+            }
+        };
+        let deadline = Instant::now() + self.connect_timeout + self.timeout;
+        match self.forward(req, head, deadline).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!(method, path = target, upstream = %self.upstream, "proxy: {e:#}");
+                if Instant::now() >= deadline {
+                    Response::text(504, "Gateway Timeout\n")
+                } else {
+                    Response::text(502, "Bad Gateway\n")
+                }
+            }
+        }
+    }
+}
+
+/// The request line and headers are copied to the upstream as they are, so they must not
+/// be able to end a line there: no control characters (HTAB aside, in values) and no
+/// spaces in the method or target. The HTTP/1.1 parser refuses these already; HTTP/2
+/// requests and any future front end get the same check here.
+fn check_request(req: &Request) -> Result<()> {
+    let bad = |s: &str| s.is_empty() || s.bytes().any(|b| b.is_ascii_control() || b == b' ');
+    if bad(&req.method) {
+        bail!("method {:?} is not a token", req.method);
+    }
+    if bad(&req.target) {
+        bail!("request target {:?} has spaces or control characters", req.target);
+    }
+    for (name, value) in &req.headers {
+        if bad(name) || name.contains(':') {
+            bail!("header name {name:?} is not a token");
+        }
+        if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+            bail!("header {name} has control characters in its value");
+        }
+    }
+    Ok(())
+}
+
+/// Request line and headers for the upstream: hop-by-hop and client-supplied identity
+/// headers removed, our identity headers added, and the body re-framed by length. Fails
+/// if the client certificate can't be put into headers safely.
+fn request_head(req: &Request) -> Result<Vec<u8>> {
+    // Headers named in Connection are hop-by-hop too.
+    let listed: Vec<String> = req.headers.iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, v)| v.split(',').map(|t| t.trim().to_ascii_lowercase()))
+        .collect();
+
+    let mut out = format!("{} {} HTTP/1.1\r\n", req.method, req.target);
+    for (name, value) in &req.headers {
+        let drop = HOP_BY_HOP.iter().chain(IDENTITY_HEADERS.iter()).any(|h| name.eq_ignore_ascii_case(h))
+            || listed.iter().any(|h| name.eq_ignore_ascii_case(h));
+        if !drop {
+            out.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    if let Some(peer) = &req.peer {
+        for (name, value) in identity_headers(peer)? {
+            out.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    if !req.body.is_empty() || !matches!(req.method.as_str(), "GET" | "HEAD" | "DELETE" | "OPTIONS") {
+        out.push_str(&format!("Content-Length: {}\r\n", req.body.len()));
+    }
+    out.push_str("Connection: close\r\n\r\n");
+    Ok(out.into_bytes())
+}
+
+/// The identity headers for `peer`. Certificate fields are attacker-chosen text, so a
+/// control character anywhere (a CR/LF would start a forged header) refuses the request.
+fn identity_headers(peer: &PeerIdentity) -> Result<[(&'static str, String); 4]> {
+    let pem = url_encode(&peer.chain[0].to_pem()?);
+
+    let mut xfcc = format!("Hash={};Cert=\"{pem}\";Subject=\"{}\"", peer.fingerprint, quote(&rfc2253(peer)));
+    for uri in &peer.san_uri {
+        xfcc.push_str(&format!(";URI={}", xfcc_value(uri)));
+    }
+    for dns in &peer.san_dns {
+        xfcc.push_str(&format!(";DNS={}", xfcc_value(dns)));
+    }
+
+    let headers = [
+        (SUBJECT_HEADER, peer.subject.clone()),
+        (FINGERPRINT_HEADER, peer.fingerprint.clone()),
+        (CERT_HEADER, pem),
+        (XFCC_HEADER, xfcc),
+    ];
+    if let Some((name, _)) = headers.iter().find(|(_, v)| v.chars().any(char::is_control)) {
+        bail!("the client certificate puts control characters into {name}");
+    }
+    Ok(headers)
+}
+
+/// Subject in RFC 2253 form (`CN=a,OU=b,O=c,C=US`), which is what Envoy puts in XFCC.
+fn rfc2253(peer: &PeerIdentity) -> String {
+    let mut parts = Vec::new();
+    for e in peer.chain[0].subject_name().entries() {
+        let key = e.object().nid().short_name().unwrap_or("UNKNOWN");
+        let value = e.data().as_utf8().map(|s| s.to_string()).unwrap_or_default();
+        parts.push(format!("{key}={}", escape_rdn(&value)));
+    }
+    parts.reverse();
+    parts.join(",")
+}
+
+fn escape_rdn(value: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == value.chars().count() - 1 && c == ' ');
+        if special {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Escape a value for a double-quoted XFCC field.
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// An unquoted XFCC value, quoted like Envoy does when it holds a separator (`,;=`).
+fn xfcc_value(value: &str) -> String {
+    if value.contains([',', ';', '=', '"', '\\']) {
+        format!("\"{}\"", quote(value))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters.
+fn url_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3);
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Version;
+    use crate::pki::{self, KeyType, Sans, Usage};
+    use openssl::x509::X509NameBuilder;
+    use std::sync::Arc;
+
+    fn peer(cn: &str, san_uri: &[&str]) -> PeerIdentity {
+        let ca_key = KeyType::EcP256.generate().unwrap();
+        let ca = pki::ca_cert(&ca_key, "CAs", "Test CA", 1, None).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("O", "Example Org").unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let sans = Sans { uri: san_uri.iter().map(|s| s.to_string()).collect(), ..Sans::default() };
+        let key = KeyType::EcP256.generate().unwrap();
+        let leaf = pki::sign_leaf(&name.build(), &key, &sans, Usage::Client, 1, (&ca, &ca_key)).unwrap();
+        PeerIdentity::from_chain(vec![leaf, ca], None).unwrap()
+    }
+
+    fn request(peer: PeerIdentity, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".into(),
+            target: "/".into(),
+            version: Version::Http11,
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: Vec::new(),
+            peer: Some(Arc::new(peer)),
+        }
+    }
+
+    fn header_lines(head: &[u8], name: &str) -> Vec<String> {
+        String::from_utf8_lossy(head).split("\r\n")
+            .filter(|l| l.to_ascii_lowercase().starts_with(&format!("{}:", name.to_ascii_lowercase())))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn client_copies_of_identity_headers_are_replaced() {
+        let req = request(peer("device-1", &[]), &[(SUBJECT_HEADER, "CN=admin"), ("x-forwarded-client-cert", "Hash=00")]);
+        let head = request_head(&req).unwrap();
+        assert_eq!(header_lines(&head, SUBJECT_HEADER), ["X-Client-Subject-DN: O=Example Org, CN=device-1"]);
+        assert_eq!(header_lines(&head, XFCC_HEADER).len(), 1);
+    }
+
+    #[test]
+    fn control_characters_in_the_certificate_are_refused() {
+        for cn in ["evil\r\nX-Client-Subject-DN: CN=admin", "evil\nX: y", "tab\there", "del\x7f"] {
+            let req = request(peer(cn, &[]), &[]);
+            assert!(request_head(&req).is_err(), "{cn:?}");
+        }
+        let req = request(peer("device-1", &["spiffe://a/b\r\nX-Injected: 1"]), &[]);
+        assert!(request_head(&req).is_err());
+    }
+
+    #[test]
+    fn control_characters_from_the_client_are_refused() {
+        for (name, value) in [
+            ("X-A", "b\rX-Injected: 1"),
+            ("X-A", "b\nX-Injected: 1"),
+            ("X-A", "b\x00"),
+            ("X-A", "b\x7f"),
+            ("X\r\nInjected", "1"),
+            ("X A", "1"),
+            ("X:A", "1"),
+            ("", "1"),
+        ] {
+            assert!(check_request(&request(peer("device-1", &[]), &[(name, value)])).is_err(), "{name:?}: {value:?}");
+        }
+        let mut req = request(peer("device-1", &[]), &[]);
+        req.target = "/ HTTP/1.1\r\nX-Injected: 1".into();
+        assert!(check_request(&req).is_err());
+        let mut req = request(peer("device-1", &[]), &[]);
+        req.method = "GET /x HTTP/1.1\r\n".into();
+        assert!(check_request(&req).is_err());
+
+        check_request(&request(peer("device-1", &[]), &[("X-A", "tab\there é")])).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_requests_never_reach_the_upstream() {
+        // Nothing listens there: forwarding would answer 502.
+        let config = ProxyConfig { upstream: Some(Upstream::try_from("127.0.0.1:1".to_string()).unwrap()), ..ProxyConfig::default() };
+        let proxy = Proxy::new(&config, 8192).unwrap();
+        let resp = proxy.handle(request(peer("device-1", &[]), &[("X-A", "b\rX-Injected: 1")])).await;
+        assert_eq!(resp.status, 400);
+        let resp = proxy.handle(request(peer("evil\r\nX: y", &[]), &[])).await;
+        assert_eq!(resp.status, 403);
+        let resp = proxy.handle(request(peer("device-1", &[]), &[])).await;
+        assert_eq!(resp.status, 502);
+    }
+
+    #[test]
+    fn xfcc_values_with_separators_are_quoted() {
+        let req = request(peer("a,b", &["spiffe://example.org/ns;x=y", "spiffe://example.org/plain"]), &[]);
+        let head = request_head(&req).unwrap();
+        let xfcc = &header_lines(&head, XFCC_HEADER)[0];
+        assert!(xfcc.contains(r#";Subject="CN=a\\,b,O=Example Org""#), "{xfcc}");
+        assert!(xfcc.contains(r#";URI="spiffe://example.org/ns;x=y""#), "{xfcc}");
+        assert!(xfcc.contains(";URI=spiffe://example.org/plain"), "{xfcc}");
+    }
+
+    #[test]
+    fn xfcc_quoting() {
+        for (value, want) in [
+            ("plain.example", "plain.example"),
+            ("a=b", r#""a=b""#),
+            ("a,b;c", r#""a,b;c""#),
+            (r#"q"uote\"#, r#""q\"uote\\""#),
+        ] {
+            assert_eq!(xfcc_value(value), want);
+        }
+    }
+}
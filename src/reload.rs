@@ -0,0 +1,96 @@
+use anyhow::Result;
+use openssl::ssl::SslAcceptor;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+use crate::config::Config;
+use crate::stapling::Stapler;
+use crate::tls::build_acceptor;
+
+/// An `SslAcceptor` that can be rebuilt from disk while the server runs.
+///
+/// Each connection takes a clone of the current acceptor when it starts, so an in-flight
+/// handshake or session keeps the context it began with; only new handshakes see a swap.
+pub struct ReloadableAcceptor {
+    config: Arc<Config>,
+    stapler: Option<Arc<Stapler>>,
+    current: RwLock<SslAcceptor>,
+    /// Stamps of the watched files at the last attempted reload (successful or not),
+    /// so a broken file is reported once rather than on every poll.
+    seen: Mutex<Vec<Stamp>>,
+}
+
+/// (mtime, len) of a watched file, `None` if it couldn't be stat'ed.
+type Stamp = Option<(SystemTime, u64)>;
+
+impl ReloadableAcceptor {
+    pub fn new(config: Arc<Config>, stapler: Option<Arc<Stapler>>) -> Result<Self> {
+        let acceptor = build_acceptor(&config, stapler.as_ref())?;
+        let seen = stamps(&config.watched_files());
+        Ok(ReloadableAcceptor { config, stapler, current: RwLock::new(acceptor), seen: Mutex::new(seen) })
+    }
+
+    /// The acceptor new connections should use.
+    pub fn current(&self) -> SslAcceptor {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Rebuild from the configured files and swap it in. On failure the old acceptor stays.
+    pub fn reload(&self, reason: &str) -> Result<()> {
+        *self.seen.lock().unwrap() = stamps(&self.config.watched_files());
+        match build_acceptor(&self.config, self.stapler.as_ref()) {
+            Ok(acceptor) => {
+                *self.current.write().unwrap() = acceptor;
+                info!(reason, "reload: new TLS material active");
+                Ok(())
+            }
+            Err(e) => {
+                error!(reason, "reload failed, keeping previous TLS material: {e:#}");
+                Err(e)
+            }
+        }
+    }
+
+    /// Poll the identity and trust files and reload when any of them changes.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let files = self.config.watched_files();
+        let mut tick = tokio::time::interval(interval);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            let now = stamps(&files);
+            if *self.seen.lock().unwrap() != now {
+                let _ = self.reload("file change");
+            }
+        }
+    }
+
+    /// Rebuild on a fixed schedule, e.g. to pick up CRLs replaced in place.
+    pub async fn periodic(self: Arc<Self>, reason: &'static str, interval: Duration) {
+        let mut tick = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            let _ = self.reload(reason);
+        }
+    }
+
+    /// Reload on every SIGHUP.
+    #[cfg(unix)]
+    pub async fn on_sighup(self: Arc<Self>) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hup = signal(SignalKind::hangup())?;
+        while hup.recv().await.is_some() {
+            let _ = self.reload("SIGHUP");
+        }
+        Ok(())
+    }
+}
+
+fn stamps(files: &[PathBuf]) -> Vec<Stamp> {
+    files.iter()
+        .map(|f| std::fs::metadata(f).ok().and_then(|m| Some((m.modified().ok()?, m.len()))))
+        .collect()
+}
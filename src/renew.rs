@@ -0,0 +1,317 @@
+//! Client certificate renewal: once a set fraction of the certificate's lifetime has
+//! passed, generate a new key of the same type, re-enroll it with EST `simplereenroll`
+//! over an mTLS connection made with the current certificate, and swap the identity
+//! files in place.
+//!
+//! Each file is replaced atomically (a sibling temporary file renamed over it). Both new
+//! files are written before either is swapped in, the key first; if the certificate
+//! can't then be swapped, the old key is put back so the two still match. The chain
+//! after the new leaf is the one already in use, so renewal stops with the files
+//! untouched if the issuer changed.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use openssl::asn1::{Asn1Time, TimeDiff};
+use openssl::base64;
+use openssl::ec::EcKey;
+use openssl::nid::Nid;
+use openssl::pkcs7::Pkcs7;
+use openssl::pkey::{Id, PKey, PKeyRef, Private};
+use openssl::rsa::Rsa;
+use openssl::symm::Cipher;
+use openssl::x509::{X509Ref, X509Req, X509};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+use zeroize::Zeroizing;
+
+use crate::client::{Client, ClientRequest};
+use crate::config::IdentityConfig;
+use crate::http::reason;
+use crate::pki;
+use crate::policy::serial_hex;
+use crate::tls::load_identity;
+
+/// Where the server's EST re-enrollment is served.
+const REENROLL_PATH: &str = "/.well-known/est/simplereenroll";
+/// Time allowed for the re-enrollment exchange after the handshake.
+const REENROLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Background renewal for long-running clients.
+#[derive(Debug, Clone, Args)]
+pub struct RenewArgs {
+    /// Renew the client certificate once this fraction of its lifetime has passed,
+    /// e.g. 0.7; off when unset
+    #[arg(long, value_parser = parse_fraction)]
+    pub renew_at: Option<f64>,
+
+    /// How often to check whether renewal is due
+    #[arg(long, default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..))]
+    pub renew_check_secs: u64,
+}
+
+/// A fraction strictly between 0 and 1.
+pub fn parse_fraction(s: &str) -> Result<f64> {
+    let f: f64 = s.parse().map_err(|_| anyhow!("{s:?}: expected a number such as 0.7"))?;
+    if !(f > 0.0 && f < 1.0) {
+        bail!("{s:?}: must be between 0 and 1");
+    }
+    Ok(f)
+}
+
+/// Check every `interval` and renew once `at` of the lifetime has passed. Failures are
+/// logged and retried at the next check.
+pub async fn run(client: Arc<Client>, at: f64, interval: Duration) {
+    loop {
+        match renew(&client, Some(at)).await {
+            Ok(Some(cert)) => info!(serial = serial_hex(&cert), not_after = %cert.not_after(), "renew: new certificate"),
+            Ok(None) => {}
+            Err(e) => error!("renew: failed: {e:#}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Renew the client's certificate if `at` of its lifetime has passed, or regardless
+/// with `None`, and switch `client` over to it. `None` when it wasn't due.
+pub async fn renew(client: &Client, at: Option<f64>) -> Result<Option<X509>> {
+    let identity = client.config().identity();
+    let current = load_identity(&identity).context("loading client identity")?;
+    if let Some(at) = at {
+        if !due(&current.cert, at)? {
+            return Ok(None);
+        }
+    }
+
+    let key = new_key_like(&current.key)?;
+    let csr = csr(&current.cert, &key)?;
+    let req = ClientRequest {
+        method: "POST".to_string(),
+        path: REENROLL_PATH.to_string(),
+        headers: vec![
+            ("Content-Type".to_string(), "application/pkcs10".to_string()),
+            ("Content-Transfer-Encoding".to_string(), "base64".to_string()),
+        ],
+        body: base64::encode_block(&csr.to_der()?).into_bytes(),
+    };
+    let (_, resp) = client.request(&req, REENROLL_TIMEOUT).await.context("re-enrolling")?;
+    if resp.status != 200 {
+        bail!("re-enrollment refused: {} {}: {}", resp.status, reason(resp.status),
+            String::from_utf8_lossy(&resp.body).trim());
+    }
+
+    let content_type = resp.headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+        .map_or("", |(_, v)| v.as_str());
+    if !content_type.starts_with("application/pkcs7-mime") {
+        bail!("re-enrollment answered with {content_type:?} instead of a certificate; is EST enabled on the server?");
+    }
+    let cert = issued_cert(&resp.body, &key)?;
+    if cert.issuer_name().try_cmp(current.cert.issuer_name())? != Ordering::Equal {
+        bail!("renewed certificate has a different issuer; files left unchanged");
+    }
+    save(&identity, &key, &cert, &current.chain)?;
+    client.reload().context("loading the renewed identity")?;
+    Ok(Some(cert))
+}
+
+/// Whether at least `at` of the validity period of `cert` lies behind us.
+fn due(cert: &X509Ref, at: f64) -> Result<bool> {
+    let secs = |d: TimeDiff| d.days as f64 * 86400.0 + d.secs as f64;
+    let now = Asn1Time::days_from_now(0)?;
+    let lifetime = secs(cert.not_before().diff(cert.not_after())?);
+    let elapsed = secs(cert.not_before().diff(&now)?);
+    Ok(elapsed >= at * lifetime)
+}
+
+/// A fresh key of the same algorithm and size as `key`.
+fn new_key_like(key: &PKeyRef<Private>) -> Result<PKey<Private>> {
+    Ok(match key.id() {
+        Id::RSA => PKey::from_rsa(Rsa::generate(key.bits())?)?,
+        Id::EC => PKey::from_ec_key(EcKey::generate(key.ec_key()?.group())?)?,
+        Id::ED25519 => PKey::generate_ed25519()?,
+        other => bail!("cannot renew a certificate with key type {other:?}"),
+    })
+}
+
+/// CSR for `key` with the current subject, as `simplereenroll` requires.
+fn csr(current: &X509Ref, key: &PKeyRef<Private>) -> Result<X509Req> {
+    let mut builder = X509Req::builder()?;
+    builder.set_version(0)?;
+    builder.set_subject_name(current.subject_name())?;
+    builder.set_pubkey(key)?;
+    builder.sign(key, pki::digest(key))?;
+    Ok(builder.build())
+}
+
+/// The certificate for `key` in a base64 certs-only PKCS#7 response.
+fn issued_cert(body: &[u8], key: &PKeyRef<Private>) -> Result<X509> {
+    let text: String = std::str::from_utf8(body)?.split_ascii_whitespace().collect();
+    let der = base64::decode_block(&text).context("response is not base64")?;
+    let p7 = Pkcs7::from_der(&der).context("response is not PKCS#7")?;
+    let certs = p7.signed().and_then(|s| s.certificates())
+        .ok_or_else(|| anyhow!("response carries no certificate"))?;
+    certs.iter()
+        .find(|c| c.public_key().is_ok_and(|k| k.public_eq(key)))
+        .map(|c| c.to_owned())
+        .ok_or_else(|| anyhow!("no certificate in the response matches the new key"))
+}
+
+/// Write the renewed identity over the files it was loaded from.
+fn save(identity: &IdentityConfig, key: &PKeyRef<Private>, cert: &X509Ref, chain: &[X509]) -> Result<()> {
+    match identity {
+        IdentityConfig::Pem { cert: cert_path, key: key_path, key_password } => {
+            let mut pem = cert.to_pem()?;
+            for c in chain {
+                pem.extend(c.to_pem()?);
+            }
+            // An encrypted key stays encrypted, with the same password.
+            let key_pem = Zeroizing::new(match key_password {
+                Some(password) => key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), password.resolve()?.as_bytes())?,
+                None => key.private_key_to_pem_pkcs8()?,
+            });
+            replace_pair(key_path, &key_pem, cert_path, &pem)?;
+        }
+        IdentityConfig::Pkcs12 { path, password } => {
+            let name = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()
+                .and_then(|e| e.data().as_utf8().ok())
+                .map(|s| s.to_string())
+                .unwrap_or_default();
+            replace_file(path, &pki::pkcs12(&name, key, cert, chain, &password.resolve()?)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace `path` through a temporary sibling and a rename.
+fn replace_file(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = stage(path, data, "tmp")?;
+    swap_in(&tmp, path)
+}
+
+/// Replace the key and then the certificate. Both are staged first, along with a copy of
+/// the old key to put back should the certificate fail, so an error leaves a matching
+/// pair either way.
+fn replace_pair(key_path: &Path, key: &[u8], cert_path: &Path, cert: &[u8]) -> Result<()> {
+    let old_key = Zeroizing::new(std::fs::read(key_path).with_context(|| format!("reading {}", key_path.display()))?);
+    let staged = stage(key_path, key, "tmp")
+        .and_then(|key_tmp| Ok((key_tmp, stage(cert_path, cert, "tmp")?, stage(key_path, &old_key, "old")?)));
+    let (key_tmp, cert_tmp, key_old) = match staged {
+        Ok(staged) => staged,
+        Err(e) => {
+            discard(&[&sibling(key_path, "tmp"), &sibling(cert_path, "tmp"), &sibling(key_path, "old")]);
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = swap_in(&key_tmp, key_path) {
+        discard(&[&key_tmp, &cert_tmp, &key_old]);
+        return Err(e);
+    }
+    if let Err(e) = swap_in(&cert_tmp, cert_path) {
+        discard(&[&cert_tmp]);
+        return Err(match swap_in(&key_old, key_path) {
+            Ok(()) => e.context("previous key restored"),
+            Err(restore) => e.context(format!(
+                "{} no longer matches {}: restoring the previous key failed: {restore:#}",
+                key_path.display(), cert_path.display(),
+            )),
+        });
+    }
+    discard(&[&key_old]);
+    Ok(())
+}
+
+/// `<path>.<suffix>`.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Write `data` to `<path>.<suffix>`, synced and with the permissions of `path`; the
+/// file is owner-only until it has them.
+fn stage(path: &Path, data: &[u8], suffix: &str) -> Result<PathBuf> {
+    let permissions = std::fs::metadata(path).with_context(|| format!("reading {}", path.display()))?.permissions();
+    let tmp = sibling(path, suffix);
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp).with_context(|| format!("writing {}", tmp.display()))?;
+    std::io::Write::write_all(&mut file, data).with_context(|| format!("writing {}", tmp.display()))?;
+    file.sync_all()?;
+    file.set_permissions(permissions)?;
+    Ok(tmp)
+}
+
+fn swap_in(tmp: &Path, path: &Path) -> Result<()> {
+    std::fs::rename(tmp, path).with_context(|| format!("replacing {}", path.display()))
+}
+
+/// Remove leftover staged files; best-effort.
+fn discard(paths: &[&Path]) {
+    for path in paths {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("renew-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn key_and_certificate_are_replaced_together() {
+        let dir = scratch("pair");
+        let (key, cert) = (dir.join("client.key"), dir.join("client.crt"));
+        std::fs::write(&key, "old key").unwrap();
+        std::fs::write(&cert, "old cert").unwrap();
+
+        replace_pair(&key, b"new key", &cert, b"new cert").unwrap();
+        assert_eq!(std::fs::read(&key).unwrap(), b"new key");
+        assert_eq!(std::fs::read(&cert).unwrap(), b"new cert");
+        let mut left: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        left.sort();
+        assert_eq!(left, ["client.crt", "client.key"], "staged files are cleaned up");
+    }
+
+    #[test]
+    fn failed_certificate_swap_restores_the_key() {
+        let dir = scratch("rollback");
+        let (key, cert) = (dir.join("client.key"), dir.join("client.crt"));
+        std::fs::write(&key, "old key").unwrap();
+        // A non-empty directory where the certificate goes: staging works, the rename
+        // over it doesn't.
+        std::fs::create_dir_all(cert.join("in-the-way")).unwrap();
+
+        let err = replace_pair(&key, b"new key", &cert, b"new cert").unwrap_err();
+        assert!(format!("{err:#}").contains("previous key restored"), "{err:#}");
+        assert_eq!(std::fs::read(&key).unwrap(), b"old key");
+        assert!(!sibling(&key, "old").exists() && !sibling(&key, "tmp").exists() && !sibling(&cert, "tmp").exists());
+    }
+
+    #[test]
+    fn failed_staging_leaves_both_files() {
+        let dir = scratch("staging");
+        let (key, cert) = (dir.join("client.key"), dir.join("missing").join("client.crt"));
+        std::fs::write(&key, "old key").unwrap();
+
+        replace_pair(&key, b"new key", &cert, b"new cert").unwrap_err();
+        assert_eq!(std::fs::read(&key).unwrap(), b"old key");
+        assert!(!sibling(&key, "tmp").exists());
+    }
+}
@@ -0,0 +1,204 @@
+//! Passwords for private keys and PKCS#12 bundles, and where they come from, written like
+//! openssl's `-passin`: `pass:<literal>`, `env:<VAR>`, `file:<path>`, `stdin`, plus
+//! `credential:<name>` for a systemd credential (`LoadCredential=`, read from
+//! `$CREDENTIALS_DIRECTORY`). Anything else is taken literally, so existing plain
+//! passwords keep working; a password that is itself `stdin` or starts with one of the
+//! prefixes must be written with `pass:`, e.g. `pass:stdin` or `pass:env:x`.
+//!
+//! Sources are read every time a key is loaded, so a rotated password file is picked up
+//! by the next reload; stdin can only be read once and is kept for the process lifetime.
+//! Values are held in [`Zeroizing`] buffers, wiped when dropped.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use zeroize::Zeroizing;
+
+use crate::config::check_file;
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Secret {
+    Literal(Zeroizing<String>),
+    Env(String),
+    File(PathBuf),
+    Stdin,
+    /// A systemd credential by name.
+    Credential(String),
+}
+
+impl Secret {
+    pub fn literal(value: &str) -> Secret {
+        Secret::Literal(Zeroizing::new(value.to_string()))
+    }
+
+    /// Parse the `-passin` syntax; for clap's `value_parser`.
+    pub fn parse(s: &str) -> Result<Secret> {
+        Secret::try_from(s.to_string())
+    }
+
+    /// Read the value now. Files and credentials contribute their first line, like
+    /// openssl's `file:`.
+    pub fn resolve(&self) -> Result<Zeroizing<String>> {
+        match self {
+            Secret::Literal(value) => Ok(value.clone()),
+            Secret::Env(var) => std::env::var(var).map(Zeroizing::new)
+                .map_err(|_| anyhow!("environment variable {var} is not set")),
+            Secret::File(path) => read_first_line(path),
+            Secret::Credential(name) => read_first_line(&credential_path(name)?),
+            Secret::Stdin => {
+                static STDIN: OnceLock<Zeroizing<String>> = OnceLock::new();
+                if let Some(value) = STDIN.get() {
+                    return Ok(value.clone());
+                }
+                let mut line = Zeroizing::new(String::new());
+                std::io::stdin().read_line(&mut line).context("reading a password from stdin")?;
+                let value = Zeroizing::new(line.trim_end_matches(['\r', '\n']).to_string());
+                Ok(STDIN.get_or_init(|| value).clone())
+            }
+        }
+    }
+
+    /// The file the value is read from, if any; watched for changes like the key itself.
+    pub fn file(&self) -> Option<PathBuf> {
+        match self {
+            Secret::File(path) => Some(path.clone()),
+            Secret::Credential(name) => credential_path(name).ok(),
+            Secret::Literal(_) | Secret::Env(_) | Secret::Stdin => None,
+        }
+    }
+
+    /// Check that the source exists, without reading it.
+    pub fn validate(&self, key: &str) -> Result<()> {
+        match self {
+            Secret::Env(var) if std::env::var_os(var).is_none() => bail!("{key}: environment variable {var} is not set"),
+            Secret::File(path) => check_file(key, path),
+            Secret::Credential(name) => check_file(key, &credential_path(name).map_err(|e| anyhow!("{key}: {e}"))?),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<String> for Secret {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        let s = Zeroizing::new(s);
+        let (scheme, rest) = s.split_once(':').unwrap_or((s.as_str(), ""));
+        let named = |what: &str| -> Result<String> {
+            if rest.is_empty() {
+                bail!("{scheme}: missing {what}");
+            }
+            Ok(rest.to_string())
+        };
+        Ok(match scheme {
+            "pass" => Secret::Literal(Zeroizing::new(rest.to_string())),
+            "env" => Secret::Env(named("variable name")?),
+            "file" => Secret::File(PathBuf::from(named("path")?)),
+            "credential" => Secret::Credential(named("credential name")?),
+            "stdin" if rest.is_empty() => Secret::Stdin,
+            _ => Secret::Literal(s.clone()),
+        })
+    }
+}
+
+/// Never shows a literal value.
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Literal(_) => f.write_str("pass:<redacted>"),
+            Secret::Env(var) => write!(f, "env:{var}"),
+            Secret::File(path) => write!(f, "file:{}", path.display()),
+            Secret::Stdin => f.write_str("stdin"),
+            Secret::Credential(name) => write!(f, "credential:{name}"),
+        }
+    }
+}
+
+fn credential_path(name: &str) -> Result<PathBuf> {
+    let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+        .ok_or_else(|| anyhow!("credential:{name}: $CREDENTIALS_DIRECTORY is not set (not started by systemd?)"))?;
+    Ok(PathBuf::from(dir).join(name))
+}
+
+fn read_first_line(path: &std::path::Path) -> Result<Zeroizing<String>> {
+    let text = Zeroizing::new(std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?);
+    Ok(Zeroizing::new(text.lines().next().unwrap_or_default().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(s: &str) -> String {
+        format!("{:?}", Secret::parse(s).unwrap())
+    }
+
+    fn literal(s: &str) -> String {
+        Secret::parse(s).unwrap().resolve().unwrap().to_string()
+    }
+
+    #[test]
+    fn sources_are_parsed() {
+        assert_eq!(parsed("env:KEY_PASSWORD"), "env:KEY_PASSWORD");
+        assert_eq!(parsed("file:/run/secrets/key"), "file:/run/secrets/key");
+        assert_eq!(parsed("credential:server-key"), "credential:server-key");
+        assert_eq!(parsed("stdin"), "stdin");
+        for s in ["pass:secret", "changeit", "pass:stdin", "stdin:x", "other:x"] {
+            assert_eq!(parsed(s), "pass:<redacted>", "{s}");
+        }
+    }
+
+    #[test]
+    fn sources_need_a_name() {
+        for s in ["env:", "file:", "credential:"] {
+            assert!(Secret::parse(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn literals_keep_everything_after_pass() {
+        assert_eq!(literal("pass:secret"), "secret");
+        assert_eq!(literal("pass:"), "");
+        assert_eq!(literal("pass:stdin"), "stdin");
+        assert_eq!(literal("pass:env:x"), "env:x");
+        assert_eq!(literal("changeit"), "changeit");
+        assert_eq!(literal("a:b"), "a:b");
+    }
+
+    #[test]
+    fn environment_variables_are_read() {
+        let var = format!("SECRET_TEST_{}", std::process::id());
+        let secret = Secret::parse(&format!("env:{var}")).unwrap();
+        assert!(secret.validate("identity.key_password").is_err());
+        assert!(secret.resolve().is_err());
+
+        std::env::set_var(&var, "from-env");
+        secret.validate("identity.key_password").unwrap();
+        assert_eq!(secret.resolve().unwrap().as_str(), "from-env");
+        std::env::remove_var(&var);
+    }
+
+    #[test]
+    fn files_and_credentials_give_their_first_line() {
+        let dir = std::env::temp_dir().join(format!("secret-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server-key");
+        std::fs::write(&path, "from-file\r\nsecond line\n").unwrap();
+
+        let file = Secret::parse(&format!("file:{}", path.display())).unwrap();
+        assert_eq!(file.resolve().unwrap().as_str(), "from-file");
+        assert_eq!(file.file(), Some(path.clone()));
+
+        let credential = Secret::parse("credential:server-key").unwrap();
+        std::env::set_var("CREDENTIALS_DIRECTORY", &dir);
+        assert_eq!(credential.resolve().unwrap().as_str(), "from-file");
+        assert_eq!(credential.file(), Some(path));
+        assert!(Secret::parse("credential:missing").unwrap().validate("identity.key_password").is_err());
+        std::env::remove_var("CREDENTIALS_DIRECTORY");
+        assert!(credential.resolve().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
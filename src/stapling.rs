@@ -0,0 +1,240 @@
+//! OCSP stapling for the server certificate(s).
+//!
+//! A background task keeps one OCSP response per served leaf, fetched from the AIA
+//! responder (or `[stapling] responder`) or read from a pre-fetched DER file. The status
+//! callback only staples a response that is inside its thisUpdate..nextUpdate window; one
+//! without nextUpdate counts as stale `ocsp::MAX_AGE_SECS` after its thisUpdate.
+
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{OcspCertId, OcspRequest};
+use openssl::ssl::SslAcceptorBuilder;
+use openssl::x509::{X509Ref, X509VerifyResult, X509};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::ocsp::{current_for, http_post, is_current, responder_url, verify_response};
+use crate::tls::Identity;
+use crate::x509_name_to_string;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaplingConfig {
+    pub enabled: bool,
+    /// Pre-fetched DER OCSP response for the top-level identity. Fetched when unset.
+    pub file: Option<PathBuf>,
+    /// Responder URL used instead of the one in the certificate's AIA extension.
+    pub responder: Option<String>,
+    pub refresh_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for StaplingConfig {
+    fn default() -> Self {
+        StaplingConfig { enabled: false, file: None, responder: None, refresh_secs: 3600, timeout_secs: 10 }
+    }
+}
+
+impl StaplingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(url) = &self.responder {
+            crate::ocsp::validate_url(url).context("stapling.responder")?;
+        }
+        if self.refresh_secs == 0 {
+            bail!("stapling.refresh_secs: must be at least 1");
+        }
+        if self.timeout_secs == 0 {
+            bail!("stapling.timeout_secs: must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Where the response for one identity comes from.
+#[derive(Debug, Clone)]
+pub enum StapleSource {
+    /// Ask this responder, or the leaf's AIA responder when `None`.
+    Fetch(Option<String>),
+    /// Read a DER OCSPResponse from disk.
+    File(PathBuf),
+}
+
+impl StapleSource {
+    pub fn for_identity(config: &StaplingConfig, file: Option<&PathBuf>) -> StapleSource {
+        match file {
+            Some(path) => StapleSource::File(path.clone()),
+            None => StapleSource::Fetch(config.responder.clone()),
+        }
+    }
+}
+
+struct Target {
+    fingerprint: String,
+    leaf: X509,
+    /// Issuer first, then the rest of the configured chain, for CertID and verification.
+    chain: Vec<X509>,
+    source: StapleSource,
+}
+
+/// A verified response, stapled until it stops being current. Timed on tokio's clock.
+struct Staple {
+    der: Vec<u8>,
+    expires: Instant,
+}
+
+pub struct Stapler {
+    targets: Mutex<Vec<Arc<Target>>>,
+    staples: RwLock<HashMap<String, Staple>>,
+    refresh: Notify,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Stapler {
+    /// `None` when stapling is disabled.
+    pub fn new(config: &StaplingConfig) -> Option<Arc<Stapler>> {
+        if !config.enabled {
+            return None;
+        }
+        Some(Arc::new(Stapler {
+            targets: Mutex::new(Vec::new()),
+            staples: RwLock::new(HashMap::new()),
+            refresh: Notify::new(),
+            interval: Duration::from_secs(config.refresh_secs),
+            timeout: Duration::from_secs(config.timeout_secs),
+        }))
+    }
+
+    /// DER response to staple for `leaf`, if we hold one that is currently valid.
+    fn staple_for(&self, leaf: &X509Ref) -> Option<Vec<u8>> {
+        let fingerprint = fingerprint(leaf).ok()?;
+        let staples = self.staples.read().unwrap();
+        let staple = staples.get(&fingerprint)?;
+        (staple.expires > Instant::now()).then(|| staple.der.clone())
+    }
+
+    fn set_targets(&self, targets: Vec<Target>) {
+        let targets: Vec<Arc<Target>> = targets.into_iter().map(Arc::new).collect();
+        self.staples.write().unwrap()
+            .retain(|fp, _| targets.iter().any(|t| &t.fingerprint == fp));
+        *self.targets.lock().unwrap() = targets;
+        self.refresh.notify_one();
+    }
+
+    /// Refresh every target on a timer and whenever the acceptor is rebuilt.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            self.refresh().await;
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => {}
+                _ = self.refresh.notified() => {}
+            }
+        }
+    }
+
+    /// Obtain a response for every target once. A target whose refresh fails keeps the
+    /// response it has until that expires.
+    pub async fn refresh(&self) {
+        let targets = self.targets.lock().unwrap().clone();
+        for target in targets {
+            let subject = x509_name_to_string(target.leaf.subject_name());
+            match self.obtain(&target).await {
+                Ok(staple) => {
+                    self.staples.write().unwrap().insert(target.fingerprint.clone(), staple);
+                }
+                Err(e) => warn!(subject, "OCSP staple refresh failed: {e:#}"),
+            }
+        }
+    }
+
+    async fn obtain(&self, target: &Target) -> Result<Staple> {
+        let issuer = &target.chain[0];
+        let der = match &target.source {
+            StapleSource::File(path) => std::fs::read(path)
+                .with_context(|| format!("reading {}", path.display()))?,
+            StapleSource::Fetch(responder) => {
+                let url = match responder {
+                    Some(url) => url.clone(),
+                    None => responder_url(&target.leaf)?,
+                };
+                let mut req = OcspRequest::new()?;
+                req.add_id(OcspCertId::from_cert(MessageDigest::sha1(), &target.leaf, issuer)?)?;
+                http_post(&url, "application/ocsp-request", &req.to_der()?, self.timeout).await?
+            }
+        };
+        let basic = verify_response(&der, &target.chain)?;
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &target.leaf, issuer)?;
+        let remaining = current_for(&basic, &id).filter(|secs| *secs > 0 && is_current(&basic, &id, 0))
+            .ok_or_else(|| anyhow!("OCSP response does not cover the certificate or is outside its validity window"))?;
+        Ok(Staple { der, expires: Instant::now() + Duration::from_secs(remaining as u64) })
+    }
+}
+
+/// Collects the leaves of an acceptor under construction and installs status callbacks.
+pub struct Stapling {
+    stapler: Arc<Stapler>,
+    targets: Vec<Target>,
+    source: StapleSource,
+    /// Source once `source` has been used; a pre-fetched file covers only one certificate.
+    rest: StapleSource,
+}
+
+impl Stapling {
+    pub fn new(stapler: Arc<Stapler>) -> Stapling {
+        Stapling { stapler, targets: Vec::new(), source: StapleSource::Fetch(None), rest: StapleSource::Fetch(None) }
+    }
+
+    /// Sources for the identities attached next: `first` for the first of them, `rest`
+    /// for the certificates with other key types that follow it.
+    pub fn source(&mut self, first: StapleSource, rest: StapleSource) -> &mut Stapling {
+        self.source = first;
+        self.rest = rest;
+        self
+    }
+
+    pub fn attach(&mut self, builder: &mut SslAcceptorBuilder, server: &Identity) -> Result<()> {
+        let leaf = &server.cert;
+        let source = std::mem::replace(&mut self.source, self.rest.clone());
+        let Some(issuer) = server.chain.iter().find(|c| c.issued(leaf) == X509VerifyResult::OK) else {
+            warn!(subject = x509_name_to_string(leaf.subject_name()), "OCSP stapling disabled: issuer not in the configured chain");
+            return Ok(());
+        };
+        let mut chain = vec![issuer.clone()];
+        chain.extend(server.chain.iter().filter(|c| *c != issuer).cloned());
+
+        self.targets.push(Target {
+            fingerprint: fingerprint(leaf)?,
+            leaf: leaf.clone(),
+            chain,
+            source,
+        });
+
+        let stapler = self.stapler.clone();
+        builder.set_status_callback(move |ssl| {
+            // The certificate actually selected for this connection (after SNI).
+            let Some(der) = ssl.certificate().and_then(|c| stapler.staple_for(c)) else {
+                return Ok(false);
+            };
+            ssl.set_ocsp_status(&der)?;
+            Ok(true)
+        })?;
+        Ok(())
+    }
+
+    /// The acceptor built: start maintaining responses for exactly these leaves.
+    pub fn commit(self) {
+        self.stapler.set_targets(self.targets);
+    }
+}
+
+fn fingerprint(cert: &X509Ref) -> Result<String> {
+    cert.digest(MessageDigest::sha256())
+        .map(hex::encode)
+        .map_err(|e| anyhow!("fingerprinting certificate: {e}"))
+}
@@ -0,0 +1,545 @@
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::pkcs12::{ParsedPkcs12_2, Pkcs12};
+use foreign_types::ForeignTypeRef;
+use openssl::error::ErrorStack;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::provider::Provider;
+use openssl::ssl::{
+    select_next_proto, AlpnError, NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslAlert, SslContext, SslContextBuilder, SslFiletype, SslMethod, SslRef,
+    SslVerifyMode,
+};
+use openssl::x509::store::X509Lookup;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509StoreContextRef, X509Ref, X509VerifyResult, X509};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use crate::config::{Config, CrlCheck, IdentityConfig, Mode, StaleCrl, TrustConfig, UnknownSni};
+use crate::policy::Policy;
+use crate::secret::Secret;
+use crate::stapling::{StapleSource, Stapler, Stapling};
+use crate::{audit, verifier_cb, x509_name_to_string};
+
+/// Verify mode for every context: a client certificate is mandatory.
+pub const CLIENT_VERIFY: SslVerifyMode = SslVerifyMode::PEER.union(SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+
+/// Verify flags that follow a `TrustConfig`, see [`trust_flags`].
+const TRUST_FLAGS: X509VerifyFlags = X509VerifyFlags::CRL_CHECK
+    .union(X509VerifyFlags::CRL_CHECK_ALL)
+    .union(X509VerifyFlags::PARTIAL_CHAIN);
+
+/// Build the acceptor from the configured identity, trust bundle and policy, plus one
+/// context per `[[sni.hosts]]` entry selected by the SNI servername callback.
+/// Fails if any file is unreadable or a private key doesn't match its certificate.
+///
+/// With a stapler, every identity gets an OCSP status callback and the stapler is told
+/// which leaves to keep responses for once the whole acceptor has built successfully.
+pub fn build_acceptor(config: &Config, stapler: Option<&Arc<Stapler>>) -> Result<SslAcceptor> {
+    let mut stapling = stapler.map(|s| Stapling::new(s.clone()));
+    let source = StapleSource::for_identity(&config.stapling, config.stapling.file.as_ref());
+    let rest = StapleSource::for_identity(&config.stapling, None);
+    let mut builder = context_builder(&config.identity, &config.extra_identities, &config.trust,
+        Arc::new(config.policy.clone()), stapling.as_mut().map(|s| s.source(source, rest.clone())))?;
+    set_alpn(&mut builder, config);
+    if config.audit.enabled() {
+        builder.set_client_hello_callback(audit::client_hello);
+    }
+
+    if !config.sni.hosts.is_empty() || config.sni.unknown == UnknownSni::Reject {
+        let mut hosts = Vec::new();
+        for (i, host) in config.sni.hosts.iter().enumerate() {
+            let trust = host.trust.as_ref().unwrap_or(&config.trust);
+            let policy = Arc::new(host.policy.clone().unwrap_or_else(|| config.policy.clone()));
+            let source = StapleSource::for_identity(&config.stapling, host.ocsp_staple.as_ref());
+            let mut host_builder = context_builder(&host.identity, &host.extra_identities, trust, policy.clone(),
+                stapling.as_mut().map(|s| s.source(source, rest.clone())))
+                .with_context(|| format!("sni.hosts[{i}]"))?;
+            set_alpn(&mut host_builder, config);
+            let ctx = host_builder.build().into_context();
+            hosts.push(VirtualHost { names: host.names.clone(), ctx, policy, stale_crl: trust.stale_crl, flags: trust_flags(trust) });
+        }
+        let unknown = config.sni.unknown;
+        builder.set_servername_callback(move |ssl, alert| select_host(ssl, alert, &hosts, unknown));
+    }
+
+    if let Some(stapling) = stapling {
+        stapling.commit();
+    }
+    Ok(builder.build())
+}
+
+/// One server identity (plus certificates with other key types) with its client trust
+/// store and verify callback.
+fn context_builder(
+    identity: &IdentityConfig,
+    extra_identities: &[IdentityConfig],
+    trust: &TrustConfig,
+    policy: Arc<Policy>,
+    stapling: Option<&mut Stapling>,
+) -> Result<SslAcceptorBuilder> {
+    let client_ca = &trust.client_ca;
+    let cas = X509::stack_from_pem(&std::fs::read(client_ca)
+        .with_context(|| format!("reading {}", client_ca.display()))?)
+        .with_context(|| format!("loading {}", client_ca.display()))?;
+    if cas.is_empty() {
+        return Err(anyhow!("{} has no certificate", client_ca.display()));
+    }
+
+    // Build TLS acceptor (server config)
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    set_identities(&mut builder, identity, extra_identities, stapling)?;
+
+    builder.set_ca_file(client_ca)?;
+    for ca in &cas {
+        builder.add_client_ca(ca)?;
+    }
+    load_crls(&mut builder, trust)?;
+    builder.verify_param_mut().set_flags(trust_flags(trust))?;
+
+    let stale_crl = trust.stale_crl;
+    builder.set_verify_callback(CLIENT_VERIFY,
+    move |preverified: bool, x509_ctx: &mut X509StoreContextRef| verifier_cb(preverified, x509_ctx, &policy, stale_crl));
+
+    Ok(builder)
+}
+
+/// Acceptor for the EST listener: the server identity with an optional client
+/// certificate, verified against `anchors` (any CA of the chain may be the anchor) and no
+/// policy. Configured CRLs are checked for the CAs they cover; other issuers pass.
+pub fn build_est_acceptor(config: &Config, anchors: &[X509]) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    set_identities(&mut builder, &config.identity, &config.extra_identities, None)?;
+    for ca in anchors {
+        builder.cert_store_mut().add_cert(ca.clone())?;
+        builder.add_client_ca(ca)?;
+    }
+    load_crls(&mut builder, &config.trust)?;
+    builder.verify_param_mut().set_flags(X509VerifyFlags::PARTIAL_CHAIN | trust_flags(&config.trust))?;
+    builder.set_alpn_select_callback(|_, client| select_next_proto(b"\x08http/1.1", client).ok_or(AlpnError::NOACK));
+
+    if config.audit.enabled() {
+        builder.set_client_hello_callback(audit::client_hello);
+    }
+
+    builder.set_verify_callback(SslVerifyMode::PEER, |preverified, x509_ctx| {
+        audit::record_chain(x509_ctx);
+        if preverified || x509_ctx.error().as_raw() == openssl_sys::X509_V_ERR_UNABLE_TO_GET_CRL {
+            return true;
+        }
+        let subject = x509_ctx.current_cert().map(|c| x509_name_to_string(c.subject_name())).unwrap_or_default();
+        warn!(depth = x509_ctx.error_depth(), error = %x509_ctx.error(), subject, "est: client certificate verification failed");
+        audit::record_error(x509_ctx);
+        false
+    });
+    Ok(builder.build())
+}
+
+/// Install the server certificate and one extra certificate per other key type, each
+/// with its own chain.
+fn set_identities(
+    builder: &mut SslAcceptorBuilder,
+    identity: &IdentityConfig,
+    extra_identities: &[IdentityConfig],
+    mut stapling: Option<&mut Stapling>,
+) -> Result<()> {
+    let mut key_types = Vec::new();
+    for (i, config) in std::iter::once(identity).chain(extra_identities).enumerate() {
+        let server = load_identity(config)?;
+        let key_type = server.key.id();
+        if key_types.contains(&key_type) {
+            return Err(anyhow!("extra_identities[{}]: another certificate already has a {} key; each key type can be served once",
+                i - 1, key_name(key_type)));
+        }
+        key_types.push(key_type);
+
+        // OpenSSL keeps one certificate per key type; the chain follows the certificate
+        // set last, so each one is sent with its own intermediates.
+        builder.set_private_key(&server.key)?;
+        builder.set_certificate(&server.cert)?;
+        for cert in &server.chain {
+            add_chain_cert(builder, cert)?;
+        }
+        if i == 0 {
+            builder.check_private_key().context("server private key does not match certificate")?;
+        } else {
+            builder.check_private_key()
+                .with_context(|| format!("extra_identities[{}]: private key does not match certificate", i - 1))?;
+        }
+        if let Some(stapling) = stapling.as_deref_mut() {
+            stapling.attach(builder, &server)?;
+        }
+    }
+    Ok(())
+}
+
+/// `SSL_CTX_add1_chain_cert`: append to the chain of the current certificate only, where
+/// `add_extra_chain_cert` would share one chain between all of them.
+fn add_chain_cert(builder: &mut SslContextBuilder, cert: &X509Ref) -> Result<()> {
+    // SAFETY: both pointers are valid for the call; add1 takes its own reference to the
+    // certificate.
+    let ret = unsafe {
+        openssl_sys::SSL_CTX_ctrl(builder.as_ptr(), openssl_sys::SSL_CTRL_CHAIN_CERT, 1, cert.as_ptr().cast())
+    };
+    if ret <= 0 {
+        return Err(ErrorStack::get()).context("adding chain certificate");
+    }
+    Ok(())
+}
+
+fn key_name(id: Id) -> &'static str {
+    match id {
+        Id::RSA => "RSA",
+        Id::RSA_PSS => "RSA-PSS",
+        Id::EC => "ECDSA",
+        Id::ED25519 => "Ed25519",
+        Id::ED448 => "Ed448",
+        Id::DSA => "DSA",
+        _ => "other",
+    }
+}
+
+/// Prefer `h2` when enabled and offered; clients that offer neither protocol (or no ALPN
+/// at all) get HTTP/1.1. Tunnels carry arbitrary protocols and don't answer ALPN.
+fn set_alpn(builder: &mut SslAcceptorBuilder, config: &Config) {
+    if config.server.mode == Mode::Tunnel {
+        return;
+    }
+    let protos: &'static [u8] = if config.http.http2 { b"\x02h2\x08http/1.1" } else { b"\x08http/1.1" };
+    builder.set_alpn_select_callback(move |_, client| select_next_proto(protos, client).ok_or(AlpnError::NOACK));
+}
+
+/// Add the configured CRLs to the client trust store and turn on revocation checking.
+/// A revoked client certificate then fails with a `certificate_revoked` alert.
+fn load_crls(builder: &mut SslAcceptorBuilder, trust: &TrustConfig) -> Result<()> {
+    if trust.crls.is_empty() {
+        return Ok(());
+    }
+    let lookup = builder.cert_store_mut().add_lookup(X509Lookup::file())?;
+    for path in &trust.crls {
+        let head = std::fs::read(path).with_context(|| format!("reading CRL {}", path.display()))?;
+        let filetype = if head.starts_with(b"-----BEGIN") { SslFiletype::PEM } else { SslFiletype::ASN1 };
+        lookup.load_crl_file(path, filetype)
+            .with_context(|| format!("loading CRL {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// CRL checking when `trust` has CRLs, and partial chains when it allows them.
+fn trust_flags(trust: &TrustConfig) -> X509VerifyFlags {
+    let mut flags = X509VerifyFlags::empty();
+    if !trust.crls.is_empty() {
+        flags |= match trust.crl_check {
+            CrlCheck::Leaf => X509VerifyFlags::CRL_CHECK,
+            CrlCheck::Chain => X509VerifyFlags::CRL_CHECK | X509VerifyFlags::CRL_CHECK_ALL,
+        };
+    }
+    if trust.partial_chain {
+        flags |= X509VerifyFlags::PARTIAL_CHAIN;
+    }
+    flags
+}
+
+struct VirtualHost {
+    names: Vec<String>,
+    ctx: SslContext,
+    policy: Arc<Policy>,
+    stale_crl: StaleCrl,
+    /// [`trust_flags`] of the host's trust.
+    flags: X509VerifyFlags,
+}
+
+/// SNI callback: switch to the matching host's context. OpenSSL keeps the verify callback
+/// and verify parameters of the context the connection started with, so the host policy
+/// and trust flags are installed on the `Ssl` itself; the trust store and client CA list
+/// follow the new context.
+fn select_host(ssl: &mut SslRef, alert: &mut SslAlert, hosts: &[VirtualHost], unknown: UnknownSni) -> Result<(), SniError> {
+    let name = ssl.servername(NameType::HOST_NAME).map(str::to_owned);
+    let host = name.as_deref()
+        .and_then(|n| hosts.iter().find(|h| h.names.iter().any(|p| host_matches(p, n))));
+
+    match (host, unknown) {
+        (Some(host), _) => {
+            ssl.set_ssl_context(&host.ctx).map_err(|_| SniError::ALERT_FATAL)?;
+            let param = ssl.param_mut();
+            param.clear_flags(TRUST_FLAGS).map_err(|_| SniError::ALERT_FATAL)?;
+            param.set_flags(host.flags).map_err(|_| SniError::ALERT_FATAL)?;
+            let (policy, stale_crl) = (host.policy.clone(), host.stale_crl);
+            ssl.set_verify_callback(CLIENT_VERIFY, move |preverified, x509_ctx| verifier_cb(preverified, x509_ctx, &policy, stale_crl));
+            Ok(())
+        }
+        (None, UnknownSni::Default) => Ok(()),
+        (None, UnknownSni::Reject) => {
+            warn!(sni = name.unwrap_or_default(), "reject handshake: unknown server name");
+            *alert = SslAlert::UNRECOGNIZED_NAME;
+            Err(SniError::ALERT_FATAL)
+        }
+    }
+}
+
+/// Case-insensitive hostname match; `*.example.com` matches exactly one extra label.
+pub(crate) fn host_matches(pattern: &str, name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => name.split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(suffix)),
+        None => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+/// Private key, leaf certificate and the chain sent after the leaf.
+pub struct Identity {
+    pub key: PKey<Private>,
+    pub cert: X509,
+    pub chain: Vec<X509>,
+}
+
+/// Read the configured identity into memory.
+pub fn load_identity(identity: &IdentityConfig) -> Result<Identity> {
+    match identity {
+        IdentityConfig::Pem { cert, key, key_password } => {
+            let key = load_private_key(key, key_password.as_ref())?;
+            let pem = std::fs::read(cert).with_context(|| format!("reading {}", cert.display()))?;
+            let mut certs = X509::stack_from_pem(&pem)
+                .with_context(|| format!("loading certificate chain {}", cert.display()))?
+                .into_iter();
+            let leaf = certs.next().ok_or_else(|| anyhow!("{} has no certificate", cert.display()))?;
+            Ok(Identity { key, cert: leaf, chain: certs.collect() })
+        }
+        IdentityConfig::Pkcs12 { path, password } => {
+            let password = password.resolve().with_context(|| format!("password for {}", path.display()))?;
+            load_pkcs12(&path.to_string_lossy(), &password)
+        }
+    }
+}
+
+/// A PEM private key, decrypted with `password` when given. The file contents and the
+/// password are wiped from memory afterwards.
+pub fn load_private_key(path: &Path, password: Option<&Secret>) -> Result<PKey<Private>> {
+    let pem = Zeroizing::new(std::fs::read(path).with_context(|| format!("reading key {}", path.display()))?);
+    let key = match password {
+        Some(password) => {
+            let password = password.resolve().with_context(|| format!("password for {}", path.display()))?;
+            PKey::private_key_from_pem_passphrase(&pem, password.as_bytes())
+        }
+        // Without a password OpenSSL would prompt on the terminal.
+        None if is_encrypted(&pem) => bail!("key {} is encrypted; configure its password", path.display()),
+        None => PKey::private_key_from_pem(&pem),
+    };
+    key.with_context(|| format!("loading key {}", path.display()))
+}
+
+/// PKCS#8 `ENCRYPTED PRIVATE KEY` or a legacy PEM with `Proc-Type: 4,ENCRYPTED`.
+fn is_encrypted(pem: &[u8]) -> bool {
+    pem.windows(b"ENCRYPTED".len()).any(|w| w == b"ENCRYPTED")
+}
+
+/// Heuristic to skip a root CA (self-signed) if it appears in the P12.
+/// This keeps the server from sending the root to clients.
+///
+/// Robust self-signed check that works on a reference.
+fn is_self_signed(cert: &X509Ref) -> bool {
+    // OpenSSL will report OK if `cert` is issued by itself.
+    cert.issued(cert) == X509VerifyResult::OK
+}
+
+/// Load a PKCS#12 bundle (server key + leaf + chain). The CA certificates may come in any
+/// order; they are sent in issuer order without the self-signed root. Fails if the key
+/// doesn't match the leaf, a certificate isn't signed by the one it names as issuer, or
+/// the bag holds certificates outside the leaf's chain, listing every such problem.
+pub fn load_pkcs12(p12_path: &str, password: &str) -> Result<Identity> {
+    let der = std::fs::read(p12_path)
+        .with_context(|| format!("reading {}", p12_path))?;
+    let p12 = Pkcs12::from_der(&der).with_context(|| format!("{p12_path} is not a PKCS#12 file"))?;
+    let parsed = parse_pkcs12(&p12, password).with_context(|| format!("loading {p12_path}"))?;
+
+    let pkey = parsed.pkey.ok_or_else(|| anyhow!("{p12_path}: PKCS#12 has no private key"))?;
+    let mut cas: Vec<X509> = parsed.ca.map(|s| s.into_iter().collect()).unwrap_or_default();
+    // Without a localKeyId tying the key to its certificate the leaf may land among the
+    // CA certificates; take the one the key belongs to.
+    let cert = match parsed.cert {
+        Some(cert) => cert,
+        None => match cas.iter().position(|c| c.public_key().is_ok_and(|k| k.public_eq(&pkey))) {
+            Some(i) => cas.remove(i),
+            None => bail!("{p12_path}: PKCS#12 has no certificate for its private key"),
+        },
+    };
+    if !cert.public_key()?.public_eq(&pkey) {
+        bail!("{p12_path}: private key does not match certificate {}", x509_name_to_string(cert.subject_name()));
+    }
+
+    let (chain, rest) = link_chain(&cert, cas);
+    let problems = chain_problems(&cert, &chain, &rest)?;
+    if !problems.is_empty() {
+        bail!("{p12_path}: {}", problems.join("; "));
+    }
+    // Either the root is left to the clients, or an intermediate is missing; which one
+    // only the clients' roots can tell.
+    if let Some(gap) = chain_gap(&cert, &chain) {
+        warn!("{p12_path}: {gap}; clients must already hold it, so add it to the bundle if it is an intermediate");
+    }
+    let chain = chain.into_iter()
+        .filter(|c| !is_self_signed(c)) // don't send a self-signed root to clients
+        .collect();
+
+    Ok(Identity { key: pkey, cert, chain })
+}
+
+/// `parse2`, retried with OpenSSL 3's legacy provider when the bundle is encrypted with an
+/// algorithm only that provider has, e.g. RC2-40 from older `openssl pkcs12 -export` or
+/// Windows exports.
+fn parse_pkcs12(p12: &Pkcs12, password: &str) -> Result<ParsedPkcs12_2> {
+    let has_reason = |e: &ErrorStack, reason: &str| e.errors().iter().any(|e| e.reason() == Some(reason));
+    match p12.parse2(password) {
+        Ok(parsed) => Ok(parsed),
+        Err(e) if has_reason(&e, "mac verify failure") => bail!("wrong password (MAC verification failed)"),
+        Err(e) if has_reason(&e, "unsupported") => {
+            legacy_provider().context("the bundle uses a legacy algorithm and OpenSSL's legacy provider is unavailable")?;
+            p12.parse2(password).context("decrypting with the legacy provider")
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// OpenSSL's legacy provider, loaded once next to the default one and kept for the life
+/// of the process.
+fn legacy_provider() -> Result<&'static Provider, ErrorStack> {
+    static LEGACY: OnceLock<Result<Provider, ErrorStack>> = OnceLock::new();
+    LEGACY.get_or_init(|| Provider::try_load(None, "legacy", true)).as_ref().map_err(Clone::clone)
+}
+
+/// Split `certs` into the chain above `leaf` (its issuer first, then that certificate's
+/// issuer and so on) and the certificates that aren't part of it, in their original order.
+pub fn link_chain(leaf: &X509Ref, mut certs: Vec<X509>) -> (Vec<X509>, Vec<X509>) {
+    let mut chain = Vec::new();
+    let mut current = leaf.to_owned();
+    while let Some(i) = certs.iter().position(|c| c.issued(&current) == X509VerifyResult::OK) {
+        let issuer = certs.remove(i);
+        let done = is_self_signed(&issuer);
+        current = issuer.clone();
+        chain.push(issuer);
+        if done {
+            break;
+        }
+    }
+    (chain, certs)
+}
+
+/// Everything wrong with a bundle's `chain` above `leaf` and the certificates left over.
+/// A chain that stops below the root is fine on its own: clients hold the root.
+fn chain_problems(leaf: &X509Ref, chain: &[X509], rest: &[X509]) -> Result<Vec<String>> {
+    let name = |c: &X509Ref| x509_name_to_string(c.subject_name());
+    let mut problems = Vec::new();
+    let mut child = leaf;
+    for issuer in chain {
+        let key = issuer.public_key()?;
+        if !child.verify(&key)? {
+            problems.push(format!("{} names {} as issuer but is not signed by its key", name(child), name(issuer)));
+        }
+        child = issuer;
+    }
+
+    let mut extra = Vec::new();
+    for (i, cert) in rest.iter().enumerate() {
+        if *cert == *leaf || chain.iter().chain(&rest[..i]).any(|c| c == cert) {
+            problems.push(format!("{} appears more than once", name(cert)));
+        } else {
+            extra.push(format!("extra certificate {} is not part of the chain of {}", name(cert), name(leaf)));
+        }
+    }
+    // Certificates that don't link usually sit above a gap; say where the chain breaks.
+    if !extra.is_empty() {
+        problems.extend(chain_gap(leaf, chain));
+    }
+    problems.extend(extra);
+    Ok(problems)
+}
+
+/// Where `chain` above `leaf` stops short of a self-signed root, if it does.
+fn chain_gap(leaf: &X509Ref, chain: &[X509]) -> Option<String> {
+    let top = chain.last().map_or(leaf, |c| c);
+    (!is_self_signed(top)).then(|| format!("chain stops at {}: its issuer {} is not in the bundle",
+        x509_name_to_string(top.subject_name()), x509_name_to_string(top.issuer_name())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    /// A certificate for `cn`, signed by `issuer` (self-signed when `None`).
+    fn cert(cn: &str, ca: bool, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(issuer.map_or(&*name, |(c, _)| c.subject_name())).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        if ca {
+            builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+        }
+        builder.sign(issuer.map_or(&key, |(_, k)| k), MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    #[test]
+    fn gaps_in_the_chain_are_found() {
+        let (root, root_key) = cert("Root", true, None);
+        let (intermediate, intermediate_key) = cert("Intermediate", true, Some((&root, &root_key)));
+        let (leaf, _) = cert("leaf", false, Some((&intermediate, &intermediate_key)));
+        let (self_signed, _) = cert("self", false, None);
+
+        for (name, leaf, cas, gap) in [
+            ("leaf alone", &leaf, vec![], Some("chain stops at CN=leaf: its issuer CN=Intermediate is not in the bundle")),
+            ("leaf and root", &leaf, vec![root.clone()], Some("chain stops at CN=leaf: its issuer CN=Intermediate")),
+            ("without the root", &leaf, vec![intermediate.clone()], Some("chain stops at CN=Intermediate: its issuer CN=Root")),
+            ("full chain", &leaf, vec![root.clone(), intermediate.clone()], None),
+            ("self-signed", &self_signed, vec![], None),
+        ] {
+            let (chain, _) = link_chain(leaf, cas);
+            let found = chain_gap(leaf, &chain);
+            match gap {
+                Some(gap) => assert!(found.as_deref().is_some_and(|f| f.starts_with(gap)), "{name}: {found:?}"),
+                None => assert_eq!(found, None, "{name}"),
+            }
+        }
+    }
+
+    #[test]
+    fn bundle_problems() {
+        let (root, root_key) = cert("Root", true, None);
+        let (intermediate, intermediate_key) = cert("Intermediate", true, Some((&root, &root_key)));
+        let (leaf, _) = cert("leaf", false, Some((&intermediate, &intermediate_key)));
+        let (other, _) = cert("Other", true, None);
+
+        let problems = |cas: Vec<X509>| {
+            let (chain, rest) = link_chain(&leaf, cas);
+            chain_problems(&leaf, &chain, &rest).unwrap()
+        };
+        assert!(problems(vec![intermediate.clone(), root.clone()]).is_empty());
+        // The root above a missing intermediate doesn't link: it's reported with the gap.
+        assert_eq!(problems(vec![root.clone()]), [
+            "chain stops at CN=leaf: its issuer CN=Intermediate is not in the bundle",
+            "extra certificate CN=Root is not part of the chain of CN=leaf",
+        ]);
+        assert_eq!(problems(vec![intermediate.clone(), root.clone(), other]), [
+            "extra certificate CN=Other is not part of the chain of CN=leaf",
+        ]);
+        assert_eq!(problems(vec![intermediate.clone(), intermediate.clone(), root]), [
+            "CN=Intermediate appears more than once",
+        ]);
+    }
+}
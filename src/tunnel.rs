@@ -0,0 +1,232 @@
+//! Raw TCP tunnel mode: after the handshake, copy bytes both ways between the TLS stream
+//! and a plaintext backend (stunnel/ghostunnel server mode).
+//!
+//! EOF in one direction is passed on as a write shutdown (close_notify on the TLS side)
+//! while the other direction keeps flowing, so half-closing protocols work.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::upstream::Upstream;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// `host:port` or `unix:/path/to.sock`; required when `server.mode = "tunnel"`.
+    pub backend: Option<Upstream>,
+    pub connect_timeout_secs: u64,
+    /// Close the tunnel when neither side has sent anything for this long.
+    pub idle_timeout_secs: u64,
+    /// Close the tunnel this long after it opened, however busy. Unlimited when unset.
+    pub max_lifetime_secs: Option<u64>,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig { backend: None, connect_timeout_secs: 5, idle_timeout_secs: 300, max_lifetime_secs: None }
+    }
+}
+
+impl TunnelConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.connect_timeout_secs == 0 {
+            bail!("tunnel.connect_timeout_secs: must be at least 1");
+        }
+        if self.idle_timeout_secs == 0 {
+            bail!("tunnel.idle_timeout_secs: must be at least 1");
+        }
+        if self.max_lifetime_secs == Some(0) {
+            bail!("tunnel.max_lifetime_secs: must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+pub struct Tunnel {
+    backend: Upstream,
+    connect_timeout: Duration,
+    limits: Limits,
+}
+
+/// Timeouts for one [`pipe`].
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub idle: Duration,
+    pub lifetime: Option<Duration>,
+}
+
+impl Tunnel {
+    /// `None` unless a backend is configured.
+    pub fn new(config: &TunnelConfig) -> Option<Tunnel> {
+        Some(Tunnel {
+            backend: config.backend.clone()?,
+            connect_timeout: Duration::from_secs(config.connect_timeout_secs),
+            limits: Limits {
+                idle: Duration::from_secs(config.idle_timeout_secs),
+                lifetime: config.max_lifetime_secs.map(Duration::from_secs),
+            },
+        })
+    }
+
+    /// Connect to the backend and relay until both directions are closed.
+    pub async fn run<S>(&self, tls: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let backend = self.backend.connect(self.connect_timeout).await?;
+        let (up, down) = pipe(tls, backend, self.limits).await?;
+        debug!(backend = %self.backend, up, down, "tunnel closed");
+        Ok(())
+    }
+}
+
+/// Copy `a` -> `b` and `b` -> `a` concurrently until both have reached EOF. Returns the
+/// byte counts in each direction; fails when a timeout in `limits` fires first.
+pub async fn pipe<A, B>(a: A, b: B, limits: Limits) -> Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = Instant::now();
+    // Milliseconds since `start` of the last byte moved in either direction.
+    let active = AtomicU64::new(0);
+
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+    let copy = async {
+        tokio::try_join!(
+            copy_half(&mut a_read, &mut b_write, start, &active),
+            copy_half(&mut b_read, &mut a_write, start, &active),
+        )
+    };
+
+    let watchdog = async {
+        loop {
+            let last = start + Duration::from_millis(active.load(Ordering::Relaxed));
+            let idle_at = last + limits.idle;
+            let wake = match limits.lifetime {
+                Some(lifetime) => idle_at.min(start + lifetime),
+                None => idle_at,
+            };
+            tokio::time::sleep_until(wake).await;
+            let now = Instant::now();
+            if limits.lifetime.is_some_and(|l| now >= start + l) {
+                return "maximum lifetime reached";
+            }
+            if now >= start + Duration::from_millis(active.load(Ordering::Relaxed)) + limits.idle {
+                return "idle timeout";
+            }
+        }
+    };
+
+    tokio::select! {
+        res = copy => Ok(res?),
+        reason = watchdog => bail!("tunnel closed: {reason}"),
+    }
+}
+
+async fn copy_half<R, W>(from: &mut R, to: &mut W, start: Instant, active: &AtomicU64) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut total = 0;
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            // Pass the half-close on; the other direction keeps running. The far side may
+            // already be gone, which is not an error for this direction.
+            to.shutdown().await.ok();
+            return Ok(total);
+        }
+        to.write_all(&buf[..n]).await?;
+        to.flush().await?;
+        total += n as u64;
+        active.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    /// A running pipe between two in-memory streams; returns the far ends of `a` and `b`.
+    async fn tunnel(limits: Limits) -> (DuplexStream, DuplexStream, JoinHandle<Result<(u64, u64)>>) {
+        let (a_client, a) = duplex(1024);
+        let (b_client, b) = duplex(1024);
+        let pipe = tokio::spawn(pipe(a, b, limits));
+        // Let it start, so its clock starts now.
+        tokio::task::yield_now().await;
+        (a_client, b_client, pipe)
+    }
+
+    async fn relay(from: &mut DuplexStream, to: &mut DuplexStream, data: &[u8]) {
+        from.write_all(data).await.unwrap();
+        let mut got = vec![0; data.len()];
+        to.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, data);
+    }
+
+    #[tokio::test]
+    async fn half_close_is_passed_on() {
+        let limits = Limits { idle: Duration::from_secs(60), lifetime: None };
+        let (mut a, mut b, pipe) = tunnel(limits).await;
+
+        relay(&mut a, &mut b, b"request").await;
+        a.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        b.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty(), "EOF reached the other side");
+
+        // The other direction still flows until it is closed as well.
+        relay(&mut b, &mut a, b"response").await;
+        relay(&mut b, &mut a, b" and more").await;
+        b.shutdown().await.unwrap();
+        a.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(pipe.await.unwrap().unwrap(), (7, 17));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_tunnels_are_closed() {
+        let start = Instant::now();
+        let limits = Limits { idle: Duration::from_secs(10), lifetime: None };
+        let (mut a, mut b, pipe) = tunnel(limits).await;
+
+        // Traffic in either direction keeps it open.
+        tokio::time::advance(Duration::from_secs(8)).await;
+        relay(&mut a, &mut b, b"x").await;
+        tokio::time::advance(Duration::from_secs(8)).await;
+        relay(&mut b, &mut a, b"y").await;
+        tokio::time::advance(Duration::from_secs(8)).await;
+        tokio::task::yield_now().await;
+        assert!(!pipe.is_finished());
+
+        let err = pipe.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("idle timeout"), "{err:#}");
+        assert_eq!(start.elapsed(), Duration::from_secs(26), "10s after the last byte");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn busy_tunnels_are_closed_at_their_lifetime() {
+        let start = Instant::now();
+        let limits = Limits { idle: Duration::from_secs(10), lifetime: Some(Duration::from_secs(30)) };
+        let (mut a, mut b, pipe) = tunnel(limits).await;
+
+        for _ in 0..5 {
+            tokio::time::advance(Duration::from_secs(5)).await;
+            relay(&mut a, &mut b, b"tick").await;
+        }
+        let err = pipe.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("maximum lifetime reached"), "{err:#}");
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+}
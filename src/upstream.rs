@@ -0,0 +1,71 @@
+//! Plaintext backends the server forwards to: `host:port` over TCP or `unix:/path`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Upstream {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl TryFrom<String> for Upstream {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("{s:?}: missing socket path");
+            }
+            #[cfg(unix)]
+            return Ok(Upstream::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            bail!("{s:?}: unix sockets are not supported on this platform");
+        }
+        let (host, port) = s.rsplit_once(':').ok_or_else(|| anyhow!("{s:?}: expected host:port or unix:/path"))?;
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            bail!("{s:?}: expected host:port or unix:/path");
+        }
+        Ok(Upstream::Tcp(s))
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Tcp(addr) => f.write_str(addr),
+            #[cfg(unix)]
+            Upstream::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connected upstream stream of either kind.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+impl Upstream {
+    pub async fn connect(&self, timeout: Duration) -> Result<Box<dyn Io>> {
+        let connect = async {
+            Ok::<Box<dyn Io>, std::io::Error>(match self {
+                Upstream::Tcp(addr) => {
+                    let tcp = TcpStream::connect(addr.as_str()).await?;
+                    tcp.set_nodelay(true)?;
+                    Box::new(tcp)
+                }
+                #[cfg(unix)]
+                Upstream::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+            })
+        };
+        tokio::time::timeout(timeout, connect).await
+            .map_err(|_| anyhow!("connecting to {self}: timed out after {timeout:?}"))?
+            .with_context(|| format!("connecting to {self}"))
+    }
+}
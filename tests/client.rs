@@ -0,0 +1,177 @@
+//! `Client` against the server binary: the handshake, policy rejections and the HTTP
+//! version ALPN picks.
+
+mod common;
+
+use common::Pki;
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio_openssl_server::client::{Client, ClientConfig, ClientRequest};
+use tokio_openssl_server::upstream::Upstream;
+
+/// The server binary running with `pki`'s config; killed on drop.
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    /// Start it with `extra` appended to the base config, listening on a free port.
+    fn start(pki: &Pki, extra: &str) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("127.0.0.1:{port}");
+        let path = pki.path("server.toml");
+        std::fs::write(&path, format!("[server]\nlisten = [{addr:?}]\n\n{}{extra}", pki.base_config())).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_tokio_openssl_server"))
+            .arg("--config")
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("starting the server");
+        let mut server = Server { child, addr };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while std::net::TcpStream::connect(&server.addr).is_err() {
+            if let Some(status) = server.child.try_wait().unwrap() {
+                panic!("server exited: {status}");
+            }
+            assert!(Instant::now() < deadline, "server did not start listening");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    /// A client of this server presenting `pki`'s certificate for `cn`/`ou`.
+    fn client(&self, pki: &Pki, cn: &str, ou: &str, alpn: &[&str]) -> Client {
+        let cert = pki.client(cn, ou);
+        Client::new(&ClientConfig {
+            connect: Upstream::try_from(self.addr.clone()).unwrap(),
+            server_name: Some("localhost".into()),
+            ca: pki.path("pki/ca_server/ca.crt"),
+            partial_chain: false,
+            cert: Some(cert.fullchain),
+            key: Some(cert.key),
+            key_password: None,
+            pkcs12: None,
+            pkcs12_password: None,
+            alpn: alpn.iter().map(|p| p.to_string()).collect(),
+            connect_timeout_secs: 5,
+        })
+        .unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn get(path: &str) -> ClientRequest {
+    ClientRequest { method: "GET".into(), path: path.into(), headers: Vec::new(), body: Vec::new() }
+}
+
+#[tokio::test]
+async fn handshake_and_request() {
+    let pki = Pki::new("client-handshake");
+    let server = Server::start(&pki, "");
+    let client = server.client(&pki, "device-1", "TrustedDevices", &[]);
+
+    let (session, resp) = client.request(&get("/"), Duration::from_secs(5)).await.unwrap();
+    assert!(session.protocol.starts_with("TLSv1."), "{session}");
+    assert_eq!(session.alpn, None);
+    assert_eq!(session.chain.len(), 3, "cert.pem holds leaf, intermediate and root");
+    assert!(session.to_string().contains("CN=localhost"), "{session}");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body, b"ok\n");
+}
+
+#[tokio::test]
+async fn server_outside_the_pinned_ca_is_refused() {
+    let pki = Pki::new("client-wrong-ca");
+    let server = Server::start(&pki, "");
+    let cert = pki.client("device-1", "TrustedDevices");
+    let client = Client::new(&ClientConfig {
+        connect: Upstream::try_from(server.addr.clone()).unwrap(),
+        server_name: Some("localhost".into()),
+        // The client CA did not issue the server certificate.
+        ca: pki.path("pki/ca_client/ca.crt"),
+        partial_chain: false,
+        cert: Some(cert.fullchain),
+        key: Some(cert.key),
+        key_password: None,
+        pkcs12: None,
+        pkcs12_password: None,
+        alpn: Vec::new(),
+        connect_timeout_secs: 5,
+    })
+    .unwrap();
+
+    let err = client.connect().await.err().expect("handshake should fail");
+    assert!(format!("{err:#}").contains("server certificate rejected"), "{err:#}");
+}
+
+#[tokio::test]
+async fn policy_rejects_other_clients() {
+    let pki = Pki::new("client-policy");
+    let policy = r#"
+[policy]
+default = "reject"
+
+[[policy.rules]]
+name = "sensors"
+verdict = "accept"
+match = { all = [{ subject = { ou = "TrustedDevices" } }, { subject = { cn = "sensor-*" } }] }
+"#;
+    let server = Server::start(&pki, policy);
+
+    let accepted = server.client(&pki, "sensor-1", "TrustedDevices", &[]);
+    let (_, resp) = accepted.request(&get("/"), Duration::from_secs(5)).await.unwrap();
+    assert_eq!(resp.status, 200);
+
+    // The verify callback refuses them, so the handshake fails with an alert.
+    for (cn, ou) in [("camera-1", "TrustedDevices"), ("sensor-2", "Guests")] {
+        let rejected = server.client(&pki, cn, ou, &[]);
+        let err = rejected.connect().await.err().unwrap_or_else(|| panic!("{cn}/{ou} was accepted"));
+        assert!(format!("{err:#}").contains("TLS handshake"), "{cn}/{ou}: {err:#}");
+        assert!(rejected.request(&get("/"), Duration::from_secs(5)).await.is_err(), "{cn}/{ou} was served");
+    }
+}
+
+#[tokio::test]
+async fn h2_when_offered_and_enabled() {
+    let pki = Pki::new("client-alpn");
+    let server = Server::start(&pki, "");
+
+    for (alpn, want) in [
+        (&["h2", "http/1.1"][..], Some("h2")),
+        (&["h2"][..], Some("h2")),
+        (&["http/1.1", "h2"][..], Some("h2")),
+        (&["http/1.1"][..], Some("http/1.1")),
+        (&[][..], None),
+    ] {
+        let client = server.client(&pki, "device-1", "TrustedDevices", alpn);
+        let (session, resp) = client.request(&get("/"), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(session.alpn.as_deref(), want, "offering {alpn:?}");
+        assert_eq!((resp.status, resp.body.as_slice()), (200, &b"ok\n"[..]), "offering {alpn:?}");
+    }
+}
+
+#[tokio::test]
+async fn http1_when_h2_is_disabled() {
+    let pki = Pki::new("client-no-h2");
+    let server = Server::start(&pki, "\n[http]\nhttp2 = false\n");
+
+    let client = server.client(&pki, "device-1", "TrustedDevices", &["h2", "http/1.1"]);
+    let (session, resp) = client.request(&get("/"), Duration::from_secs(5)).await.unwrap();
+    assert_eq!(session.alpn.as_deref(), Some("http/1.1"));
+    assert_eq!(resp.status, 200);
+
+    // Nothing it can speak: the server declines ALPN and the client falls back.
+    let client = server.client(&pki, "device-1", "TrustedDevices", &["h2"]);
+    let (session, resp) = client.request(&get("/"), Duration::from_secs(5)).await.unwrap();
+    assert_eq!(session.alpn, None);
+    assert_eq!(resp.status, 200);
+}
@@ -0,0 +1,167 @@
+//! Shared setup for the integration tests: a PKI generated with `pki` in a scratch
+//! directory, `openssl ca`/`openssl ocsp` driven through an index file, configs written
+//! to disk and in-memory handshakes against an acceptor.
+
+#![allow(dead_code)]
+
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio_openssl::SslStream;
+use tokio_openssl_server::config::{Config, Overrides};
+use tokio_openssl_server::pki::{self, ClientArgs, InitArgs, KeyType, LeafArgs, PkiCommand, ServerArgs};
+
+/// Server and client CAs, each with an intermediate, and a server certificate for
+/// `localhost`, under a fresh directory.
+pub struct Pki {
+    pub dir: PathBuf,
+}
+
+/// A client certificate issued by [`Pki::client`].
+pub struct Client {
+    /// Leaf, intermediate and root.
+    pub fullchain: PathBuf,
+    pub key: PathBuf,
+    pub cert: X509,
+}
+
+impl Pki {
+    pub fn new(name: &str) -> Pki {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("{name}-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        pki::run(PkiCommand::Init(InitArgs { dir: dir.clone(), key_type: KeyType::EcP256, days: 30, intermediate: true, force: true }))
+            .unwrap();
+        pki::run(PkiCommand::IssueServer(ServerArgs {
+            cn: "localhost".into(),
+            ou: "Infra".into(),
+            dns: vec!["localhost".into()],
+            ip: vec![IpAddr::from([127, 0, 0, 1])],
+            name: "server".into(),
+            leaf: leaf_args(&dir),
+        }))
+        .unwrap();
+        Pki { dir }
+    }
+
+    pub fn path(&self, relative: &str) -> PathBuf {
+        self.dir.join(relative)
+    }
+
+    /// Issue a client certificate from the client intermediate and keep it as
+    /// `clients/<cn>.{crt,key}`.
+    pub fn client(&self, cn: &str, ou: &str) -> Client {
+        pki::run(PkiCommand::IssueClient(ClientArgs {
+            cn: cn.into(),
+            ou: ou.into(),
+            dns: Vec::new(),
+            ip: Vec::new(),
+            leaf: leaf_args(&self.dir),
+        }))
+        .unwrap();
+        let out = self.path("clients");
+        std::fs::create_dir_all(&out).unwrap();
+        let (fullchain, key) = (out.join(format!("{cn}.crt")), out.join(format!("{cn}.key")));
+        std::fs::copy(self.path("pki/client/client-fullchain.crt"), &fullchain).unwrap();
+        std::fs::copy(self.path("pki/client/client.key"), &key).unwrap();
+        let cert = X509::from_pem(&std::fs::read(self.path("pki/client/client.crt")).unwrap()).unwrap();
+        Client { fullchain, key, cert }
+    }
+
+    /// Write the client intermediate's `openssl ca` database: `revoked` certificates are
+    /// marked revoked, `valid` ones good; anything else is unknown to it.
+    pub fn index(&self, valid: &[&Client], revoked: &[&Client]) {
+        let ca = self.path("ocsp-ca");
+        std::fs::create_dir_all(&ca).unwrap();
+        let mut index = String::new();
+        let entry = |status: &str, revoked_at: &str, client: &Client| {
+            let serial = client.cert.serial_number().to_bn().unwrap().to_hex_str().unwrap().to_string();
+            format!("{status}\t351231235959Z\t{revoked_at}\t{serial}\tunknown\t/CN=x\n")
+        };
+        for client in valid {
+            index.push_str(&entry("V", "", client));
+        }
+        for client in revoked {
+            index.push_str(&entry("R", "250101000000Z", client));
+        }
+        std::fs::write(ca.join("index.txt"), index).unwrap();
+        std::fs::write(ca.join("index.txt.attr"), "unique_subject = no\n").unwrap();
+        std::fs::write(ca.join("ca.cnf"), format!(
+            "[ca]\ndefault_ca = ca_default\n[ca_default]\ndatabase = {}\ndefault_md = sha256\ndefault_crl_days = 7\n",
+            ca.join("index.txt").display(),
+        ))
+        .unwrap();
+    }
+
+    /// A CRL from the client intermediate for the last [`Pki::index`].
+    pub fn crl(&self) -> PathBuf {
+        let out = self.path("ocsp-ca/client.crl");
+        openssl(&[
+            "ca", "-gencrl", "-batch",
+            "-config", &self.path("ocsp-ca/ca.cnf").to_string_lossy(),
+            "-keyfile", &self.path("pki/ca_client/intermediate.key").to_string_lossy(),
+            "-cert", &self.path("pki/ca_client/intermediate.crt").to_string_lossy(),
+            "-out", &out.to_string_lossy(),
+        ]);
+        out
+    }
+
+    /// Write `toml` as the config file and load it like the server does.
+    pub fn config(&self, toml: &str) -> Config {
+        let path = self.path("mtls.toml");
+        std::fs::write(&path, toml).unwrap();
+        Config::load(Some(&path), &Overrides::default()).unwrap()
+    }
+
+    /// `[identity]` and `[trust]` for this PKI, as a config starts.
+    pub fn base_config(&self) -> String {
+        format!(
+            "[identity]\nsource = \"pem\"\ncert = {:?}\nkey = {:?}\n\n[trust]\nclient_ca = {:?}\n",
+            self.path("cert.pem"), self.path("key.pem"), self.path("client-ca.pem"),
+        )
+    }
+}
+
+fn leaf_args(dir: &Path) -> LeafArgs {
+    LeafArgs {
+        dir: dir.to_path_buf(),
+        key_type: KeyType::EcP256,
+        days: 7,
+        uri: Vec::new(),
+        email: Vec::new(),
+        p12_password: "changeit".into(),
+    }
+}
+
+/// Run the `openssl` command line tool, failing the test if it fails.
+pub fn openssl(args: &[&str]) {
+    let out = Command::new("openssl").args(args).output().expect("running openssl");
+    assert!(out.status.success(), "openssl {}: {}", args.join(" "), String::from_utf8_lossy(&out.stderr));
+}
+
+/// Handshake in memory as `client` with `sni`; the server side's result.
+pub async fn handshake(acceptor: &SslAcceptor, sni: &str, client: &Client) -> Result<(), String> {
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let mut server = SslStream::new(openssl::ssl::Ssl::new(acceptor.context()).unwrap(), server_io).unwrap();
+
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_verify(SslVerifyMode::NONE);
+    connector.set_certificate_chain_file(&client.fullchain).unwrap();
+    connector.set_private_key_file(&client.key, SslFiletype::PEM).unwrap();
+    let ssl = connector.build().configure().unwrap().into_ssl(sni).unwrap();
+    let mut tls = SslStream::new(ssl, client_io).unwrap();
+
+    let (accepted, _) = tokio::join!(
+        std::pin::Pin::new(&mut server).accept(),
+        std::pin::Pin::new(&mut tls).connect(),
+    );
+    accepted.map_err(|e| {
+        let verify = server.ssl().verify_result();
+        format!("{e} ({})", verify.error_string())
+    })
+}
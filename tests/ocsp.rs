@@ -0,0 +1,177 @@
+//! OCSP checks of client leaves against a local `openssl ocsp` responder.
+
+mod common;
+
+use common::{Client, Pki};
+use openssl::x509::X509;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio_openssl_server::ocsp::{http_post, OcspChecker, OcspConfig, OcspMode};
+
+/// `openssl ocsp` answering for the client intermediate from its index; killed on drop.
+struct Responder {
+    child: Child,
+    port: u16,
+    url: String,
+}
+
+impl Responder {
+    /// Listen on `port` (a free one when 0); answers are valid for `next_update_mins`, or
+    /// carry no nextUpdate when `None`.
+    fn start(pki: &Pki, port: u16, next_update_mins: Option<u32>) -> Responder {
+        let port = if port == 0 { free_port() } else { port };
+        let intermediate = pki.path("pki/ca_client/intermediate.crt");
+        let mut args = vec![
+            "ocsp".to_string(),
+            "-index".into(), pki.path("ocsp-ca/index.txt").display().to_string(),
+            "-port".into(), port.to_string(),
+            "-CA".into(), intermediate.display().to_string(),
+            "-rsigner".into(), intermediate.display().to_string(),
+            "-rkey".into(), pki.path("pki/ca_client/intermediate.key").display().to_string(),
+        ];
+        if let Some(mins) = next_update_mins {
+            args.extend(["-nmin".into(), mins.to_string()]);
+        }
+        let mut child = Command::new("openssl")
+            .args(&args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("running openssl ocsp");
+        // It announces itself on stderr once listening; probing the port instead would
+        // wedge it, as it blocks on a connection that sends nothing. The rest of its
+        // chatter is drained on a thread so it never blocks on a full pipe.
+        let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+        loop {
+            match stderr.next() {
+                Some(Ok(line)) if line.contains("waiting for OCSP client connections") => break,
+                Some(Ok(_)) => continue,
+                _ => panic!("openssl ocsp did not start"),
+            }
+        }
+        std::thread::spawn(move || stderr.for_each(drop));
+        Responder { child, port, url: format!("http://127.0.0.1:{port}/") }
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn checker(mode: OcspMode, url: &str) -> OcspChecker {
+    OcspChecker::new(&OcspConfig { mode, responder: Some(url.to_string()), timeout_secs: 5 }).unwrap()
+}
+
+/// Leaf, intermediate and root, as the handshake verifies them.
+fn chain(pki: &Pki, client: &Client) -> Vec<X509> {
+    let mut chain = vec![client.cert.clone()];
+    for ca in ["pki/ca_client/intermediate.crt", "pki/ca_client/ca.crt"] {
+        chain.push(X509::from_pem(&std::fs::read(pki.path(ca)).unwrap()).unwrap());
+    }
+    chain
+}
+
+#[tokio::test]
+async fn good_revoked_and_unknown_in_soft_and_hard_mode() {
+    let pki = Pki::new("ocsp-status");
+    let good = pki.client("good", "TrustedDevices");
+    let revoked = pki.client("revoked", "TrustedDevices");
+    let unknown = pki.client("unknown", "TrustedDevices");
+    pki.index(&[&good], &[&revoked]);
+    let responder = Responder::start(&pki, 0, Some(5));
+
+    for mode in [OcspMode::Soft, OcspMode::Hard] {
+        let ocsp = checker(mode, &responder.url);
+        ocsp.check(&chain(&pki, &good)).await.unwrap();
+        let err = ocsp.check(&chain(&pki, &revoked)).await.unwrap_err();
+        assert!(err.to_string().contains("revoked"), "{mode:?}: {err:#}");
+        let unknown = ocsp.check(&chain(&pki, &unknown)).await;
+        match mode {
+            OcspMode::Soft => unknown.unwrap(),
+            _ => assert!(unknown.unwrap_err().to_string().contains("unknown"), "{mode:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn unreachable_responder_fails_only_in_hard_mode() {
+    let pki = Pki::new("ocsp-down");
+    let client = pki.client("device", "TrustedDevices");
+    let url = format!("http://127.0.0.1:{}/", free_port());
+
+    checker(OcspMode::Soft, &url).check(&chain(&pki, &client)).await.unwrap();
+    checker(OcspMode::Hard, &url).check(&chain(&pki, &client)).await.unwrap_err();
+}
+
+#[tokio::test]
+async fn answers_are_cached_until_next_update() {
+    let pki = Pki::new("ocsp-cache");
+    let client = pki.client("device", "TrustedDevices");
+    pki.index(&[&client], &[]);
+    let responder = Responder::start(&pki, 0, Some(1));
+    let (url, port) = (responder.url.clone(), responder.port);
+    let ocsp = checker(OcspMode::Hard, &url);
+    ocsp.check(&chain(&pki, &client)).await.unwrap();
+
+    // Revoked now and the responder gone, but the good answer is cached until its
+    // nextUpdate a minute out; hard mode would refuse if it asked. The cache runs on
+    // tokio's clock, paused here only while nothing touches the network.
+    drop(responder);
+    pki.index(&[], &[&client]);
+    tokio::time::pause();
+    tokio::time::advance(Duration::from_secs(50)).await;
+    ocsp.check(&chain(&pki, &client)).await.unwrap();
+
+    // Once it has expired the responder is asked again and the revocation shows.
+    tokio::time::advance(Duration::from_secs(11)).await;
+    tokio::time::resume();
+    let _responder = Responder::start(&pki, port, Some(1));
+    let err = ocsp.check(&chain(&pki, &client)).await.unwrap_err();
+    assert!(err.to_string().contains("revoked"), "{err:#}");
+}
+
+#[tokio::test]
+async fn answers_without_next_update_are_not_cached() {
+    let pki = Pki::new("ocsp-no-next-update");
+    let client = pki.client("device", "TrustedDevices");
+    pki.index(&[&client], &[]);
+    let responder = Responder::start(&pki, 0, None);
+    let (url, port) = (responder.url.clone(), responder.port);
+    let ocsp = checker(OcspMode::Hard, &url);
+    ocsp.check(&chain(&pki, &client)).await.unwrap();
+
+    // Nothing to expire it by, so the next handshake asks again: a gone responder
+    // fails hard mode, and a revocation shows straight away.
+    drop(responder);
+    ocsp.check(&chain(&pki, &client)).await.unwrap_err();
+    pki.index(&[], &[&client]);
+    let _responder = Responder::start(&pki, port, None);
+    let err = ocsp.check(&chain(&pki, &client)).await.unwrap_err();
+    assert!(err.to_string().contains("revoked"), "{err:#}");
+}
+
+#[tokio::test]
+async fn oversized_responder_replies_are_refused() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (mut tcp, _) = listener.accept().await.unwrap();
+        let head = b"HTTP/1.0 200 OK\r\nContent-Type: application/ocsp-response\r\n\r\n";
+        let _ = tcp.write_all(head).await;
+        // Endless body; the client must stop reading, not the timeout.
+        while tcp.write_all(&[0; 16384]).await.is_ok() {}
+    });
+
+    let err = http_post(&url, "application/ocsp-request", b"", Duration::from_secs(30)).await.unwrap_err();
+    assert!(err.to_string().contains("response larger than"), "{err:#}");
+}
@@ -0,0 +1,63 @@
+//! Each `[[sni.hosts]]` entry verifies clients with its own trust: CRLs and partial
+//! chains follow the host, not the top-level `[trust]`.
+
+mod common;
+
+use common::{handshake, Pki};
+use tokio_openssl_server::tls::build_acceptor;
+
+fn host(pki: &Pki, name: &str, trust: &str) -> String {
+    format!(
+        "\n[[sni.hosts]]\nnames = [{name:?}]\nidentity = {{ source = \"pem\", cert = {:?}, key = {:?} }}\ntrust = {{ {trust} }}\n",
+        pki.path("cert.pem"), pki.path("key.pem"),
+    )
+}
+
+#[tokio::test]
+async fn host_crls_are_checked() {
+    let pki = Pki::new("sni-host-crl");
+    let (good, revoked) = (pki.client("good", "TrustedDevices"), pki.client("revoked", "TrustedDevices"));
+    pki.index(&[&good], &[&revoked]);
+    let crl = pki.crl();
+    let trust = format!("client_ca = {:?}, crls = [{crl:?}]", pki.path("client-ca.pem"));
+    let config = pki.config(&(pki.base_config() + &host(&pki, "sni.test", &trust)));
+    let acceptor = build_acceptor(&config, None).unwrap();
+
+    let err = handshake(&acceptor, "sni.test", &revoked).await.unwrap_err();
+    assert!(err.contains("revoked"), "{err}");
+    handshake(&acceptor, "sni.test", &good).await.unwrap();
+    // The top-level trust has no CRL.
+    handshake(&acceptor, "localhost", &revoked).await.unwrap();
+}
+
+#[tokio::test]
+async fn top_level_crls_stay_out_of_hosts() {
+    let pki = Pki::new("sni-top-crl");
+    let (good, revoked) = (pki.client("good", "TrustedDevices"), pki.client("revoked", "TrustedDevices"));
+    pki.index(&[&good], &[&revoked]);
+    let crl = pki.crl();
+    let trust = format!("client_ca = {:?}", pki.path("client-ca.pem"));
+    let config = pki.config(&(pki.base_config() + &format!("crls = [{crl:?}]\n") + &host(&pki, "sni.test", &trust)));
+    let acceptor = build_acceptor(&config, None).unwrap();
+
+    let err = handshake(&acceptor, "localhost", &revoked).await.unwrap_err();
+    assert!(err.contains("revoked"), "{err}");
+    // No CRL for this host, so no CRL check either.
+    handshake(&acceptor, "sni.test", &good).await.unwrap();
+    handshake(&acceptor, "sni.test", &revoked).await.unwrap();
+}
+
+#[tokio::test]
+async fn host_partial_chain_is_applied() {
+    let pki = Pki::new("sni-partial");
+    let client = pki.client("device", "TrustedDevices");
+    let intermediate = pki.path("pki/ca_client/intermediate.crt");
+    let config = pki.config(&(pki.base_config()
+        + &host(&pki, "partial.test", &format!("client_ca = {intermediate:?}, partial_chain = true"))
+        + &host(&pki, "strict.test", &format!("client_ca = {intermediate:?}"))));
+    let acceptor = build_acceptor(&config, None).unwrap();
+
+    handshake(&acceptor, "partial.test", &client).await.unwrap();
+    handshake(&acceptor, "strict.test", &client).await.unwrap_err();
+    handshake(&acceptor, "localhost", &client).await.unwrap();
+}
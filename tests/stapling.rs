@@ -0,0 +1,132 @@
+//! OCSP stapling of the server certificate from pre-fetched DER responses made with
+//! `openssl ocsp`: what gets stapled, and when it stops being.
+
+mod common;
+
+use common::{openssl, Client, Pki};
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode, StatusType};
+use openssl::x509::X509;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_openssl::SslStream;
+use tokio_openssl_server::ocsp::MAX_AGE_SECS;
+use tokio_openssl_server::stapling::Stapler;
+use tokio_openssl_server::tls::build_acceptor;
+
+/// A response from the server intermediate, valid for `next_update_mins` (no nextUpdate
+/// when `None`), about `serial` (hex), or about the server certificate when that is `None`.
+fn response(pki: &Pki, name: &str, serial: Option<&str>, next_update_mins: Option<u32>) -> PathBuf {
+    let server = X509::from_pem(&std::fs::read(pki.path("pki/server/server.crt")).unwrap()).unwrap();
+    let serial = match serial {
+        Some(serial) => serial.to_string(),
+        None => server.serial_number().to_bn().unwrap().to_hex_str().unwrap().to_string(),
+    };
+    let dir = pki.path("staples");
+    std::fs::create_dir_all(&dir).unwrap();
+    let index = dir.join(format!("{name}.index"));
+    std::fs::write(&index, format!("V\t351231235959Z\t\t{serial}\tunknown\t/CN=x\n")).unwrap();
+    std::fs::write(dir.join(format!("{name}.index.attr")), "unique_subject = no\n").unwrap();
+
+    let (req, resp) = (dir.join(format!("{name}.req")), dir.join(format!("{name}.der")));
+    let path = |p: &PathBuf| p.display().to_string();
+    let intermediate = path(&pki.path("pki/ca_server/intermediate.crt"));
+    openssl(&["ocsp", "-issuer", &intermediate, "-serial", &format!("0x{serial}"), "-no_nonce", "-reqout", &path(&req)]);
+    let mut args = vec![
+        "ocsp".to_string(), "-index".into(), path(&index), "-CA".into(), intermediate.clone(),
+        "-rsigner".into(), intermediate.clone(), "-rkey".into(), path(&pki.path("pki/ca_server/intermediate.key")),
+        "-reqin".into(), path(&req), "-respout".into(), path(&resp),
+    ];
+    if let Some(mins) = next_update_mins {
+        args.extend(["-nmin".into(), mins.to_string()]);
+    }
+    openssl(&args.iter().map(String::as_str).collect::<Vec<_>>());
+    resp
+}
+
+/// An acceptor stapling `file`, with its stapler refreshed once.
+async fn stapling(pki: &Pki, file: &PathBuf) -> (SslAcceptor, Arc<Stapler>) {
+    let config = pki.config(&format!("{}\n[stapling]\nenabled = true\nfile = {file:?}\n", pki.base_config()));
+    let stapler = Stapler::new(&config.stapling).unwrap();
+    let acceptor = build_acceptor(&config, Some(&stapler)).unwrap();
+    stapler.refresh().await;
+    (acceptor, stapler)
+}
+
+/// Handshake as `client`, asking for a stapled response; the one the server sent.
+async fn stapled(acceptor: &SslAcceptor, client: &Client) -> Option<Vec<u8>> {
+    let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+    let mut server = SslStream::new(openssl::ssl::Ssl::new(acceptor.context()).unwrap(), server_io).unwrap();
+
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_verify(SslVerifyMode::NONE);
+    connector.set_certificate_chain_file(&client.fullchain).unwrap();
+    connector.set_private_key_file(&client.key, SslFiletype::PEM).unwrap();
+    let mut ssl = connector.build().configure().unwrap().into_ssl("localhost").unwrap();
+    ssl.set_status_type(StatusType::OCSP).unwrap();
+    let mut tls = SslStream::new(ssl, client_io).unwrap();
+
+    let (accepted, connected) = tokio::join!(
+        std::pin::Pin::new(&mut server).accept(),
+        std::pin::Pin::new(&mut tls).connect(),
+    );
+    accepted.unwrap();
+    connected.unwrap();
+    tls.ssl().ocsp_status().map(<[u8]>::to_vec)
+}
+
+#[tokio::test]
+async fn a_current_response_is_stapled() {
+    let pki = Pki::new("staple-current");
+    let client = pki.client("device", "TrustedDevices");
+    let file = response(&pki, "current", None, Some(5));
+    let (acceptor, _stapler) = stapling(&pki, &file).await;
+
+    assert_eq!(stapled(&acceptor, &client).await, Some(std::fs::read(&file).unwrap()));
+}
+
+#[tokio::test]
+async fn expired_responses_are_not_stapled() {
+    let pki = Pki::new("staple-expired");
+    let client = pki.client("device", "TrustedDevices");
+    let (acceptor, _stapler) = stapling(&pki, &response(&pki, "minute", None, Some(1))).await;
+    assert!(stapled(&acceptor, &client).await.is_some());
+
+    // Staples expire on tokio's clock; nothing here waits on a timer while it is paused.
+    tokio::time::pause();
+    tokio::time::advance(Duration::from_secs(61)).await;
+    assert_eq!(stapled(&acceptor, &client).await, None);
+}
+
+#[tokio::test]
+async fn responses_without_next_update_go_stale() {
+    let pki = Pki::new("staple-no-next-update");
+    let client = pki.client("device", "TrustedDevices");
+    let (acceptor, _stapler) = stapling(&pki, &response(&pki, "open-ended", None, None)).await;
+
+    tokio::time::pause();
+    tokio::time::advance(Duration::from_secs(u64::from(MAX_AGE_SECS) - 60)).await;
+    assert!(stapled(&acceptor, &client).await.is_some());
+    tokio::time::advance(Duration::from_secs(61)).await;
+    assert_eq!(stapled(&acceptor, &client).await, None);
+}
+
+#[tokio::test]
+async fn unusable_responses_are_not_stapled() {
+    let pki = Pki::new("staple-unusable");
+    let client = pki.client("device", "TrustedDevices");
+    let file = pki.path("garbage.der");
+    std::fs::write(&file, b"not an OCSP response").unwrap();
+    let (acceptor, _stapler) = stapling(&pki, &file).await;
+
+    assert_eq!(stapled(&acceptor, &client).await, None);
+}
+
+#[tokio::test]
+async fn responses_for_other_certificates_are_not_stapled() {
+    let pki = Pki::new("staple-other");
+    let client = pki.client("device", "TrustedDevices");
+    let (acceptor, _stapler) = stapling(&pki, &response(&pki, "other", Some("1234"), Some(5))).await;
+
+    assert_eq!(stapled(&acceptor, &client).await, None);
+}